@@ -11,12 +11,17 @@
 //!
 //! * any number of bands, holding tree indexs to describe which files
 //!   are present in a version.
+//!
+//! * optionally, a parity directory from which damaged blocks and index hunks can be
+//!   reconstructed.
+//!
+//! * optionally, a quarantine directory holding damaged blocks moved aside by `repair`.
 
-use std::cmp::max;
-use std::collections::BTreeSet;
+use std::collections::{BTreeSet, HashMap};
 use std::fs::read_dir;
 use std::path::{Path, PathBuf};
 
+use chrono::{DateTime, UTC};
 use thousands::Separable;
 
 use super::io::{file_exists, require_empty_directory};
@@ -25,7 +30,10 @@ use super::misc::remove_item;
 use super::*;
 
 const HEADER_FILENAME: &str = "CONSERVE";
+const CONFIG_FILENAME: &str = "CONFIG";
 static BLOCK_DIR: &str = "d";
+static PARITY_DIR: &str = "p";
+static QUARANTINE_DIR: &str = "quarantine";
 
 /// An archive holding backup material.
 #[derive(Clone, Debug)]
@@ -45,6 +53,27 @@ struct ArchiveHeader {
     conserve_archive_version: String,
 }
 
+/// Settings stored in the archive that apply by default to operations on it.
+///
+/// Archives without a config file behave as if they have the default config.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ArchiveConfig {
+    /// Exclusion patterns applied by default when backing up into this archive.
+    #[serde(default)]
+    pub excludes: Vec<String>,
+}
+
+/// How thoroughly to check an archive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValidateDepth {
+    /// Check the archive's structure and indexes, and that every referenced block exists,
+    /// without reading the blocks.
+    Shallow,
+    /// Also decompress every block and check it matches its hash, so that every stored
+    /// file is known to be readable.
+    Deep,
+}
+
 impl Archive {
     /// Make a new directory to hold an archive, and write the header.
     pub fn create<P: AsRef<Path>>(path: P) -> Result<Archive> {
@@ -91,19 +120,54 @@ impl Archive {
         &self.block_dir
     }
 
+    /// Read the stored archive config, or the default config if none has been written.
+    pub fn config(&self) -> Result<ArchiveConfig> {
+        let config_path = self.path.join(CONFIG_FILENAME);
+        if file_exists(&config_path)? {
+            jsonio::read_serde(&config_path, &self.report)
+        } else {
+            Ok(ArchiveConfig::default())
+        }
+    }
+
+    /// Replace the stored archive config.
+    pub fn write_config(&self, config: &ArchiveConfig) -> Result<()> {
+        // Check the patterns are usable before storing them.
+        excludes::from_strings(&config.excludes)?;
+        jsonio::write_serde(&self.path.join(CONFIG_FILENAME), config, &self.report)
+    }
+
     /// Returns the top-level directory for the archive.
     pub fn path(&self) -> &Path {
         self.path.as_path()
     }
 
+    /// Return the directory holding parity data.
+    pub fn parity_path(&self) -> PathBuf {
+        self.path.join(PARITY_DIR)
+    }
+
+    /// Return the directory where `repair` moves damaged blocks.
+    pub fn quarantine_path(&self) -> PathBuf {
+        self.path.join(QUARANTINE_DIR)
+    }
+
     /// Returns a vector of band ids, in sorted order from first to last.
+    ///
+    /// Other directories in the archive, including bands whose names aren't in the canonical
+    /// format, are ignored here, and reported by `validate`.
     pub fn list_bands(&self) -> Result<Vec<BandId>> {
         let mut band_ids = Vec::<BandId>::new();
         for e in read_dir(self.path())? {
             let e = e?;
-            let n = e.file_name().into_string().unwrap();
-            if e.file_type()?.is_dir() && n != BLOCK_DIR {
-                band_ids.push(BandId::from_string(&n)?);
+            let name = match e.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            if let Ok(band_id) = BandId::from_string(&name) {
+                if band_id.to_string() == name && e.file_type()?.is_dir() {
+                    band_ids.push(band_id);
+                }
             }
         }
         band_ids.sort_unstable();
@@ -113,19 +177,7 @@ impl Archive {
     /// Return the `BandId` of the highest-numbered band, or ArchiveEmpty,
     /// or an Err if any occurred reading the directory.
     pub fn last_band_id(&self) -> Result<BandId> {
-        let mut l: Option<BandId> = None;
-        for e in read_dir(self.path())? {
-            let e = e?;
-            let n = e.file_name().into_string().unwrap();
-            if e.file_type()?.is_dir() && n != BLOCK_DIR {
-                let bn = BandId::from_string(&n)?;
-                l = match l {
-                    None => Some(bn),
-                    Some(ll) => Some(max(ll, bn)),
-                };
-            }
-        }
-        l.ok_or(Error::ArchiveEmpty)
+        self.list_bands()?.pop().ok_or(Error::ArchiveEmpty)
     }
 
     /// Return the last completely-written band id.
@@ -139,6 +191,46 @@ impl Archive {
         Err(Error::NoCompleteBands)
     }
 
+    /// Return the most recent complete band that was completed at or before `time`.
+    ///
+    /// Bands closed after an interrupted backup are skipped unless `include_partial` is
+    /// true. Bands that can't be read are reported as problems and skipped.
+    pub fn last_complete_band_before(
+        &self,
+        time: DateTime<UTC>,
+        include_partial: bool,
+    ) -> Result<Band> {
+        for id in self.list_bands()?.iter().rev() {
+            let b = match Band::open(self, id) {
+                Ok(b) => b,
+                Err(e) => {
+                    self.report.problem(
+                        Problem::new(ProblemKind::BadBand, id, "Failed to open band").with_error(e),
+                    );
+                    continue;
+                }
+            };
+            let info = match b.get_info(&self.report) {
+                Ok(info) => info,
+                Err(e) => {
+                    self.report.problem(
+                        Problem::new(ProblemKind::BadBand, id, "Failed to read band tail")
+                            .with_error(e),
+                    );
+                    continue;
+                }
+            };
+            if info.is_partial && !include_partial {
+                continue;
+            }
+            match info.end_time {
+                Some(end_time) if end_time <= time => return Ok(b),
+                _ => (),
+            }
+        }
+        Err(Error::NoCompleteBandsBefore(time))
+    }
+
     /// Return a sorted set containing all the blocks referenced by all bands.
     pub fn referenced_blocks(&self) -> Result<BTreeSet<String>> {
         let mut hs = BTreeSet::<String>::new();
@@ -156,50 +248,84 @@ impl Archive {
         Ok(hs)
     }
 
-    pub fn validate(&self) -> Result<()> {
+    /// Check the archive for problems, and report them to the archive's Report.
+    ///
+    /// An `Err` is returned only if validation couldn't continue.
+    pub fn validate(&self, depth: ValidateDepth) -> Result<()> {
         // Check there's no extra top-level contents.
         self.validate_archive_dir()?;
-        self.report.print("Check blockdir...");
-        self.block_dir.validate(self.report())?;
-        self.validate_bands()?;
+        let blocks: HashMap<String, Option<u64>> = match depth {
+            ValidateDepth::Deep => {
+                self.report.note("Check blockdir...");
+                self.block_dir
+                    .validate(self.report())?
+                    .into_iter()
+                    .map(|(hash, len)| (hash, Some(len)))
+                    .collect()
+            }
+            ValidateDepth::Shallow => {
+                self.report.note("List blockdir...");
+                self.block_dir
+                    .block_names(self.report())?
+                    .into_iter()
+                    .map(|hash| (hash, None))
+                    .collect()
+            }
+        };
+        self.validate_bands(&blocks)?;
+        if depth == ValidateDepth::Deep {
+            self.validate_parity()?;
+        }
 
-        // TODO: Don't say "OK" if there were non-fatal problems.
-        self.report.print("Archive is OK.");
+        let problems = self.report.borrow_counts().count_problems();
+        if problems == 0 {
+            self.report.note("Archive is OK.");
+        } else {
+            self.report.note(&format!(
+                "Archive has {} problems.",
+                problems.separate_with_commas()
+            ));
+        }
         Ok(())
     }
 
     fn validate_archive_dir(&self) -> Result<()> {
-        self.report.print("Check archive top-level directory...");
+        self.report.note("Check archive top-level directory...");
         let (mut files, mut dirs) = list_dir(self.path())?;
 
         remove_item(&mut files, &HEADER_FILENAME);
+        remove_item(&mut files, &CONFIG_FILENAME);
         if !files.is_empty() {
-            self.report.problem(&format!(
-                "Unexpected files in archive directory {:?}: {:?}",
-                self.path(),
-                files
-            ));
+            for f in files {
+                self.report.problem(Problem::for_path(
+                    ProblemKind::UnexpectedFile,
+                    self.path().join(f),
+                    "Unexpected file in archive directory",
+                ));
+            }
         }
 
         remove_item(&mut dirs, &BLOCK_DIR);
+        remove_item(&mut dirs, &PARITY_DIR);
+        remove_item(&mut dirs, &QUARANTINE_DIR);
         dirs.sort();
         let mut bs = BTreeSet::<BandId>::new();
         for d in dirs.iter() {
             if let Ok(b) = BandId::from_string(&d) {
                 if bs.contains(&b) {
-                    self.report.problem(&format!(
-                        "Duplicated band directory in {:?}: {:?}",
-                        self.path(),
-                        d
+                    self.report.problem(Problem::for_path(
+                        ProblemKind::UnexpectedFile,
+                        self.path().join(d),
+                        "Duplicated band directory",
                     ));
                 } else {
                     bs.insert(b);
                 }
             } else {
-                self.report.problem(&format!(
-                    "Unexpected directory in {:?}: {:?}",
-                    self.path(),
-                    d
+                self.report.problem(Problem::for_path(
+                    ProblemKind::UnexpectedFile,
+                    self.path().join(d),
+                    "Unexpected directory in archive directory",
                 ));
             }
         }
@@ -207,30 +333,64 @@ impl Archive {
         Ok(())
     }
 
-    fn validate_bands(&self) -> Result<()> {
-        self.report.print("Measure stored trees...");
-        self.report.set_phase("Measure stored trees");
-        self.report.set_total_work(0);
-        let mut total_size: u64 = 0;
-        for bid in self.list_bands()?.iter() {
-            let b = StoredTree::open_incomplete_version(self, bid)?
-                .size()?
-                .file_bytes;
-            total_size += b;
-            self.report.increment_work(b);
+    /// Check the parity data, and say which damaged files it can reconstruct.
+    ///
+    /// The damage itself is reported when the blocks and bands are checked.
+    fn validate_parity(&self) -> Result<()> {
+        self.report.note("Check parity...");
+        for damage in find_parity_damage(self, self.report())? {
+            if damage.recoverable {
+                self.report.note(&format!(
+                    "Can reconstruct {} from parity, by running `conserve repair`",
+                    damage.path.display()
+                ));
+            }
         }
+        Ok(())
+    }
 
-        self.report.print(&format!(
-            "Check {} MB in stored files...",
-            (total_size / 1_000_000).separate_with_commas()
+    fn validate_bands(&self, blocks: &HashMap<String, Option<u64>>) -> Result<()> {
+        let band_ids = self.list_bands()?;
+        self.report.note(&format!(
+            "Check {} bands...",
+            band_ids.len().separate_with_commas()
         ));
-        self.report.set_total_work(total_size);
-        for bid in self.list_bands()?.iter() {
-            let b = Band::open(self, bid)?;
+        self.report.set_phase("Check bands");
+        self.report.set_total_work(band_ids.len() as u64);
+        for bid in band_ids.iter() {
+            let b = match Band::open(self, bid) {
+                Ok(b) => b,
+                Err(e) => {
+                    self.report.problem(
+                        Problem::for_path(
+                            ProblemKind::BadBand,
+                            self.path.join(bid.to_string()),
+                            "Can't read band head",
+                        )
+                        .with_error(e),
+                    );
+                    continue;
+                }
+            };
             b.validate(&self.report)?;
+            if !b.is_closed()? {
+                if Some(bid) == band_ids.last() {
+                    self.report.note(&format!(
+                        "Band {} is incomplete: perhaps a backup is running or was interrupted.",
+                        bid
+                    ));
+                } else {
+                    self.report.problem(Problem::for_path(
+                        ProblemKind::BadBand,
+                        b.path(),
+                        "Band is incomplete, but isn't the last band",
+                    ));
+                }
+            }
 
             let st = StoredTree::open_incomplete_version(self, bid)?;
-            st.validate()?;
+            st.validate(blocks)?;
+            self.report.increment_work(1);
         }
         Ok(())
     }
@@ -310,6 +470,104 @@ mod tests {
         assert!(af.block_dir.blocks(&af.report).unwrap().is_empty());
     }
 
+    #[test]
+    fn config_round_trip() {
+        let af = ScratchArchive::new();
+        assert_eq!(af.config().unwrap(), ArchiveConfig::default());
+
+        let config = ArchiveConfig {
+            excludes: vec!["/**/*.swp".to_owned(), "/tmp".to_owned()],
+        };
+        af.write_config(&config).unwrap();
+        assert_eq!(af.config().unwrap(), config);
+
+        let (file_names, _dir_names) = list_dir(af.path()).unwrap();
+        assert_eq!(file_names, &["CONFIG", "CONSERVE"]);
+
+        // Reopening the archive sees the same config, and it's not a validation problem.
+        let reopened = Archive::open(af.path(), &Report::new()).unwrap();
+        assert_eq!(reopened.config().unwrap(), config);
+        reopened.validate(ValidateDepth::Deep).unwrap();
+    }
+
+    #[test]
+    fn write_config_rejects_bad_glob() {
+        let af = ScratchArchive::new();
+        let config = ArchiveConfig {
+            excludes: vec!["[".to_owned()],
+        };
+        match af.write_config(&config) {
+            Err(Error::BadGlob(_)) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(af.config().unwrap(), ArchiveConfig::default());
+    }
+
+    #[test]
+    fn last_complete_band_before() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let b0_info = Band::open(&af, &BandId::new(&[0]))
+            .unwrap()
+            .get_info(&Report::new())
+            .unwrap();
+        let b0_end = b0_info.end_time.unwrap();
+
+        let err = af
+            .last_complete_band_before(b0_info.start_time - chrono::Duration::days(1), false)
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Archive has no bands completed before "));
+        assert!(af.last_complete_band_before(b0_end, false).is_ok());
+        assert_eq!(
+            af.last_complete_band_before(b0_end + chrono::Duration::days(1), false)
+                .unwrap()
+                .id(),
+            BandId::new(&[1])
+        );
+    }
+
+    #[test]
+    fn last_complete_band_before_skips_partial_and_unreadable_bands() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        Band::create(&af)
+            .unwrap()
+            .close_partial(af.report())
+            .unwrap();
+        fs::create_dir(af.path().join("b0003")).unwrap();
+        let later = UTC::now() + chrono::Duration::days(1);
+
+        assert_eq!(
+            af.last_complete_band_before(later, false).unwrap().id(),
+            BandId::new(&[1])
+        );
+        assert!(
+            af.report()
+                .borrow_counts()
+                .count_problems_of_kind(ProblemKind::BadBand)
+                > 0
+        );
+        assert_eq!(
+            af.last_complete_band_before(later, true).unwrap().id(),
+            BandId::new(&[2])
+        );
+    }
+
+    #[test]
+    fn list_bands_ignores_other_directories() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        fs::create_dir(af.path().join("junk")).unwrap();
+        fs::create_dir(af.path().join("b01")).unwrap();
+        assert_eq!(
+            af.list_bands().unwrap(),
+            vec![BandId::new(&[0]), BandId::new(&[1])]
+        );
+        assert_eq!(af.last_band_id().unwrap(), BandId::new(&[1]));
+    }
+
     #[test]
     fn create_bands() {
         use super::super::io::directory_exists;
@@ -335,4 +593,57 @@ mod tests {
         assert!(af.referenced_blocks().unwrap().is_empty());
         assert!(af.block_dir.blocks(&af.report).unwrap().is_empty());
     }
+
+    #[test]
+    fn validate_shallow_and_deep() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let validate = |depth| {
+            let report = Report::new();
+            Archive::open(af.path(), &report)
+                .unwrap()
+                .validate(depth)
+                .unwrap();
+            report
+        };
+        assert_eq!(
+            validate(ValidateDepth::Deep)
+                .borrow_counts()
+                .count_problems(),
+            0
+        );
+
+        // All the files have the same content, so are stored in one block.
+        let hash = StoredTree::open_last(&af)
+            .unwrap()
+            .find_entry(&Apath::from("/hello"))
+            .unwrap()
+            .unwrap()
+            .addrs[0]
+            .hash
+            .clone();
+        let block_path = af.path().join(BLOCK_DIR).join(&hash[..3]).join(&hash);
+
+        // Shallow validation doesn't read the block, so doesn't notice it's damaged.
+        fs::write(&block_path, b"garbage").unwrap();
+        assert_eq!(
+            validate(ValidateDepth::Shallow)
+                .borrow_counts()
+                .count_problems(),
+            0
+        );
+        let report = validate(ValidateDepth::Deep);
+        let counts = report.borrow_counts();
+        assert_eq!(counts.count_problems_of_kind(ProblemKind::BadBlock), 1);
+        // Two files in the first version, and three in the second.
+        assert_eq!(counts.count_problems_of_kind(ProblemKind::DamagedFile), 5);
+        assert!(counts.problems().iter().any(|p| p.path == "/subdir/subfile"
+            && p.message == format!("Block {} is missing or damaged", hash)));
+
+        fs::remove_file(&block_path).unwrap();
+        let report = validate(ValidateDepth::Shallow);
+        let counts = report.borrow_counts();
+        assert_eq!(counts.count_problems(), 5);
+        assert_eq!(counts.count_problems_of_kind(ProblemKind::DamagedFile), 5);
+    }
 }
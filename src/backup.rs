@@ -4,6 +4,10 @@
 //! Make a backup by walking a source directory and copying the contents
 //! into an archive.
 
+use std::io::Read;
+
+use chrono::UTC;
+
 use super::*;
 
 /// Accepts files to write in the archive (in apath order.)
@@ -94,6 +98,43 @@ impl tree::WriteTree for BackupWriter {
     }
 }
 
+/// Make a backup containing one file, `apath`, read from a stream such as stdin.
+///
+/// The stream is read only once, as it's stored, so it needn't be saved anywhere first.
+/// The file, and the directories above it, are given the current time as their mtime.
+/// If reading the stream fails, the band is left incomplete.
+pub fn backup_stream(archive: &Archive, apath: &Apath, content: &mut dyn Read) -> Result<()> {
+    if &apath[..] == "/" {
+        return Err(Error::InvalidApath(apath.to_string()));
+    }
+    let mtime = Some(UTC::now().timestamp() as u64);
+    let dir_entry = |apath: Apath| Entry {
+        apath,
+        kind: Kind::Dir,
+        mtime,
+        addrs: vec![],
+        target: None,
+        size: None,
+    };
+    let mut bw = BackupWriter::begin(archive)?;
+    bw.write_dir(&dir_entry("/".into()))?;
+    for (i, _) in apath.match_indices('/').skip(1) {
+        bw.write_dir(&dir_entry(apath[..i].into()))?;
+    }
+    bw.write_file(
+        &Entry {
+            apath: apath.clone(),
+            kind: Kind::File,
+            mtime,
+            addrs: vec![],
+            target: None,
+            size: None,
+        },
+        content,
+    )?;
+    bw.finish()
+}
+
 impl HasReport for BackupWriter {
     fn report(&self) -> &Report {
         &self.report
@@ -201,4 +242,33 @@ mod tests {
         assert_eq!(sf.read_to_string(&mut s).unwrap(), 0);
         assert_eq!(s.len(), 0);
     }
+
+    #[test]
+    pub fn backup_stream_as_one_file() {
+        use std::io::Read;
+
+        let af = ScratchArchive::new();
+        let apath = Apath::from("/dumps/db.sql");
+        backup_stream(&af, &apath, &mut "create table t;".as_bytes()).unwrap();
+
+        let st = StoredTree::open_last(&af).unwrap();
+        let apaths: Vec<String> = st
+            .iter_entries(af.report())
+            .unwrap()
+            .map(|e| e.unwrap().apath.into())
+            .collect();
+        assert_eq!(apaths, ["/", "/dumps", "/dumps/db.sql"]);
+        let mut s = String::new();
+        st.open_file(&apath)
+            .unwrap()
+            .read_to_string(&mut s)
+            .unwrap();
+        assert_eq!(s, "create table t;");
+        assert_eq!(1, af.report().get_count("file"));
+
+        match backup_stream(&af, &Apath::from("/"), &mut "".as_bytes()) {
+            Err(Error::InvalidApath(_)) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
 }
@@ -12,11 +12,12 @@
 //! StoredTree rather than the Band itself.
 
 use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
 
 use chrono::{DateTime, TimeZone, UTC};
 
-use super::io::file_exists;
+use super::io::{ensure_dir_exists, file_exists};
 use super::jsonio;
 use super::misc::remove_item;
 use super::*;
@@ -42,6 +43,11 @@ struct Head {
 #[derive(Debug, Serialize, Deserialize)]
 struct Tail {
     end_time: i64,
+
+    /// True if the band was closed by `repair` after an interrupted backup, so its index
+    /// may not cover the whole source tree.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    partial: bool,
 }
 
 /// Readonly summary info about a band, from `Band::get_info`.
@@ -54,6 +60,10 @@ pub struct Info {
 
     /// Time this band was completed, if it is complete.
     pub end_time: Option<DateTime<UTC>>,
+
+    /// True if this band was closed after its backup was interrupted, so it may hold only
+    /// part of the source tree.
+    pub is_partial: bool,
 }
 
 impl Band {
@@ -61,33 +71,89 @@ impl Band {
     ///
     /// The Band gets the next id after those that already exist.
     pub fn create(archive: &Archive) -> Result<Band> {
-        let new_band_id = match archive.last_band_id() {
-            Err(Error::ArchiveEmpty) => BandId::zero(),
-            Ok(b) => b.next_sibling(),
-            Err(e) => return Err(e),
-        };
-        Band::create_specific_id(archive, new_band_id)
+        Band::create_specific_id(archive, Band::next_id(archive)?)
+    }
+
+    /// Return the id of the next band to be created in the archive.
+    pub(crate) fn next_id(archive: &Archive) -> Result<BandId> {
+        match archive.last_band_id() {
+            Err(Error::ArchiveEmpty) => Ok(BandId::zero()),
+            Ok(b) => Ok(b.next_sibling()),
+            Err(e) => Err(e),
+        }
     }
 
     /// Create a Band with a given id.
     fn create_specific_id(archive: &Archive, id: BandId) -> Result<Band> {
-        let archive_dir = archive.path();
-        let new = Band::new(archive_dir, id);
+        let head = Head {
+            start_time: UTC::now().timestamp(),
+        };
+        Band::create_with_head(archive, id, &head)
+    }
+
+    /// Create a band with a given id, as a copy of `source`, which may be in another archive.
+    ///
+    /// The new band has the same start time as the source, and is incomplete until
+    /// `copy_tail_from` is called.
+    pub(crate) fn create_copy(archive: &Archive, id: BandId, source: &Band) -> Result<Band> {
+        let head = source.read_head(archive.report())?;
+        Band::create_with_head(archive, id, &head)
+    }
+
+    /// Reopen an incomplete band that was being copied from `source` when the copy was
+    /// interrupted, so that it can be copied again.
+    ///
+    /// It's an error if the band is complete, or was started at a different time from
+    /// `source`, since then it isn't a copy of it.
+    pub(crate) fn reopen_copy(archive: &Archive, id: BandId, source: &Band) -> Result<Band> {
+        let report = archive.report();
+        let head = source.read_head(report)?;
+        let band = Band::new(archive.path(), id);
+        // The copy might have been interrupted before the head was written.
+        let is_copy = !file_exists(&band.head_path())?
+            || band.read_head(report)?.start_time == head.start_time;
+        if band.is_closed()? || !is_copy {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists and isn't an incomplete copy", band.id),
+            )
+            .into());
+        }
+        ensure_dir_exists(&band.index_dir_path)?;
+        jsonio::write_serde(&band.head_path(), &head, report)?;
+        Ok(band)
+    }
+
+    fn create_with_head(archive: &Archive, id: BandId, head: &Head) -> Result<Band> {
+        let new = Band::new(archive.path(), id);
 
         fs::create_dir(&new.path_buf)?;
         fs::create_dir(&new.index_dir_path)?;
 
-        let head = Head {
-            start_time: UTC::now().timestamp(),
-        };
-        jsonio::write_serde(&new.head_path(), &head, archive.report())?;
+        jsonio::write_serde(&new.head_path(), head, archive.report())?;
         Ok(new)
     }
 
     /// Mark this band closed: no more blocks should be written after this.
     pub fn close(&self, report: &Report) -> Result<()> {
+        self.write_tail(false, report)
+    }
+
+    /// Close a band whose backup was interrupted, marking it as partial, so that it can be
+    /// read like a complete band even though it may hold only part of the source tree.
+    pub fn close_partial(&self, report: &Report) -> Result<()> {
+        self.write_tail(true, report)
+    }
+
+    /// Close this band with a copy of the tail of `source`, so it has the same end time.
+    pub(crate) fn copy_tail_from(&self, source: &Band, report: &Report) -> Result<()> {
+        jsonio::write_serde(&self.tail_path(), &source.read_tail(report)?, report)
+    }
+
+    fn write_tail(&self, partial: bool, report: &Report) -> Result<()> {
         let tail = Tail {
             end_time: UTC::now().timestamp(),
+            partial,
         };
         jsonio::write_serde(&self.tail_path(), &tail, report)
     }
@@ -155,16 +221,21 @@ impl Band {
     pub fn get_info(&self, report: &Report) -> Result<Info> {
         let head = self.read_head(&report)?;
         let is_closed = self.is_closed()?;
-        let end_time = if is_closed {
-            Some(UTC.timestamp(self.read_tail(&report)?.end_time, 0))
+        let (end_time, is_partial) = if is_closed {
+            let tail = self.read_tail(&report)?;
+            (
+                Some(time_from_file(tail.end_time, &self.tail_path())?),
+                tail.partial,
+            )
         } else {
-            None
+            (None, false)
         };
         Ok(Info {
             id: self.id.clone(),
             is_closed,
-            start_time: UTC.timestamp(head.start_time, 0),
+            start_time: time_from_file(head.start_time, &self.head_path())?,
             end_time,
+            is_partial,
         })
     }
 
@@ -172,46 +243,61 @@ impl Band {
     ///
     /// Not very useful at the moment as it doesn't include the blocks.
     pub fn get_disk_size(&self) -> Result<u64> {
-        // TODO: Better handling than panic of unexpected errors.
         let mut total = 0u64;
         for entry in walkdir::WalkDir::new(self.path()) {
-            match entry {
-                Ok(entry) => {
-                    if entry.file_type().is_file() {
-                        total += entry.metadata().unwrap().len();
-                    }
-                }
-                Err(e) => return Err(e.into_io_error().unwrap().into()),
+            let entry = entry.map_err(io::Error::from)?;
+            if entry.file_type().is_file() {
+                total += entry.metadata().map_err(io::Error::from)?.len();
             }
         }
         Ok(total)
     }
 
+    /// Check the band directory, its tail and its index, reporting any problems to `report`.
     pub fn validate(&self, report: &Report) -> Result<()> {
         self.validate_band_dir(report)?;
+        if self.is_closed()? {
+            if let Err(e) = self.read_tail(report) {
+                report.problem(
+                    Problem::for_path(
+                        ProblemKind::BadBand,
+                        self.tail_path(),
+                        "Can't read band tail",
+                    )
+                    .with_error(e),
+                );
+            }
+        }
+        if self.index_dir_path.is_dir() {
+            self.index().validate(report)?;
+        }
         Ok(())
     }
 
     fn validate_band_dir(&self, report: &Report) -> Result<()> {
         let (mut files, dirs) = list_dir(self.path())?;
         if !files.contains(&HEAD_FILENAME.to_string()) {
-            report.problem(&format!("No band head file in {:?}", self.path()));
+            report.problem(Problem::for_path(
+                ProblemKind::MissingFile,
+                self.path().join(HEAD_FILENAME),
+                "No band head file",
+            ));
         }
         remove_item(&mut files, &HEAD_FILENAME);
         remove_item(&mut files, &TAIL_FILENAME);
-        if !files.is_empty() {
-            report.problem(&format!(
-                "Unexpected files in {:?}: {:?}",
-                self.path(),
-                files
+        for f in files {
+            report.problem(Problem::for_path(
+                ProblemKind::UnexpectedFile,
+                self.path().join(f),
+                "Unexpected file in band directory",
             ));
         }
 
         if dirs != [INDEX_DIR.to_string()] {
-            report.problem(&format!(
-                "Incongruous directories in {:?}: {:?}",
+            report.problem(Problem::for_path(
+                ProblemKind::UnexpectedFile,
                 self.path(),
-                dirs
+                &format!("Incongruous directories {:?} in band directory", dirs),
             ));
         }
 
@@ -219,6 +305,13 @@ impl Band {
     }
 }
 
+/// Convert a time read from a band head or tail file, which might be out of range.
+fn time_from_file(secs: i64, path: &Path) -> Result<DateTime<UTC>> {
+    UTC.timestamp_opt(secs, 0)
+        .single()
+        .ok_or_else(|| Error::BandCorrupt(path.to_path_buf()))
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs;
@@ -256,6 +349,7 @@ mod tests {
         let info = band2.get_info(&Report::new()).expect("get_info failed");
         assert_eq!(info.id.to_string(), "b0000");
         assert_eq!(info.is_closed, true);
+        assert!(!info.is_partial);
         let dur = info.end_time.expect("info has an end_time") - info.start_time;
         // Test should have taken (much) less than 5s between starting and finishing
         // the band.  (It might fail if you set a breakpoint right there.)
@@ -266,6 +360,21 @@ mod tests {
         assert!(bytes > 10 && bytes < 8000, bytes);
     }
 
+    #[test]
+    fn close_partial_band() {
+        let af = ScratchArchive::new();
+        let report = &Report::new();
+        let band = Band::create(&af).unwrap();
+        band.close_partial(report).unwrap();
+        assert!(band.is_closed().unwrap());
+        let info = band.get_info(report).unwrap();
+        assert!(info.is_partial);
+        assert!(info.end_time.is_some());
+        assert!(fs::read_to_string(band.path().join("BANDTAIL"))
+            .unwrap()
+            .contains("\"partial\":true"));
+    }
+
     #[test]
     fn create_existing_band() {
         let af = ScratchArchive::new();
@@ -278,4 +387,16 @@ mod tests {
             panic!("expected an ioerror, got {:?}", e);
         };
     }
+
+    #[test]
+    fn out_of_range_time_is_an_error() {
+        let af = ScratchArchive::new();
+        let band = Band::create(&af).unwrap();
+        band.close(af.report()).unwrap();
+        fs::write(band.tail_path(), format!("{{\"end_time\": {}}}", i64::MAX)).unwrap();
+        match band.get_info(af.report()) {
+            Err(Error::BandCorrupt(path)) => assert_eq!(path, band.tail_path()),
+            other => panic!("unexpected result {:?}", other.map(|i| i.end_time)),
+        }
+    }
 }
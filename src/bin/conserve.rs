@@ -3,6 +3,9 @@
 
 //! Command-line entry point for Conserve backups.
 
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
 use std::path::Path;
 
 #[macro_use]
@@ -12,7 +15,7 @@ extern crate chrono;
 extern crate globset;
 extern crate thousands;
 
-use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, SubCommand};
 use thousands::Separable;
 
 extern crate conserve;
@@ -20,21 +23,57 @@ use conserve::*;
 
 use conserve::Result;
 
-fn main() -> conserve::Result<()> {
-    let matches = make_clap().get_matches();
-    let ui_name = matches.value_of("ui").unwrap_or("auto");
+/// Process exit codes.
+mod exit_code {
+    /// The command succeeded with no problems.
+    pub const OK: i32 = 0;
+    /// The command failed with an error.
+    pub const ERROR: i32 = 1;
+    /// The command line couldn't be parsed.
+    pub const USAGE: i32 = 2;
+    /// The command completed, but reported problems such as source files that couldn't
+    /// be read.
+    pub const PROBLEMS: i32 = 3;
+    /// `validate` found problems in the archive.
+    pub const INVALID_ARCHIVE: i32 = 4;
+    /// The archive directory doesn't exist or isn't a Conserve archive.
+    pub const NOT_AN_ARCHIVE: i32 = 5;
+}
+
+fn main() {
+    let matches = match make_clap().get_matches_safe() {
+        Ok(matches) => matches,
+        Err(e) => match e.kind {
+            clap::ErrorKind::HelpDisplayed | clap::ErrorKind::VersionDisplayed => e.exit(),
+            _ => usage_error(e),
+        },
+    };
+    let (n, sm) = rollup_subcommands(&matches);
+    let ui_name = if sm.is_present("json") {
+        "json"
+    } else {
+        matches.value_of("ui").unwrap_or("auto")
+    };
     let no_progress = matches.is_present("no-progress");
     let ui = UI::by_name(ui_name, !no_progress).expect("Couldn't make UI");
     let mut report = Report::with_ui(ui);
 
-    let (n, sm) = rollup_subcommands(&matches);
     let c = match n.as_str() {
         "backup" => backup,
+        "cat" => cat,
+        "copy" => copy,
+        "export" => export,
         "debug block list" => debug_block_list,
         "debug block referenced" => debug_block_referenced,
         "diff" => diff,
+        "excludes set" => excludes_set,
+        "excludes show" => excludes_show,
         "init" => init,
         "ls" => ls,
+        #[cfg(all(unix, feature = "fuse"))]
+        "mount" => mount,
+        "parity" => parity,
+        "repair" => repair_archive,
         "restore" => restore,
         "source ls" => source_ls,
         "source size" => source_size,
@@ -50,12 +89,28 @@ fn main() -> conserve::Result<()> {
     if matches.is_present("stats") {
         report.print(&format!("{}", report));
     }
+    let problems = report.borrow_counts().count_problems();
+    let code = match result {
+        Err(Error::NotAnArchive(_)) => exit_code::NOT_AN_ARCHIVE,
+        Err(_) => exit_code::ERROR,
+        Ok(()) if problems == 0 => exit_code::OK,
+        Ok(()) if n == "validate" => exit_code::INVALID_ARCHIVE,
+        Ok(()) if matches.is_present("fail-on-warning") => exit_code::ERROR,
+        Ok(()) => exit_code::PROBLEMS,
+    };
     if let Err(ref e) = result {
         show_chained_errors(&report, e);
         // TODO: Maybe show backtraces once they're available in stable
         // Rust Errors.
+        eprintln!("Error: {:?}", e);
     }
-    result
+    std::process::exit(code)
+}
+
+/// Show a command line error and exit.
+fn usage_error(e: clap::Error) -> ! {
+    eprintln!("{}", e.message);
+    std::process::exit(exit_code::USAGE)
 }
 
 fn rollup_subcommands<'a>(matches: &'a ArgMatches) -> (String, &'a ArgMatches<'a>) {
@@ -87,6 +142,24 @@ fn make_clap<'a, 'b>() -> clap::App<'a, 'b> {
             .value_name("VERSION")
     };
 
+    fn at_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("at")
+            .help(
+                "Use the last complete backup version finished by this time, such as \
+                 \"2019-03-01 14:00\" or \"yesterday\"",
+            )
+            .long("at")
+            .takes_value(true)
+            .value_name("TIME")
+            .conflicts_with("backup")
+    };
+
+    fn json_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("json")
+            .long("json")
+            .help("Print results and problems as JSON, one object per line")
+    };
+
     fn exclude_arg<'a, 'b>() -> Arg<'a, 'b> {
         Arg::with_name("exclude")
             .long("exclude")
@@ -98,6 +171,70 @@ fn make_clap<'a, 'b>() -> clap::App<'a, 'b> {
             .help("Exclude files that match the provided glob pattern")
     };
 
+    fn exclude_from_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("exclude-from")
+            .long("exclude-from")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("FILE")
+            .help("Exclude files that match glob patterns read from a file, one per line")
+    };
+
+    fn include_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("include")
+            .long("include")
+            .short("i")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("GLOB")
+            .help(
+                "Include only files that match the provided glob pattern, \
+                 and the directories containing them",
+            )
+    };
+
+    fn one_file_system_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("one-file-system")
+            .long("one-file-system")
+            .help("Don't descend into directories on other filesystems")
+    };
+
+    fn metadata_exclude_args<'a, 'b>() -> [Arg<'a, 'b>; 4] {
+        [
+            Arg::with_name("exclude-larger-than")
+                .long("exclude-larger-than")
+                .takes_value(true)
+                .value_name("SIZE")
+                .help("Exclude files larger than SIZE bytes, or with a suffix K, M, G or T"),
+            Arg::with_name("exclude-older-than")
+                .long("exclude-older-than")
+                .takes_value(true)
+                .value_name("DATE")
+                .help("Exclude files last modified before DATE"),
+            Arg::with_name("exclude-newer-than")
+                .long("exclude-newer-than")
+                .takes_value(true)
+                .value_name("DATE")
+                .help("Exclude files last modified after DATE"),
+            Arg::with_name("exclude-kind")
+                .long("exclude-kind")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("KIND")
+                .possible_values(&["file", "dir", "symlink", "unknown"])
+                .help("Exclude all entries of this kind"),
+        ]
+    };
+
+    fn archive_excludes_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("archive-excludes")
+            .long("archive-excludes")
+            .help("Also apply the default excludes stored in the archive")
+    };
+
     fn incomplete_arg<'a, 'b>() -> Arg<'a, 'b> {
         Arg::with_name("incomplete")
             .help("Read from incomplete (truncated) version")
@@ -108,6 +245,31 @@ fn make_clap<'a, 'b>() -> clap::App<'a, 'b> {
         Arg::with_name("v").short("v").help("Print filenames")
     };
 
+    /// `mount` is only built with the `fuse` feature.
+    #[cfg(all(unix, feature = "fuse"))]
+    fn mount_subcommands<'a, 'b>() -> Vec<App<'a, 'b>> {
+        vec![SubCommand::with_name("mount")
+            .about("Mount an archive as a read-only filesystem")
+            .after_help(
+                "\
+                 Each closed version is a directory named after its band, and `latest` \
+                 links to the most recent. Files are read from the archive as they're \
+                 opened; damaged blocks give I/O errors and are reported as problems.\n\n\
+                 This runs until the filesystem is unmounted, or it's interrupted.",
+            )
+            .arg(archive_arg())
+            .arg(
+                Arg::with_name("mountpoint")
+                    .help("Empty directory to mount on")
+                    .required(true),
+            )]
+    }
+
+    #[cfg(not(all(unix, feature = "fuse")))]
+    fn mount_subcommands<'a, 'b>() -> Vec<App<'a, 'b>> {
+        Vec::new()
+    }
+
     App::new("conserve")
         .about("A robust backup tool <https://github.com/sourcefrog/conserve/>")
         .author(crate_authors!())
@@ -131,6 +293,17 @@ fn make_clap<'a, 'b>() -> clap::App<'a, 'b> {
                 .long("stats")
                 .help("Show stats about IO, timing, and compression"),
         )
+        .arg(
+            Arg::with_name("fail-on-warning")
+                .long("fail-on-warning")
+                .help("Exit with status 1, rather than 3, if any problems were reported"),
+        )
+        .after_help(
+            "Exit status is 0 on success; 1 if the command failed; 2 if the command line \
+             is invalid; 3 if the command completed but reported problems, such as source \
+             files that couldn't be read; 4 if `validate` found problems; and 5 if the \
+             archive doesn't exist.",
+        )
         .subcommand(
             SubCommand::with_name("debug")
                 .about("Show developer-oriented information")
@@ -152,8 +325,44 @@ fn make_clap<'a, 'b>() -> clap::App<'a, 'b> {
         .subcommand(
             SubCommand::with_name("validate")
                 .about("Check whether an archive is internally consistent")
+                .arg(archive_arg())
+                .arg(
+                    Arg::with_name("shallow")
+                        .long("shallow")
+                        .help("Check referenced blocks exist, without reading their contents"),
+                )
+                .arg(json_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("parity")
+                .about("Write parity data, from which damaged blocks can be reconstructed")
+                .after_help(
+                    "\
+                     Parity is written for every block, and every index hunk of a complete \
+                     band, that isn't already protected. `conserve repair` reconstructs \
+                     damaged files from it.",
+                )
                 .arg(archive_arg()),
         )
+        .subcommand(
+            SubCommand::with_name("repair")
+                .about("Repair damage to an archive, such as from an interrupted backup")
+                .after_help(
+                    "\
+                     Incomplete bands are closed and marked as partial, after removing \
+                     any unreadable index hunks from their end. Damaged blocks and index \
+                     hunks are reconstructed from parity data, if possible, and otherwise \
+                     damaged blocks are moved into the archive's quarantine directory.\n\n\
+                     The repairs are always listed first. Nothing is changed unless \
+                     --apply is given. Don't run this while a backup is in progress.",
+                )
+                .arg(archive_arg())
+                .arg(
+                    Arg::with_name("apply")
+                        .long("apply")
+                        .help("Make the repairs, rather than only listing them"),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("init")
                 .display_order(1)
@@ -165,6 +374,11 @@ fn make_clap<'a, 'b>() -> clap::App<'a, 'b> {
                              should either not exist or be an empty directory",
                         )
                         .required(true),
+                )
+                .arg(exclude_arg().help("Set a default exclusion pattern for the archive"))
+                .arg(
+                    exclude_from_arg()
+                        .help("Set default exclusion patterns for the archive, read from a file"),
                 ),
         )
         .subcommand(
@@ -174,20 +388,130 @@ fn make_clap<'a, 'b>() -> clap::App<'a, 'b> {
                 .arg(archive_arg())
                 .arg(
                     Arg::with_name("source")
-                        .help("Backup from this directory")
-                        .required(true),
+                        .help("Backup from these files or directories")
+                        .required_unless_one(&["from-tar", "stdin"])
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::with_name("from-tar")
+                        .long("from-tar")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .conflicts_with_all(&["source", "include", "one-file-system"])
+                        .help("Backup the contents of a tar file, or of stdin if FILE is -"),
+                )
+                .arg(
+                    Arg::with_name("stdin")
+                        .long("stdin")
+                        .requires("name")
+                        .conflicts_with_all(&["source", "from-tar", "include", "one-file-system"])
+                        .help("Backup stdin as a single file"),
+                )
+                .arg(
+                    Arg::with_name("name")
+                        .long("name")
+                        .takes_value(true)
+                        .value_name("APATH")
+                        .requires("stdin")
+                        .help("Store stdin as this file, like /db.sql"),
                 )
+                .after_help(
+                    "\
+                     If several sources are given, the backup is rooted at the deepest \
+                     directory containing all of them, and contains only the sources and \
+                     the directories above them.\n\n\
+                     With --from-tar, the whole tar stream is read first, spooling file \
+                     contents to a temporary file, because the backup must be stored in \
+                     sorted order.\n\n\
+                     With --stdin, stdin is stored as it's read, as a single file named by \
+                     --name, with the current time as its modification time.",
+                )
+                .arg(include_arg())
+                .arg(one_file_system_arg())
                 .arg(exclude_arg())
+                .args(&metadata_exclude_args())
+                .arg(exclude_from_arg())
+                .arg(
+                    Arg::with_name("no-archive-excludes")
+                        .long("no-archive-excludes")
+                        .help("Don't apply the default excludes stored in the archive"),
+                )
+                .arg(
+                    Arg::with_name("parity")
+                        .long("parity")
+                        .help("Write parity data for the new blocks and index afterwards"),
+                )
                 .arg(verbose_arg()),
         )
         .subcommand(
             SubCommand::with_name("diff")
-                .about("Diff source against a stored tree")
+                .about("Diff source against a stored tree, or two stored trees")
                 .arg(archive_arg())
+                .arg(
+                    backup_arg()
+                        .help("Backup version number; give twice to compare two versions")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .max_values(2),
+                )
+                .arg(at_arg())
                 .arg(
                     Arg::with_name("source")
-                        .help("Diff against this source")
-                        .required(true),
+                        .help("Diff against these sources")
+                        .multiple(true),
+                )
+                .arg(include_arg())
+                .arg(one_file_system_arg())
+                .arg(exclude_arg())
+                .args(&metadata_exclude_args())
+                .arg(exclude_from_arg())
+                .arg(archive_excludes_arg())
+                .arg(
+                    Arg::with_name("compare-content")
+                        .long("compare-content")
+                        .help("Read files of the same size to check whether their content differs"),
+                )
+                .arg(
+                    Arg::with_name("include-unchanged")
+                        .long("include-unchanged")
+                        .help("Also list entries that are the same on both sides"),
+                )
+                .arg(json_arg())
+                .after_help(
+                    "Each line shows how an entry differs between the stored tree and the \
+                     source, or between the first and second stored trees:\n\n\
+                     left      only in the stored tree (or the first one)\n\
+                     right     only in the source (or the second stored tree)\n\
+                     changed   the content differs\n\
+                     metadata  only the modification time differs\n\
+                     kind      the kind of entry changed, such as from a file to a directory\n\
+                     same      unchanged (only with --include-unchanged)\n\n\
+                     Without --compare-content, files of the same size in the source are \
+                     assumed to have changed if their modification time differs. Files in two \
+                     stored trees are compared by their block addresses, without reading them.",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("excludes")
+                .about("Manage the default exclusion patterns stored in an archive")
+                .subcommand(
+                    SubCommand::with_name("set")
+                        .about("Replace the archive's default exclusion patterns")
+                        .after_help(
+                            "\
+                             The default excludes are applied by `conserve backup`, \
+                             unless it's given --no-archive-excludes, and by `ls`, `diff` \
+                             and `restore` when they're given --archive-excludes. \
+                             Giving no patterns clears the defaults.",
+                        )
+                        .arg(archive_arg())
+                        .arg(exclude_arg())
+                        .arg(exclude_from_arg()),
+                )
+                .subcommand(
+                    SubCommand::with_name("show")
+                        .about("List the archive's default exclusion patterns")
+                        .arg(archive_arg()),
                 ),
         )
         .subcommand(
@@ -196,25 +520,87 @@ fn make_clap<'a, 'b>() -> clap::App<'a, 'b> {
                 .about("Copy a backup tree out of an archive")
                 .arg(archive_arg())
                 .arg(backup_arg())
+                .arg(at_arg())
                 .arg(incomplete_arg())
                 .after_help(
                     "\
                      Conserve will by default refuse to restore incomplete versions, \
                      to prevent you thinking you restored the whole tree when it may \
                      be truncated.  You can override this with --incomplete, or \
-                     select an older version with --backup.",
+                     select an older version with --backup.\n\n\
+                     Given one or more apaths, such as /home/mbp/Documents, only \
+                     those files or directories, and the directories containing them, \
+                     are restored.  --only restores entries that match a glob pattern.",
                 )
                 .arg(
                     Arg::with_name("destination")
                         .help("Restore to this new directory")
                         .required(true),
                 )
+                .arg(
+                    Arg::with_name("apath")
+                        .help("Restore only these files or directories")
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::with_name("only")
+                        .long("only")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("GLOB")
+                        .help("Restore only entries matching this glob pattern"),
+                )
                 .arg(
                     Arg::with_name("force-overwrite")
                         .long("force-overwrite")
                         .help("Overwrite existing destination directory"),
                 )
+                .arg(
+                    Arg::with_name("overwrite")
+                        .long("overwrite")
+                        .takes_value(true)
+                        .value_name("WHEN")
+                        .possible_values(&["never", "changed", "different", "always"])
+                        .help(
+                            "Restore into an existing directory, replacing existing files: \
+                             never; if their size or mtime changed; if their content is \
+                             different; or always",
+                        ),
+                )
+                .group(
+                    ArgGroup::with_name("overwrite-mode")
+                        .args(&["overwrite", "force-overwrite"])
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::with_name("delete")
+                        .long("delete")
+                        .requires("overwrite-mode")
+                        .help(
+                            "Delete files from the destination that are not in the backup; \
+                             needs --overwrite",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .short("n")
+                        .help("Show what would be changed, without changing anything"),
+                )
+                .arg(Arg::with_name("salvage").long("salvage").help(
+                    "List the files that couldn't be restored from a damaged archive; \
+                         restore carries on past damaged files even without this",
+                ))
+                .arg(
+                    Arg::with_name("zero-fill")
+                        .long("zero-fill")
+                        .requires("salvage")
+                        .help("Write damaged files with zeros in place of unreadable blocks"),
+                )
                 .arg(exclude_arg())
+                .arg(exclude_from_arg())
+                .arg(archive_excludes_arg())
                 .arg(verbose_arg()),
         )
         .subcommand(
@@ -238,17 +624,116 @@ fn make_clap<'a, 'b>() -> clap::App<'a, 'b> {
                         .help("List just version name without details")
                         .long("short")
                         .short("s"),
+                )
+                .arg(json_arg().conflicts_with("short")),
+        )
+        .subcommand(
+            SubCommand::with_name("cat")
+                .display_order(6)
+                .about("Write the contents of one stored file to stdout")
+                .arg(archive_arg())
+                .arg(
+                    Arg::with_name("apath")
+                        .help("Path of the file within the backup, like /etc/hosts")
+                        .required(true),
+                )
+                .arg(backup_arg())
+                .arg(at_arg())
+                .arg(incomplete_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("copy")
+                .about("Copy backup versions from one archive into another")
+                .after_help(
+                    "\
+                     Only the blocks that the destination archive doesn't already have \
+                     are copied, so this can keep a replica of an archive up to date. \
+                     The destination must already have been created by `conserve init`.\n\n\
+                     By default, every complete version that isn't already in the \
+                     destination is copied, keeping its version number. With --backup, \
+                     only the given versions are copied. --fresh-ids gives each copy the \
+                     next version number in the destination; versions with the same start \
+                     and end times as one already in the destination are then taken to \
+                     have been copied already.",
+                )
+                .arg(
+                    Arg::with_name("source")
+                        .help("Copy from this archive")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("destination")
+                        .help("Copy into this archive")
+                        .required(true),
+                )
+                .arg(
+                    backup_arg()
+                        .help("Copy this version; may be given several times")
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("fresh-ids")
+                        .long("fresh-ids")
+                        .help("Number copies after the versions in the destination"),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Write a backup version as a tar or zip file")
+                .after_help(
+                    "\
+                     Files are written with their stored paths and modification times, \
+                     mode 0644, and directories with mode 0755, since Conserve doesn't \
+                     store permissions. Zip files can't hold symlinks, so they're skipped \
+                     and reported as problems.\n\n\
+                     Without --output, or with `-o -`, the export is written to stdout, \
+                     and problems to stderr.",
+                )
+                .arg(archive_arg())
+                .arg(backup_arg())
+                .arg(at_arg())
+                .arg(incomplete_arg())
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .value_name("FORMAT")
+                        .possible_values(&["tar", "zip"])
+                        .default_value("tar")
+                        .help("Format of the exported file"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("Write to this file, rather than stdout"),
+                )
+                .arg(
+                    Arg::with_name("zero-fill")
+                        .long("zero-fill")
+                        .help("Write damaged files with zeros in place of unreadable blocks"),
+                )
+                .arg(exclude_arg())
+                .arg(exclude_from_arg())
+                .arg(archive_excludes_arg()),
+        )
         .subcommand(
             SubCommand::with_name("ls")
                 .display_order(5)
                 .about("List files in a backup version")
                 .arg(archive_arg())
                 .arg(backup_arg())
+                .arg(at_arg())
                 .arg(exclude_arg())
-                .arg(incomplete_arg()),
+                .arg(exclude_from_arg())
+                .arg(archive_excludes_arg())
+                .arg(incomplete_arg())
+                .arg(json_arg()),
         )
+        .subcommands(mount_subcommands())
         .subcommand(
             SubCommand::with_name("source")
                 .about("Operate on source directories")
@@ -257,19 +742,32 @@ fn make_clap<'a, 'b>() -> clap::App<'a, 'b> {
                         .about("Recursive list files from source directory")
                         .arg(
                             Arg::with_name("source")
-                                .help("Source directory")
-                                .required(true),
+                                .help("Source files or directories")
+                                .required(true)
+                                .multiple(true),
                         )
-                        .arg(exclude_arg()),
+                        .arg(include_arg())
+                        .arg(one_file_system_arg())
+                        .arg(exclude_arg())
+                        .args(&metadata_exclude_args())
+                        .arg(exclude_from_arg())
+                        .arg(json_arg()),
                 )
                 .subcommand(
                     SubCommand::with_name("size")
                         .about("Show the size of a source directory")
                         .arg(
                             Arg::with_name("source")
-                                .help("Source directory")
-                                .required(true),
-                        ),
+                                .help("Source files or directories")
+                                .required(true)
+                                .multiple(true),
+                        )
+                        .arg(include_arg())
+                        .arg(one_file_system_arg())
+                        .arg(exclude_arg())
+                        .args(&metadata_exclude_args())
+                        .arg(exclude_from_arg())
+                        .arg(json_arg()),
                 ),
         )
         .subcommand(
@@ -279,69 +777,220 @@ fn make_clap<'a, 'b>() -> clap::App<'a, 'b> {
                     SubCommand::with_name("size")
                         .about("Show the size of a stored tree (as it would be when restored)")
                         .arg(archive_arg())
-                        .arg(backup_arg()),
+                        .arg(backup_arg())
+                        .arg(at_arg())
+                        .arg(json_arg()),
                 ),
         )
 }
 
 fn show_chained_errors(report: &Report, e: &dyn std::error::Error) {
-    report.problem(&format!("{}", e));
+    report.error(&format!("{}", e));
     let mut ce = e;
     while let Some(c) = ce.source() {
-        report.problem(&format!("  caused by: {}", c));
+        report.error(&format!("  caused by: {}", c));
         ce = c;
     }
 }
 
 fn init(subm: &ArgMatches, report: &Report) -> Result<()> {
     let archive_path = subm.value_of("archive").expect("'archive' arg not found");
-    Archive::create(archive_path).and(Ok(()))?;
+    let excludes = exclude_patterns_from_options(subm)?;
+    // Check the patterns are valid before creating anything.
+    excludes::from_strings(&excludes)?;
+    let archive = Archive::create(archive_path)?;
+    if !excludes.is_empty() {
+        archive.write_config(&ArchiveConfig { excludes })?;
+    }
     report.print(&format!("Created new archive in {}", archive_path));
     Ok(())
 }
 
 fn backup(subm: &ArgMatches, report: &Report) -> Result<()> {
     let archive = Archive::open(subm.value_of("archive").unwrap(), &report)?;
-    let lt = live_tree_from_options(subm, report)?;
-    let mut bw = BackupWriter::begin(&archive)?;
-    copy_tree(&lt, &mut bw)?;
+    let archive_excludes = if subm.is_present("no-archive-excludes") {
+        None
+    } else {
+        Some(&archive)
+    };
+    // Open the source before starting the band, so that bad options don't leave an
+    // incomplete band.
+    if subm.is_present("stdin") {
+        let apath = apath_from_arg(subm.value_of("name").unwrap())?;
+        let stdin = std::io::stdin();
+        backup_stream(&archive, &apath, &mut stdin.lock())?;
+    } else if let Some(tar_path) = subm.value_of("from-tar") {
+        let tt = tar_tree_from_options(subm, tar_path, archive_excludes, report)?;
+        copy_tree(&tt, &mut BackupWriter::begin(&archive)?)?;
+    } else {
+        let lt = live_tree_from_options(subm, archive_excludes, report)?;
+        copy_tree(&lt, &mut BackupWriter::begin(&archive)?)?;
+    }
     report.print("Backup complete.");
     report.print(&report.borrow_counts().summary_for_backup());
+    if subm.is_present("parity") {
+        print_parity_groups(write_parity(&archive)?, report);
+    }
     Ok(())
 }
 
+fn parity(subm: &ArgMatches, report: &Report) -> Result<()> {
+    let archive = Archive::open(subm.value_of("archive").unwrap(), report)?;
+    print_parity_groups(write_parity(&archive)?, report);
+    Ok(())
+}
+
+fn print_parity_groups(n_groups: usize, report: &Report) {
+    report.print(&format!(
+        "Wrote {} parity groups.",
+        n_groups.separate_with_commas()
+    ));
+}
+
 fn diff(subm: &ArgMatches, report: &Report) -> Result<()> {
+    let band_ids = subm
+        .values_of("backup")
+        .into_iter()
+        .flatten()
+        .map(BandId::from_string)
+        .collect::<Result<Vec<BandId>>>()?;
+    if band_ids.len() == 2 {
+        if subm.is_present("source") {
+            usage_error(clap::Error::with_description(
+                "A source can't be given when comparing two backup versions",
+                clap::ErrorKind::ArgumentConflict,
+            ));
+        }
+        // These only choose a version, or how to read the source.
+        for option in &["at", "incomplete", "one-file-system"] {
+            if subm.is_present(option) {
+                usage_error(clap::Error::with_description(
+                    &format!(
+                        "--{} can't be given when comparing two backup versions",
+                        option
+                    ),
+                    clap::ErrorKind::ArgumentConflict,
+                ));
+            }
+        }
+        let archive = Archive::open(subm.value_of("archive").unwrap(), report)?;
+        let excludes = excludes_from_options(subm, archive_excludes_from_option(subm, &archive))?;
+        let includes = includes_from_options(subm)?;
+        let open = |band_id: &BandId| -> Result<StoredTree> {
+            Ok(StoredTree::open_version(&archive, band_id)?
+                .with_excludes(excludes.clone())
+                .with_includes(includes.clone()))
+        };
+        print_diff(&open(&band_ids[0])?, &open(&band_ids[1])?, subm, report)
+    } else {
+        if !subm.is_present("source") {
+            usage_error(clap::Error::with_description(
+                "A source directory is required, unless two backup versions are given",
+                clap::ErrorKind::MissingRequiredArgument,
+            ));
+        }
+        let st = stored_tree_from_options(subm, report)?;
+        let lt = live_tree_from_options(
+            subm,
+            archive_excludes_from_option(subm, st.archive()),
+            report,
+        )?;
+        print_diff(&st, &lt, subm, report)
+    }
+}
+
+/// Print the differences between two trees, one line per entry.
+fn print_diff<AT: ReadTree, BT: ReadTree>(
+    a: &AT,
+    b: &BT,
+    subm: &ArgMatches,
+    report: &Report,
+) -> Result<()> {
     // TODO: Move this to a text-mode formatter library?
-    // TODO: Consider whether the actual files have changed.
     // TODO: Summarize diff.
-    // TODO: Optionally include unchanged files.
-    let st = stored_tree_from_options(subm, report)?;
-    let lt = live_tree_from_options(subm, report)?;
-    for e in conserve::iter_merged_entries(&st, &lt, &report)? {
+    let compare_content = subm.is_present("compare-content");
+    let include_unchanged = subm.is_present("include-unchanged");
+    let json = subm.is_present("json");
+    for e in conserve::iter_merged_entries(a, b, report)? {
         use MergedEntryKind::*;
         let ee = e?;
         let ks = match ee.kind {
             LeftOnly => "left",
             RightOnly => "right",
-            Both => "both",
+            Both => match ee.change(a, b, compare_content)? {
+                Some(Change::Unchanged) if !include_unchanged => continue,
+                Some(Change::Unchanged) => "same",
+                Some(Change::MetadataChanged) => "metadata",
+                Some(Change::ContentChanged) => "changed",
+                Some(Change::KindChanged) => "kind",
+                None => unreachable!(),
+            },
         };
-        report.print(&format!("{:<8} {}", ks, ee.apath));
+        if json {
+            report.print(&output::json_diff(&ee.apath, ks));
+        } else {
+            report.print(&format!("{:<8} {}", ks, ee.apath));
+        }
+    }
+    Ok(())
+}
+
+fn excludes_set(subm: &ArgMatches, report: &Report) -> Result<()> {
+    let archive = Archive::open(subm.value_of("archive").unwrap(), report)?;
+    let mut config = archive.config()?;
+    config.excludes = exclude_patterns_from_options(subm)?;
+    archive.write_config(&config)
+}
+
+fn excludes_show(subm: &ArgMatches, report: &Report) -> Result<()> {
+    let archive = Archive::open(subm.value_of("archive").unwrap(), report)?;
+    for pattern in archive.config()?.excludes {
+        report.print(&pattern);
     }
-    // report.print(&report.borrow_counts().summary_for_backup());
     Ok(())
 }
 
 fn validate(subm: &ArgMatches, report: &Report) -> Result<()> {
     let archive = Archive::open(subm.value_of("archive").unwrap(), &report)?;
-    archive.validate()?;
-    report.print(&report.borrow_counts().summary_for_validate());
+    let depth = if subm.is_present("shallow") {
+        ValidateDepth::Shallow
+    } else {
+        ValidateDepth::Deep
+    };
+    archive.validate(depth)?;
+    if !subm.is_present("json") {
+        report.print(&report.borrow_counts().summary_for_validate());
+    }
+    Ok(())
+}
+
+fn repair_archive(subm: &ArgMatches, report: &Report) -> Result<()> {
+    let archive = Archive::open(subm.value_of("archive").unwrap(), report)?;
+    let repairs = plan_repair(&archive)?;
+    if repairs.is_empty() {
+        report.print("Nothing to repair.");
+        return Ok(());
+    }
+    for r in &repairs {
+        report.print(&r.to_string());
+    }
+    if subm.is_present("apply") {
+        repair(&archive, &repairs)?;
+        report.print("Repair complete.");
+    } else {
+        report.print("Dry run: nothing changed. Use --apply to make these repairs.");
+    }
     Ok(())
 }
 
 fn versions(subm: &ArgMatches, report: &Report) -> Result<()> {
     use conserve::output::ShowArchive;
     let archive = Archive::open(subm.value_of("archive").unwrap(), &report)?;
-    if subm.is_present("short") {
+    if subm.is_present("json") {
+        output::JsonVersionList::default()
+            .show_sizes(subm.is_present("sizes"))
+            .show_archive(&archive)
+    } else if subm.is_present("short") {
         output::ShortVersionList::default().show_archive(&archive)
     } else {
         output::VerboseVersionList::default()
@@ -351,46 +1000,261 @@ fn versions(subm: &ArgMatches, report: &Report) -> Result<()> {
 }
 
 fn source_ls(subm: &ArgMatches, report: &Report) -> Result<()> {
-    let lt = live_tree_from_options(subm, report)?;
-    list_tree_contents(&lt, report)?;
+    let lt = live_tree_from_options(subm, None, report)?;
+    list_tree_contents(&lt, subm.is_present("json"), report)?;
     Ok(())
 }
 
 fn source_size(subm: &ArgMatches, report: &Report) -> Result<()> {
-    let source = live_tree_from_options(subm, report)?;
+    let source = live_tree_from_options(subm, None, report)?;
     report.set_phase("Measuring");
-    report.print(&format!("{}", source.size()?.file_bytes).separate_with_commas());
+    print_tree_size(source.size()?.file_bytes, subm, report);
+    Ok(())
+}
+
+fn copy(subm: &ArgMatches, report: &Report) -> Result<()> {
+    let source = Archive::open(subm.value_of("source").unwrap(), report)?;
+    let dest = Archive::open(subm.value_of("destination").unwrap(), report)?;
+    let naming = if subm.is_present("fresh-ids") {
+        BandNaming::FreshIds
+    } else {
+        BandNaming::PreserveIds
+    };
+    let band_ids = match subm.values_of("backup") {
+        Some(values) => values
+            .map(BandId::from_string)
+            .collect::<Result<Vec<BandId>>>()?,
+        None => {
+            let existing = dest.list_bands()?;
+            // Copies with fresh ids have the same start and end times as the original,
+            // so those identify bands that were already copied.
+            let mut existing_times = Vec::new();
+            if naming == BandNaming::FreshIds {
+                for band_id in &existing {
+                    if let Ok(info) = Band::open(&dest, band_id)?.get_info(report) {
+                        existing_times.push((info.start_time, info.end_time));
+                    }
+                }
+            }
+            let mut band_ids = Vec::new();
+            for band_id in source.list_bands()? {
+                let band = Band::open(&source, &band_id)?;
+                if !band.is_closed()? {
+                    continue;
+                }
+                // A band that's incomplete in the destination is left from an interrupted
+                // copy, and is copied again to finish it.
+                if naming == BandNaming::PreserveIds
+                    && existing.contains(&band_id)
+                    && Band::open(&dest, &band_id)
+                        .and_then(|b| b.is_closed())
+                        .unwrap_or(false)
+                {
+                    continue;
+                }
+                if naming == BandNaming::FreshIds {
+                    let info = band.get_info(report)?;
+                    let times = (info.start_time, info.end_time);
+                    if let Some(i) = existing_times.iter().position(|t| *t == times) {
+                        // Each copy accounts for only one source band.
+                        existing_times.remove(i);
+                        continue;
+                    }
+                }
+                band_ids.push(band_id);
+            }
+            band_ids
+        }
+    };
+    for band_id in band_ids {
+        let new_id = copy_band(&source, &band_id, &dest, naming)?;
+        report.print(&format!("Copied {} to {}", band_id, new_id));
+    }
+    let counts = report.borrow_counts();
+    report.print(&format!(
+        "{} blocks copied, {} already present.",
+        counts.get_count("block.copied").separate_with_commas(),
+        counts
+            .get_count("block.already_present")
+            .separate_with_commas()
+    ));
+    Ok(())
+}
+
+#[cfg(all(unix, feature = "fuse"))]
+fn mount(subm: &ArgMatches, report: &Report) -> Result<()> {
+    let archive = Archive::open(subm.value_of("archive").unwrap(), report)?;
+    conserve::mount(&archive, Path::new(subm.value_of("mountpoint").unwrap()))
+}
+
+fn export(subm: &ArgMatches, report: &Report) -> Result<()> {
+    let output = subm.value_of("output").filter(|o| *o != "-");
+    // Messages on stdout would be mixed into the export, so when writing there, collect
+    // problems quietly and show them on stderr.
+    let quiet_report = Report::with_ui(Box::new(ui::null::NullUI::new()));
+    let export_report = if output.is_some() {
+        report
+    } else {
+        &quiet_report
+    };
+    let st =
+        stored_tree_from_options(subm, export_report)?.with_zero_fill(subm.is_present("zero-fill"));
+    let result = match (subm.value_of("format").unwrap(), output) {
+        ("tar", Some(path)) => copy_tree(
+            &st,
+            &mut TarWriteTree::new(File::create(path)?, export_report),
+        ),
+        ("tar", None) => {
+            let stdout = std::io::stdout();
+            copy_tree(&st, &mut TarWriteTree::new(stdout.lock(), export_report))
+        }
+        ("zip", Some(path)) => copy_tree(
+            &st,
+            &mut ZipWriteTree::new(File::create(path)?, export_report),
+        ),
+        ("zip", None) => {
+            // Zip files are written by seeking back, so build it in a temporary file.
+            let mut temp = tempfile::tempfile()?;
+            let result = copy_tree(&st, &mut ZipWriteTree::new(&mut temp, export_report));
+            result.and_then(|()| {
+                temp.seek(SeekFrom::Start(0))?;
+                let stdout = std::io::stdout();
+                std::io::copy(&mut temp, &mut stdout.lock())?;
+                Ok(())
+            })
+        }
+        (other, _) => unreachable!("unexpected --format value {:?}", other),
+    };
+    if output.is_none() {
+        for problem in quiet_report.borrow_counts().problems() {
+            eprintln!("{}", problem);
+        }
+        report.merge_from(&quiet_report);
+    } else if result.is_ok() {
+        report.print("Export complete.");
+    }
+    result
+}
+
+fn cat(subm: &ArgMatches, report: &Report) -> Result<()> {
+    let apath = apath_from_arg(subm.value_of("apath").unwrap())?;
+    let st = stored_tree_from_options(subm, report)?;
+    let mut content = st.open_file(&apath)?;
+    let stdout = std::io::stdout();
+    std::io::copy(&mut content, &mut stdout.lock())?;
     Ok(())
 }
 
 fn ls(subm: &ArgMatches, report: &Report) -> Result<()> {
     let st = stored_tree_from_options(subm, report)?;
-    list_tree_contents(&st, report)?;
+    list_tree_contents(&st, subm.is_present("json"), report)?;
     Ok(())
 }
 
-fn list_tree_contents<T: ReadTree>(tree: &T, report: &Report) -> Result<()> {
+fn list_tree_contents<T: ReadTree>(tree: &T, json: bool, report: &Report) -> Result<()> {
     // TODO: Maybe should be a specific concept in the UI.
     for entry in tree.iter_entries(report)? {
-        report.print(&entry?.apath());
+        let entry = entry?;
+        if json {
+            report.print(&output::json_entry(&entry));
+        } else {
+            report.print(&entry.apath());
+        }
     }
     Ok(())
 }
 
 fn restore(subm: &ArgMatches, report: &Report) -> Result<()> {
     let dest = Path::new(subm.value_of("destination").unwrap());
-    let st = stored_tree_from_options(subm, report)?;
-    let mut rt = if subm.is_present("force-overwrite") {
-        RestoreTree::create_overwrite(dest, report)
-    } else {
-        RestoreTree::create(dest, report)
-    }?;
+    let subtrees = subm
+        .values_of("apath")
+        .into_iter()
+        .flatten()
+        .map(apath_from_arg)
+        .collect::<Result<Vec<Apath>>>()?;
+    let includes = Includes::from_strings(subm.values_of("only").into_iter().flatten())?
+        .with_subtrees(subtrees);
+    let st = stored_tree_from_options(subm, report)?
+        .with_includes(includes)
+        .with_zero_fill(subm.is_present("zero-fill"));
+    let overwrite = match subm.value_of("overwrite") {
+        Some("never") => Some(Overwrite::Never),
+        Some("changed") => Some(Overwrite::IfChanged),
+        Some("different") => Some(Overwrite::IfContentDiffers),
+        Some("always") => Some(Overwrite::Always),
+        Some(other) => unreachable!("unexpected --overwrite value {:?}", other),
+        None if subm.is_present("force-overwrite") => Some(Overwrite::Always),
+        None => None,
+    };
+    let dry_run = subm.is_present("dry-run");
+    let mut rt = match overwrite {
+        Some(overwrite) => RestoreTree::create_overwrite(dest, report)?.with_overwrite(overwrite),
+        None => RestoreTree::create(dest, report)?,
+    }
+    .with_dry_run(dry_run);
     copy_tree(&st, &mut rt)?;
-    report.print("Restore complete.");
-    report.print(&report.borrow_counts().summary_for_restore());
+    if subm.is_present("delete") {
+        rt.delete_extra(&st)?;
+    }
+    if dry_run {
+        report.print("Dry run: nothing changed.");
+    } else {
+        report.print("Restore complete.");
+        report.print(&report.borrow_counts().summary_for_restore());
+    }
+    if subm.is_present("salvage") {
+        print_salvage_summary(report);
+    }
     Ok(())
 }
 
+/// List the files that a salvaging restore couldn't restore, or restored with holes.
+fn print_salvage_summary(report: &Report) {
+    let problem_paths = |kind: ProblemKind| {
+        report
+            .borrow_counts()
+            .problems()
+            .iter()
+            .filter(|p| p.kind == kind)
+            .map(|p| p.path.clone())
+            .collect::<BTreeSet<String>>()
+    };
+    let lost = problem_paths(ProblemKind::CopyFailed);
+    let damaged = problem_paths(ProblemKind::DamagedFile);
+    if lost.is_empty() && damaged.is_empty() {
+        report.print("No files were lost.");
+    }
+    if !lost.is_empty() {
+        report.print("Lost files, which were not restored:");
+        for apath in lost {
+            report.print(&format!("  {}", apath));
+        }
+    }
+    if !damaged.is_empty() {
+        report.print("Damaged files, restored with zeros in place of unreadable data:");
+        for apath in damaged {
+            report.print(&format!("  {}", apath));
+        }
+    }
+    let index_damaged = [
+        ProblemKind::SourceRead,
+        ProblemKind::MissingFile,
+        ProblemKind::BadIndex,
+    ]
+    .iter()
+    .any(|&kind| report.borrow_counts().count_problems_of_kind(kind) > 0);
+    if index_damaged {
+        report.print("Some of the index couldn't be read, so other files may have been lost.");
+    }
+    let some_not_kept = {
+        let counts = report.borrow_counts();
+        counts.count_problems() > counts.problems().len() as u64
+    };
+    if some_not_kept {
+        report.print("There were too many problems to list them all.");
+    }
+}
+
 fn debug_block_list(subm: &ArgMatches, report: &Report) -> Result<()> {
     let archive = Archive::open(subm.value_of("archive").unwrap(), &report)?;
     for b in archive.block_dir().block_names(report)? {
@@ -410,13 +1274,28 @@ fn debug_block_referenced(subm: &ArgMatches, report: &Report) -> Result<()> {
 fn tree_size(subm: &ArgMatches, report: &Report) -> Result<()> {
     let st = stored_tree_from_options(subm, report)?;
     report.set_phase("Measuring");
-    report.print(&format!("{}", st.size()?.file_bytes).separate_with_commas());
+    print_tree_size(st.size()?.file_bytes, subm, report);
     Ok(())
 }
 
+fn print_tree_size(file_bytes: u64, subm: &ArgMatches, report: &Report) {
+    if subm.is_present("json") {
+        report.print(&output::json_tree_size(file_bytes));
+    } else {
+        report.print(&file_bytes.separate_with_commas());
+    }
+}
+
 fn stored_tree_from_options(subm: &ArgMatches, report: &Report) -> Result<StoredTree> {
     let archive = Archive::open(subm.value_of("archive").unwrap(), &report)?;
     let st = match band_id_from_option(subm)? {
+        None if subm.is_present("at") => {
+            let band = archive.last_complete_band_before(
+                parse_date(subm.value_of("at").unwrap())?,
+                subm.is_present("incomplete"),
+            )?;
+            StoredTree::open_version(&archive, &band.id())
+        }
         None => StoredTree::open_last(&archive),
         Some(ref b) => {
             if subm.is_present("incomplete") {
@@ -426,12 +1305,93 @@ fn stored_tree_from_options(subm: &ArgMatches, report: &Report) -> Result<Stored
             }
         }
     }?;
-    Ok(st.with_excludes(excludes_from_option(subm)?))
+    let excludes = excludes_from_options(subm, archive_excludes_from_option(subm, &archive))?;
+    Ok(st.with_excludes(excludes))
+}
+
+/// Make MetadataExcludes from the `--exclude-larger-than` etc options.
+fn metadata_excludes_from_options(subm: &ArgMatches) -> Result<excludes::MetadataExcludes> {
+    Ok(excludes::MetadataExcludes {
+        larger_than: subm
+            .value_of("exclude-larger-than")
+            .map(parse_size)
+            .transpose()?,
+        older_than: subm
+            .value_of("exclude-older-than")
+            .map(parse_date)
+            .transpose()?,
+        newer_than: subm
+            .value_of("exclude-newer-than")
+            .map(parse_date)
+            .transpose()?,
+        kinds: subm
+            .values_of("exclude-kind")
+            .into_iter()
+            .flatten()
+            .map(excludes::parse_kind)
+            .collect::<Result<_>>()?,
+    })
+}
+
+/// Make a LiveTree from the `source` arguments and inclusion and exclusion options.
+///
+/// If `archive` is given, its stored default excludes are also applied.
+fn live_tree_from_options(
+    subm: &ArgMatches,
+    archive: Option<&Archive>,
+    report: &Report,
+) -> Result<LiveTree> {
+    let sources: Vec<&str> = subm.values_of("source").unwrap().collect();
+    Ok(LiveTree::open_sources(&sources, report)?
+        .with_includes(includes_from_options(subm)?)
+        .with_one_file_system(subm.is_present("one-file-system"))
+        .with_metadata_excludes(metadata_excludes_from_options(subm)?)
+        .with_excludes(excludes_from_options(subm, archive)?))
+}
+
+fn tar_tree_from_options(
+    subm: &ArgMatches,
+    tar_path: &str,
+    archive: Option<&Archive>,
+    report: &Report,
+) -> Result<TarTree> {
+    let tt = if tar_path == "-" {
+        TarTree::read(std::io::stdin().lock(), report)?
+    } else {
+        TarTree::read(File::open(tar_path)?, report)?
+    };
+    Ok(tt
+        .with_metadata_excludes(metadata_excludes_from_options(subm)?)
+        .with_excludes(excludes_from_options(subm, archive)?))
 }
 
-fn live_tree_from_options(subm: &ArgMatches, report: &Report) -> Result<LiveTree> {
-    Ok(LiveTree::open(&subm.value_of("source").unwrap(), &report)?
-        .with_excludes(excludes_from_option(subm)?))
+/// Make Includes from the `--include` options.
+fn includes_from_options(subm: &ArgMatches) -> Result<Includes> {
+    match subm.values_of("include") {
+        Some(includes) => Includes::from_strings(includes),
+        None => Ok(Includes::everything()),
+    }
+}
+
+/// Return the archive if its default excludes were requested by `--archive-excludes`.
+fn archive_excludes_from_option<'a>(
+    subm: &ArgMatches,
+    archive: &'a Archive,
+) -> Option<&'a Archive> {
+    if subm.is_present("archive-excludes") {
+        Some(archive)
+    } else {
+        None
+    }
+}
+
+/// Check and convert an apath given on the command line.
+fn apath_from_arg(a: &str) -> Result<Apath> {
+    if Apath::is_valid(a) {
+        Ok(Apath::from(a))
+    } else {
+        Err(Error::InvalidApath(a.to_owned()))
+    }
 }
 
 fn band_id_from_option(subm: &ArgMatches) -> Result<Option<BandId>> {
@@ -441,10 +1401,26 @@ fn band_id_from_option(subm: &ArgMatches) -> Result<Option<BandId>> {
     }
 }
 
-/// Make an exclusion globset from the `--exclude` option.
-fn excludes_from_option(subm: &ArgMatches) -> Result<globset::GlobSet> {
-    match subm.values_of("exclude") {
-        Some(excludes) => excludes::from_strings(excludes),
-        None => Ok(excludes::excludes_nothing()),
+/// Collect exclusion patterns from the `--exclude` and `--exclude-from` options.
+fn exclude_patterns_from_options(subm: &ArgMatches) -> Result<Vec<String>> {
+    let mut patterns = Vec::<String>::new();
+    if let Some(excludes) = subm.values_of("exclude") {
+        patterns.extend(excludes.map(String::from));
+    }
+    if let Some(files) = subm.values_of("exclude-from") {
+        for f in files {
+            patterns.extend(excludes::read_patterns_file(Path::new(f))?);
+        }
+    }
+    Ok(patterns)
+}
+
+/// Make an exclusion globset from the exclusion options, plus the default excludes stored
+/// in `archive` if one is given.
+fn excludes_from_options(subm: &ArgMatches, archive: Option<&Archive>) -> Result<globset::GlobSet> {
+    let mut patterns = exclude_patterns_from_options(subm)?;
+    if let Some(archive) = archive {
+        patterns.extend(archive.config()?.excludes);
     }
+    excludes::from_strings(patterns)
 }
@@ -11,6 +11,7 @@
 //!
 //! The structure is: archive > blockdir > subdir > file.
 
+use std::collections::HashMap;
 use std::fs;
 use std::fs::File;
 use std::io;
@@ -54,6 +55,16 @@ pub struct Address {
     pub len: u64,
 }
 
+impl Address {
+    /// True if the addressed range lies within a block of this uncompressed length.
+    pub(crate) fn fits_in(&self, block_len: u64) -> bool {
+        match self.start.checked_add(self.len) {
+            Some(end) => end <= block_len,
+            None => false,
+        }
+    }
+}
+
 /// A readable, writable directory within a band holding data blocks.
 #[derive(Clone, Debug)]
 pub struct BlockDir {
@@ -84,7 +95,7 @@ impl BlockDir {
     }
 
     /// Return the full path for a file called `hex_hash`.
-    fn path_for_file(&self, hash_hex: &str) -> PathBuf {
+    pub(crate) fn path_for_file(&self, hash_hex: &str) -> PathBuf {
         self.subdir_for(hash_hex).join(hash_hex)
     }
 
@@ -156,9 +167,10 @@ impl BlockDir {
         if let Err(e) = tempf.persist(&self.path_for_file(&hex_hash)) {
             if e.error.kind() == io::ErrorKind::AlreadyExists {
                 // Suprising we saw this rather than detecting it above.
-                report.problem(&format!(
-                    "Unexpected late detection of existing block {:?}",
-                    hex_hash
+                report.problem(Problem::new(
+                    ProblemKind::UnexpectedFile,
+                    hex_hash,
+                    "Unexpected late detection of existing block",
                 ));
                 report.increment("block.already_present", 1);
             } else {
@@ -177,6 +189,27 @@ impl BlockDir {
         }
     }
 
+    /// Copy a block from another blockdir, after checking its content matches its hash.
+    ///
+    /// Returns the compressed size of the block.
+    pub fn copy_from(&self, source: &BlockDir, hash: &str, report: &Report) -> Result<u64> {
+        source.get_block(hash).validate(report)?;
+        let content = fs::read(source.path_for_file(hash))?;
+        ensure_dir_exists(&self.subdir_for(hash))?;
+        let mut f = AtomicFile::new(&self.path_for_file(hash))?;
+        f.write_all(&content)?;
+        f.close(report)?;
+        Ok(content.len() as u64)
+    }
+
+    /// Move a block out of this directory into `quarantine_dir`, so that it's no longer
+    /// read, but is kept for inspection.
+    pub fn quarantine(&self, hash: &str, quarantine_dir: &Path) -> Result<()> {
+        ensure_dir_exists(quarantine_dir)?;
+        fs::rename(self.path_for_file(hash), quarantine_dir.join(hash))?;
+        Ok(())
+    }
+
     /// Get an object accessing a whole block.
     /// The contents are not yet narrowed down to only the addressed region.
     pub fn get_block(&self, hash: &str) -> Block {
@@ -190,28 +223,40 @@ impl BlockDir {
     ///
     /// To read a whole file, use StoredFile instead.
     pub fn get(&self, addr: &Address, report: &Report) -> Result<Vec<u8>> {
-        if addr.start != 0 {
-            unimplemented!();
-        }
         let b = self.get_block(&addr.hash);
-        let decompressed = b.get_all(report)?;
-        // TODO: Accept addresses referring to only part of a block.
-        if decompressed.len() != addr.len as usize {
-            unimplemented!();
+        let mut decompressed = b.get_verified(report)?;
+        let block_len = decompressed.len() as u64;
+        if !addr.fits_in(block_len) {
+            Err(Error::AddressOutOfRange {
+                address: addr.clone(),
+                block_len,
+            })
+        } else if addr.start == 0 && addr.len == block_len {
+            Ok(decompressed)
+        } else {
+            decompressed.truncate((addr.start + addr.len) as usize);
+            Ok(decompressed.split_off(addr.start as usize))
         }
-        Ok(decompressed)
     }
 
     /// Return a sorted vec of prefix subdirectories.
     fn subdirs(&self, report: &Report) -> Result<Vec<String>> {
         // This doesn't check every invariant that should be true; that's the job of the validation
         // code.
-        let (_fs, mut ds) = list_dir(&self.path)?;
+        let (fs, mut ds) = list_dir(&self.path)?;
+        for f in fs {
+            report.problem(Problem::for_path(
+                ProblemKind::UnexpectedFile,
+                self.path.join(f),
+                "Unexpected file in blockdir",
+            ));
+        }
         ds.retain(|dd| {
             if dd.len() != SUBDIR_NAME_CHARS {
-                report.problem(&format!(
-                    "unexpected subdirectory in blockdir {:?}: {:?}",
-                    self, dd
+                report.problem(Problem::for_path(
+                    ProblemKind::UnexpectedFile,
+                    self.path.join(dd),
+                    "Unexpected subdirectory in blockdir",
                 ));
                 false
             } else {
@@ -225,24 +270,28 @@ impl BlockDir {
     pub fn block_names(&self, report: &Report) -> Result<Vec<String>> {
         // The vecs from `subdirs` and `list_dir` are already sorted, so
         // we don't need to sort here.
-        Ok(self
-            .subdirs(report)?
-            .iter()
-            .flat_map(|s| {
-                let (mut fs, _ds) = list_dir(&self.path.join(s)).unwrap();
-                fs.retain(|ff| {
-                    if ff.starts_with(TMP_PREFIX) {
-                        false
-                    } else if ff.len() != BLOCKDIR_FILE_NAME {
-                        report.problem(&format!("unlikely file name in {:?}: {:?}", self, ff));
-                        false
-                    } else {
-                        true
-                    }
-                });
-                fs.into_iter()
-            })
-            .collect())
+        let mut names = Vec::new();
+        for s in self.subdirs(report)? {
+            let (fs, _ds) = list_dir(&self.path.join(&s))?;
+            for ff in fs {
+                if ff.starts_with(TMP_PREFIX) {
+                    report.problem(Problem::for_path(
+                        ProblemKind::UnexpectedFile,
+                        self.path.join(&s).join(ff),
+                        "Leftover temporary file in blockdir",
+                    ));
+                } else if ff.len() != BLOCKDIR_FILE_NAME {
+                    report.problem(Problem::for_path(
+                        ProblemKind::UnexpectedFile,
+                        self.path.join(&s).join(ff),
+                        "Unlikely file name in blockdir",
+                    ));
+                } else {
+                    names.push(ff);
+                }
+            }
+        }
+        Ok(names)
     }
 
     pub fn blocks(&self, report: &Report) -> Result<Vec<Block>> {
@@ -253,10 +302,12 @@ impl BlockDir {
             .collect::<Vec<Block>>())
     }
 
-    /// Check format invariants of the BlockDir; report any problems to the Report.
-    pub fn validate(&self, report: &Report) -> Result<()> {
-        // TODO: In the top-level directory, no files or directories other than prefix
-        // directories of the right length.
+    /// Check format invariants of the BlockDir, and the content of every block; report any
+    /// problems to the Report.
+    ///
+    /// Returns the uncompressed length of every block that was read and found to have the
+    /// right hash, indexed by the hash.
+    pub fn validate(&self, report: &Report) -> Result<HashMap<String, u64>> {
         report.set_phase("Count blocks");
         let bs = self.blocks(report)?;
         let tot = bs
@@ -264,18 +315,29 @@ impl BlockDir {
             .try_fold(0u64, |t, b| Ok(t + b.compressed_size()?) as Result<u64>)?;
         report.set_total_work(tot);
 
-        report.print(&format!(
+        report.note(&format!(
             "Check {} MB in blocks...",
             (tot / 1_000_000).separate_with_commas()
         ));
         report.set_phase("Check block hashes");
-        bs.par_iter()
-            .map(|b| {
-                report.increment_work(b.compressed_size()?);
-                b.validate(report)
+        Ok(bs
+            .par_iter()
+            .filter_map(|b| {
+                report.increment_work(b.compressed_size().unwrap_or_default());
+                match b.validate(report) {
+                    Ok(len) => Some((b.hash.clone(), len)),
+                    // Already reported as a problem.
+                    Err(Error::BlockCorrupt(_)) => None,
+                    Err(e) => {
+                        report.problem(
+                            Problem::for_path(ProblemKind::BadBlock, &b.path, "Can't read block")
+                                .with_error(e),
+                        );
+                        None
+                    }
+                }
             })
-            .try_for_each(|i| i)?;
-        Ok(())
+            .collect())
     }
 }
 
@@ -296,7 +358,10 @@ impl Block {
             Ok(d) => d,
             Err(e) => {
                 report.increment("block.corrupt", 1);
-                report.problem(&format!("Block file {:?} read error {:?}", self.path, e));
+                report.problem(
+                    Problem::for_path(ProblemKind::BadBlock, &self.path, "Block file read error")
+                        .with_error(e),
+                );
                 return Err(Error::BlockCorrupt(self.path.clone()));
             }
         };
@@ -311,18 +376,25 @@ impl Block {
         Ok(de)
     }
 
-    pub fn validate(&self, report: &Report) -> Result<()> {
+    /// Check the block's content matches its hash, and return its uncompressed length.
+    pub fn validate(&self, report: &Report) -> Result<u64> {
+        Ok(self.get_verified(report)?.len() as u64)
+    }
+
+    /// Return the entire contents of the block, after checking they match its hash.
+    fn get_verified(&self, report: &Report) -> Result<Vec<u8>> {
         let de = self.get_all(report)?;
         let actual_hash = hex::encode(blake2b::blake2b(BLAKE_HASH_SIZE_BYTES, &[], &de).as_bytes());
         if actual_hash != *self.hash {
             report.increment("block.misplaced", 1);
-            report.problem(&format!(
-                "Block file {:?} has actual decompressed hash {:?}",
-                self.path, actual_hash
+            report.problem(Problem::for_path(
+                ProblemKind::BadBlock,
+                &self.path,
+                &format!("Block file has actual decompressed hash {:?}", actual_hash),
             ));
             return Err(Error::BlockCorrupt(self.path.clone()));
         }
-        Ok(())
+        Ok(de)
     }
 
     pub fn compressed_size(&self) -> Result<u64> {
@@ -343,6 +415,7 @@ mod tests {
     use std::io::SeekFrom;
     use tempfile::{NamedTempFile, TempDir};
 
+    use super::Address;
     use crate::*;
 
     const EXAMPLE_TEXT: &'static [u8] = b"hello!";
@@ -417,7 +490,10 @@ mod tests {
 
         // Validate
         let validate_r = Report::new();
-        block_dir.validate(&validate_r).unwrap();
+        let good_blocks = block_dir.validate(&validate_r).unwrap();
+        assert_eq!(good_blocks.len(), 1);
+        assert_eq!(good_blocks[&expected_hash], 6);
+        assert_eq!(validate_r.borrow_counts().count_problems(), 0);
     }
 
     #[test]
@@ -480,4 +556,29 @@ mod tests {
             assert!(retr.iter().all(|b| *b == 64u8));
         }
     }
+
+    #[test]
+    pub fn get_part_of_block() {
+        let (_testdir, mut block_dir) = setup();
+        let report = Report::new();
+        let addrs = block_dir.store(&mut make_example_file(), &report).unwrap();
+        let addr = Address {
+            start: 1,
+            len: 3,
+            ..addrs[0].clone()
+        };
+        assert_eq!(block_dir.get(&addr, &report).unwrap(), b"ell");
+
+        for &(start, len) in &[(0, 7), (6, 1), (u64::MAX, 2)] {
+            let addr = Address {
+                start,
+                len,
+                ..addrs[0].clone()
+            };
+            match block_dir.get(&addr, &report) {
+                Err(Error::AddressOutOfRange { block_len: 6, .. }) => (),
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+    }
 }
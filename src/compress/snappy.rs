@@ -10,7 +10,7 @@ pub struct Snappy {}
 impl super::Compression for Snappy {
     fn compress_and_write(in_buf: &[u8], w: &mut dyn io::Write) -> io::Result<usize> {
         let mut encoder = snap::Encoder::new();
-        let r = encoder.compress_vec(in_buf).unwrap();
+        let r = encoder.compress_vec(in_buf)?;
         w.write_all(&r)?;
         Ok(r.len())
     }
@@ -21,8 +21,7 @@ impl super::Compression for Snappy {
         let compressed_len = r.read_to_end(&mut compressed_buf)?;
 
         let mut decoder = snap::Decoder::new();
-        // TODO: Clean errors.
-        let decompressed = decoder.decompress_vec(&compressed_buf).unwrap();
+        let decompressed = decoder.decompress_vec(&compressed_buf)?;
 
         Ok((compressed_len, decompressed))
     }
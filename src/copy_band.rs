@@ -0,0 +1,179 @@
+// Conserve backup system.
+// Copyright 2019 Martin Pool.
+
+//! Copy bands from one archive to another, such as to keep an offsite replica in sync.
+//!
+//! Only the blocks that the destination lacks are copied. The band head, index and tail are
+//! copied unchanged, so the copy has the same contents and times as the original.
+
+use std::collections::BTreeSet;
+
+use crate::*;
+
+/// How to choose the ids of bands copied into another archive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BandNaming {
+    /// Keep the same id as in the source archive. It's an error if the destination already
+    /// has a band with that id, unless it's an incomplete copy of the same band.
+    PreserveIds,
+    /// Give each copy the next id after the bands already in the destination.
+    FreshIds,
+}
+
+/// Copy one band from `source` into `dest`, and return the id of the copy.
+///
+/// The blocks and index are copied before the tail, so an interrupted copy leaves an
+/// incomplete band, which is finished by copying the band again with `PreserveIds`.
+/// Copying an incomplete band gives an incomplete copy.
+///
+/// Progress, and the number of blocks copied, are reported to the destination's report.
+pub fn copy_band(
+    source: &Archive,
+    band_id: &BandId,
+    dest: &Archive,
+    naming: BandNaming,
+) -> Result<BandId> {
+    let report = dest.report();
+    let source_band = Band::open(source, band_id)?;
+    let dest_band = match naming {
+        BandNaming::PreserveIds if dest.list_bands()?.contains(band_id) => {
+            Band::reopen_copy(dest, band_id.clone(), &source_band)?
+        }
+        BandNaming::PreserveIds => Band::create_copy(dest, band_id.clone(), &source_band)?,
+        BandNaming::FreshIds => Band::create_copy(dest, Band::next_id(dest)?, &source_band)?,
+    };
+    let dest_id = dest_band.id();
+
+    report.set_phase("Read index");
+    let mut hashes = BTreeSet::new();
+    for entry in source_band
+        .index()
+        .iter(&excludes::excludes_nothing(), report)?
+    {
+        hashes.extend(entry?.addrs.into_iter().map(|addr| addr.hash));
+    }
+    report.set_phase("Copy blocks");
+    report.set_total_work(hashes.len() as u64);
+    for hash in hashes {
+        if dest.block_dir().contains(&hash)? {
+            report.increment("block.already_present", 1);
+        } else {
+            dest.block_dir()
+                .copy_from(source.block_dir(), &hash, report)?;
+            report.increment("block.copied", 1);
+        }
+        report.increment_work(1);
+    }
+    report.clear_phase();
+
+    source_band
+        .index()
+        .copy_hunks_to(&dest_band.index_dir_path, report)?;
+    if source_band.is_closed()? {
+        dest_band.copy_tail_from(&source_band, report)?;
+    }
+    Ok(dest_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::*;
+
+    fn apaths(archive: &Archive, band_id: &BandId) -> Vec<String> {
+        StoredTree::open_version(archive, band_id)
+            .unwrap()
+            .iter_entries(archive.report())
+            .unwrap()
+            .map(|e| e.unwrap().apath.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn copy_bands_preserving_ids() {
+        let source = ScratchArchive::new();
+        source.store_two_versions();
+        let dest = ScratchArchive::new();
+        let b0 = BandId::new(&[0]);
+        let b1 = BandId::new(&[1]);
+
+        assert_eq!(
+            copy_band(&source, &b1, &dest, BandNaming::PreserveIds).unwrap(),
+            b1
+        );
+        assert_eq!(dest.list_bands().unwrap(), vec![b1.clone()]);
+        assert_eq!(apaths(&dest, &b1), apaths(&source, &b1));
+        let copied = dest.report().borrow_counts().get_count("block.copied");
+        assert!(copied > 0);
+
+        // The first version's blocks are all in the second, so none need to be copied.
+        copy_band(&source, &b0, &dest, BandNaming::PreserveIds).unwrap();
+        let counts = dest.report().borrow_counts();
+        assert_eq!(counts.get_count("block.copied"), copied);
+        assert!(counts.get_count("block.already_present") > 0);
+        drop(counts);
+
+        let source_info = Band::open(&source, &b0)
+            .unwrap()
+            .get_info(source.report())
+            .unwrap();
+        let dest_info = Band::open(&dest, &b0)
+            .unwrap()
+            .get_info(dest.report())
+            .unwrap();
+        assert_eq!(dest_info.start_time, source_info.start_time);
+        assert_eq!(dest_info.end_time, source_info.end_time);
+
+        dest.validate(ValidateDepth::Deep).unwrap();
+        assert_eq!(dest.report().borrow_counts().count_problems(), 0);
+
+        match copy_band(&source, &b0, &dest, BandNaming::PreserveIds) {
+            Err(Error::IoError(ref e)) if e.kind() == std::io::ErrorKind::AlreadyExists => (),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn copy_band_with_fresh_id() {
+        let source = ScratchArchive::new();
+        source.store_two_versions();
+        let dest = ScratchArchive::new();
+        dest.store_two_versions();
+
+        let b0 = BandId::new(&[0]);
+        let new_id = copy_band(&source, &b0, &dest, BandNaming::FreshIds).unwrap();
+        assert_eq!(new_id, BandId::new(&[2]));
+        assert_eq!(apaths(&dest, &new_id), apaths(&source, &b0));
+    }
+
+    #[test]
+    fn finish_interrupted_copy() {
+        let source = ScratchArchive::new();
+        source.store_two_versions();
+        let dest = ScratchArchive::new();
+        let b1 = BandId::new(&[1]);
+        copy_band(&source, &b1, &dest, BandNaming::PreserveIds).unwrap();
+        // Interrupt it before the tail and the last hunk were written.
+        let dest_band = Band::open(&dest, &b1).unwrap();
+        let hunks = dest_band.index().hunk_paths().unwrap();
+        std::fs::remove_file(hunks.last().unwrap()).unwrap();
+        std::fs::remove_file(dest_band.path().join("BANDTAIL")).unwrap();
+        assert!(!dest_band.is_closed().unwrap());
+
+        copy_band(&source, &b1, &dest, BandNaming::PreserveIds).unwrap();
+        assert!(dest_band.is_closed().unwrap());
+        assert_eq!(apaths(&dest, &b1), apaths(&source, &b1));
+        dest.validate(ValidateDepth::Deep).unwrap();
+        assert_eq!(dest.report().borrow_counts().count_problems(), 0);
+    }
+
+    #[test]
+    fn copy_incomplete_band() {
+        let source = ScratchArchive::new();
+        source.setup_incomplete_empty_band();
+        let dest = ScratchArchive::new();
+        let b0 = BandId::new(&[0]);
+        copy_band(&source, &b0, &dest, BandNaming::PreserveIds).unwrap();
+        assert!(!Band::open(&dest, &b0).unwrap().is_closed().unwrap());
+    }
+}
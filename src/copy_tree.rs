@@ -18,30 +18,56 @@ pub fn copy_tree<ST: ReadTree, DT: WriteTree>(source: &ST, dest: &mut DT) -> Res
     // again a second time? But, that'll potentially use memory proportional to tree size, which
     // I'd like to avoid, and also perhaps make it more likely we grumble about files that were
     // deleted or changed while this is running.
-    report.set_total_work(source.size()?.file_bytes);
+    //
+    // Entries and problems seen while measuring are counted separately, so that they're only
+    // reported once, while copying.
+    //
+    // If the source can't be measured, carry on without a total, so that as much as possible
+    // is copied: errors that recur while copying are reported as problems.
+    let measure_report = Report::with_ui(Box::new(ui::null::NullUI::new()));
+    report.set_total_work(
+        source
+            .measure(&measure_report)
+            .map(|size| size.file_bytes)
+            .unwrap_or(0),
+    );
     report.set_phase("Copying");
+    // Errors reading a damaged index hunk say which hunk it was; otherwise they're
+    // reported after the last entry that was read.
+    let mut last_apath = Apath::from("/");
     for entry in source.iter_entries(&report)? {
         let entry = match entry {
             Ok(entry) => entry,
             Err(e) => {
-                report.problem(&format!("Error iterating source, continuing: {}", e));
+                let message = "Error iterating source, continuing";
+                let problem = match &e {
+                    Error::IndexCorrupt(hunk_path) => {
+                        Problem::for_path(ProblemKind::SourceRead, hunk_path, message)
+                    }
+                    _ => Problem::new(ProblemKind::SourceRead, &last_apath, message),
+                };
+                report.problem(problem.with_error(e));
                 continue;
             }
         };
+        last_apath = entry.apath();
         report.start_entry(&entry);
         if let Err(e) = match entry.kind() {
             Kind::Dir => dest.write_dir(&entry),
             Kind::File => dest.copy_file(&entry, source),
             Kind::Symlink => dest.write_symlink(&entry),
             Kind::Unknown => {
-                report.problem(&format!(
-                    "Skipping unsupported file kind of {}",
-                    &entry.apath()
+                report.problem(Problem::new(
+                    ProblemKind::UnsupportedKind,
+                    entry.apath(),
+                    "Skipping unsupported file kind",
                 ));
                 continue;
             }
         } {
-            report.problem(&format!("Error copying {}: {}", &entry.apath(), e));
+            report.problem(
+                Problem::new(ProblemKind::CopyFailed, entry.apath(), "Error copying").with_error(e),
+            );
             continue;
         }
         report.increment_work(entry.size().unwrap_or(0));
@@ -8,6 +8,8 @@ use std::fmt;
 use std::io;
 use std::path::PathBuf;
 
+use chrono::{DateTime, Local, UTC};
+
 use crate::*;
 
 /// Conserve specific error.
@@ -21,13 +23,35 @@ pub enum Error {
     DestinationNotEmpty(PathBuf),
     ArchiveEmpty,
     NoCompleteBands,
+    NoCompleteBandsBefore(DateTime<UTC>),
     InvalidVersion,
     BandIncomplete(BandId),
+    BandCorrupt(PathBuf),
     IoError(io::Error),
     // TODO: Include the path in the json error.
     JsonDeserialize(serde_json::Error),
     BadGlob(globset::Error),
     IndexCorrupt(PathBuf),
+    NotProtectedByParity(PathBuf),
+    ParityUnrecoverable(PathBuf),
+    NoSources,
+    InvalidSourcePath(PathBuf),
+    InvalidSize(String),
+    InvalidDate(String),
+    InvalidKind(String),
+    InvalidApath(String),
+    ApathNotFound(Apath),
+    NotAStoredFile(Apath),
+    MountFailed {
+        mountpoint: PathBuf,
+        error: io::Error,
+    },
+    ExportIncomplete(Apath),
+    ZipError(zip::result::ZipError),
+    AddressOutOfRange {
+        address: blockdir::Address,
+        block_len: u64,
+    },
     FileCorrupt {
         // band_id: BandId,
         apath: Apath,
@@ -44,9 +68,41 @@ impl fmt::Display for Error {
             Error::DestinationNotEmpty(d) => write!(f, "Destination directory not empty: {:?}", d),
             Error::ArchiveEmpty => write!(f, "Archive is empty"),
             Error::NoCompleteBands => write!(f, "Archive has no complete bands"),
+            Error::NoCompleteBandsBefore(t) => write!(
+                f,
+                "Archive has no bands completed before {}",
+                t.with_timezone(&Local).to_rfc3339()
+            ),
+            Error::BlockCorrupt(p) => write!(f, "Block file is corrupt: {:?}", p),
+            Error::IndexCorrupt(p) => write!(f, "Index hunk is corrupt: {:?}", p),
+            Error::NotProtectedByParity(p) => write!(f, "Not protected by parity: {:?}", p),
+            Error::ParityUnrecoverable(p) => {
+                write!(f, "Too much damage to reconstruct from parity: {:?}", p)
+            }
+            Error::AddressOutOfRange { address, block_len } => write!(
+                f,
+                "Address {}+{} is beyond the end of block {} of length {}",
+                address.start, address.len, address.hash, block_len
+            ),
             Error::InvalidVersion => write!(f, "Invalid version number"),
             Error::NotAnArchive(p) => write!(f, "Not a Conserve archive: {:?}", p),
             Error::BandIncomplete(b) => write!(f, "Band {} is incomplete", b),
+            Error::BandCorrupt(p) => write!(f, "Band metadata is corrupt: {:?}", p),
+            Error::NoSources => write!(f, "No source directories given"),
+            Error::InvalidSourcePath(p) => write!(f, "Unsupported source path: {:?}", p),
+            Error::InvalidSize(s) => write!(f, "Invalid size: {:?}", s),
+            Error::InvalidDate(s) => write!(f, "Invalid date: {:?}", s),
+            Error::InvalidKind(s) => write!(f, "Unknown file kind: {:?}", s),
+            Error::InvalidApath(s) => write!(f, "Invalid apath (archive path): {:?}", s),
+            Error::ApathNotFound(a) => write!(f, "Not found in the archive: {}", a),
+            Error::NotAStoredFile(a) => write!(f, "Not a file in the archive: {}", a),
+            Error::MountFailed { mountpoint, error } => {
+                write!(f, "Failed to mount on {:?}: {}", mountpoint, error)
+            }
+            Error::ExportIncomplete(a) => {
+                write!(f, "Export is incomplete: couldn't write all of {}", a)
+            }
+            Error::ZipError(e) => write!(f, "Zip error: {}", e),
             Error::UnsupportedArchiveVersion(v) => write!(
                 f,
                 "Archive version {:?} is not supported by Conserve {}",
@@ -85,6 +141,12 @@ impl From<globset::Error> for Error {
     }
 }
 
+impl From<zip::result::ZipError> for Error {
+    fn from(c: zip::result::ZipError) -> Error {
+        Error::ZipError(c)
+    }
+}
+
 impl From<serde_json::Error> for Error {
     fn from(c: serde_json::Error) -> Error {
         Error::JsonDeserialize(c)
@@ -1,7 +1,11 @@
 // Copyright 2017 Julian Raufelder.
 
-//! Create GlobSet from a list of strings
+//! Create GlobSet from a list of strings, and exclude entries by their metadata.
 
+use std::fs;
+use std::path::Path;
+
+use chrono::{DateTime, UTC};
 use globset::{Glob, GlobSet, GlobSetBuilder};
 
 use super::*;
@@ -23,6 +27,88 @@ pub fn excludes_nothing() -> GlobSet {
     GlobSetBuilder::new().build().unwrap()
 }
 
+/// Read exclusion patterns from a file, one per line.
+///
+/// Leading and trailing whitespace is stripped; blank lines and lines starting
+/// with `#` are ignored.
+pub fn read_patterns_file(path: &Path) -> Result<Vec<String>> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+/// Exclude entries by their size, modification time or kind, rather than by name.
+///
+/// The size and time limits apply only to files, so that directories are always kept
+/// unless their kind is excluded.
+#[derive(Clone, Debug, Default)]
+pub struct MetadataExcludes {
+    /// Exclude files larger than this many bytes.
+    pub larger_than: Option<u64>,
+
+    /// Exclude files last modified before this time.
+    pub older_than: Option<DateTime<UTC>>,
+
+    /// Exclude files last modified after this time.
+    pub newer_than: Option<DateTime<UTC>>,
+
+    /// Exclude all entries of these kinds.
+    pub kinds: Vec<Kind>,
+}
+
+impl MetadataExcludes {
+    /// Exclude nothing.
+    pub fn nothing() -> MetadataExcludes {
+        MetadataExcludes::default()
+    }
+
+    /// Decide whether to exclude an entry.
+    ///
+    /// Returns the name of the `skipped.*` counter to increment if it's excluded, or
+    /// None if it should be kept.
+    pub fn check(&self, entry: &Entry) -> Option<&'static str> {
+        if self.kinds.contains(&entry.kind) {
+            return Some(match entry.kind {
+                Kind::File => "skipped.kind.files",
+                Kind::Dir => "skipped.kind.directories",
+                Kind::Symlink => "skipped.kind.symlinks",
+                Kind::Unknown => "skipped.kind.unknown",
+            });
+        }
+        if entry.kind != Kind::File {
+            return None;
+        }
+        if let (Some(limit), Some(size)) = (self.larger_than, entry.size) {
+            if size > limit {
+                return Some("skipped.large_files");
+            }
+        }
+        if let Some(mtime) = entry.mtime {
+            let mtime = mtime as i64;
+            match (self.older_than, self.newer_than) {
+                (Some(t), _) if mtime < t.timestamp() => return Some("skipped.old_files"),
+                (_, Some(t)) if mtime > t.timestamp() => return Some("skipped.new_files"),
+                _ => (),
+            }
+        }
+        None
+    }
+}
+
+/// Parse the name of a kind of entry, as used on the command line.
+pub fn parse_kind(s: &str) -> Result<Kind> {
+    match s {
+        "file" => Ok(Kind::File),
+        "dir" => Ok(Kind::Dir),
+        "symlink" => Ok(Kind::Symlink),
+        "unknown" => Ok(Kind::Unknown),
+        _ => Err(Error::InvalidKind(s.to_owned())),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::super::*;
@@ -53,6 +139,69 @@ mod tests {
         assert_eq!(excludes.matches("a").len(), 0);
     }
 
+    #[test]
+    pub fn patterns_file() {
+        let tf = test_fixtures::TreeFixture::new();
+        tf.create_file_with_contents(
+            "excludes",
+            b"# Editor droppings\n/**/*.swp\n\n  /tmp  \n/**/target\n",
+        );
+        let patterns = excludes::read_patterns_file(&tf.path().join("excludes")).unwrap();
+        assert_eq!(patterns, &["/**/*.swp", "/tmp", "/**/target"]);
+    }
+
+    #[test]
+    pub fn metadata_excludes() {
+        use chrono::{TimeZone, UTC};
+
+        let file = |size, mtime| Entry {
+            apath: Apath::from("/a"),
+            kind: Kind::File,
+            mtime: Some(mtime),
+            target: None,
+            size: Some(size),
+            addrs: vec![],
+        };
+        let t = UTC.ymd(2019, 1, 1).and_hms(0, 0, 0);
+        let t_secs = t.timestamp() as u64;
+        let me = excludes::MetadataExcludes {
+            larger_than: Some(1000),
+            older_than: Some(t),
+            newer_than: Some(t + chrono::Duration::days(10)),
+            kinds: vec![Kind::Symlink],
+        };
+        assert_eq!(me.check(&file(1000, t_secs)), None);
+        assert_eq!(me.check(&file(1001, t_secs)), Some("skipped.large_files"));
+        assert_eq!(me.check(&file(10, t_secs - 1)), Some("skipped.old_files"));
+        assert_eq!(
+            me.check(&file(10, t_secs + 11 * 86400)),
+            Some("skipped.new_files")
+        );
+        let old_dir = Entry {
+            kind: Kind::Dir,
+            size: None,
+            ..file(0, 0)
+        };
+        assert_eq!(me.check(&old_dir), None);
+        let link = Entry {
+            kind: Kind::Symlink,
+            target: Some("a".to_owned()),
+            ..old_dir
+        };
+        assert_eq!(me.check(&link), Some("skipped.kind.symlinks"));
+
+        assert_eq!(
+            excludes::MetadataExcludes::nothing().check(&file(1 << 40, 0)),
+            None
+        );
+    }
+
+    #[test]
+    pub fn parse_kind() {
+        assert_eq!(excludes::parse_kind("symlink").unwrap(), Kind::Symlink);
+        assert!(excludes::parse_kind("pipe").is_err());
+    }
+
     #[test]
     pub fn nothing_parse() {
         let excludes = excludes::excludes_nothing();
@@ -0,0 +1,377 @@
+// Conserve backup system.
+// Copyright 2019 Martin Pool.
+
+//! Export a tree as a tar or zip file, for people who don't have Conserve.
+//!
+//! Conserve doesn't store permissions, so exported files are given mode 0644, and
+//! directories 0755. The top directory of the tree isn't written as an entry.
+
+use std::io;
+use std::io::prelude::*;
+
+use chrono::{Datelike, Local, TimeZone, Timelike};
+use zip::write::FileOptions;
+
+use crate::*;
+
+const FILE_MODE: u32 = 0o644;
+const DIR_MODE: u32 = 0o755;
+const SYMLINK_MODE: u32 = 0o777;
+
+/// The name of an entry within an exported tar or zip file: its apath without the
+/// leading slash.
+fn export_name(entry: &Entry) -> Option<&str> {
+    let name = &entry.apath[1..];
+    if name.is_empty() {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+/// The name of a file or symlink in an exported tar or zip file.
+///
+/// Only directories can be the top of the tree, so if a damaged index says otherwise the
+/// entry is reported and should be skipped.
+fn export_name_or_problem<'e>(entry: &'e Entry, report: &Report) -> Option<&'e str> {
+    let name = export_name(entry);
+    if name.is_none() {
+        report.problem(Problem::new(
+            ProblemKind::BadIndex,
+            entry.apath(),
+            "Top of the tree isn't a directory; skipped",
+        ));
+    }
+    name
+}
+
+/// A write-only tree that writes a tar stream.
+///
+/// Tar can't go back to fix up an entry, so if a file can't be completely read, the
+/// stream is left unusable, and every later write fails.
+pub struct TarWriteTree<W: Write> {
+    builder: tar::Builder<W>,
+    report: Report,
+
+    /// The file that couldn't be completely written, if any.
+    failed: Option<Apath>,
+}
+
+impl<W: Write> TarWriteTree<W> {
+    /// Write a tar stream to `out`.
+    pub fn new(out: W, report: &Report) -> TarWriteTree<W> {
+        TarWriteTree {
+            builder: tar::Builder::new(out),
+            report: report.clone(),
+            failed: None,
+        }
+    }
+
+    fn check_not_failed(&self) -> Result<()> {
+        match &self.failed {
+            Some(apath) => Err(Error::ExportIncomplete(apath.clone())),
+            None => Ok(()),
+        }
+    }
+
+    fn header(entry: &Entry, entry_type: tar::EntryType, mode: u32, size: u64) -> tar::Header {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_mode(mode);
+        header.set_size(size);
+        header.set_mtime(entry.mtime.unwrap_or(0));
+        header
+    }
+}
+
+impl<W: Write> WriteTree for TarWriteTree<W> {
+    fn finish(&mut self) -> Result<()> {
+        self.check_not_failed()?;
+        self.builder.finish()?;
+        Ok(self.builder.get_mut().flush()?)
+    }
+
+    fn write_dir(&mut self, entry: &Entry) -> Result<()> {
+        self.check_not_failed()?;
+        if let Some(name) = export_name(entry) {
+            let mut header = Self::header(entry, tar::EntryType::Directory, DIR_MODE, 0);
+            self.builder
+                .append_data(&mut header, format!("{}/", name), io::empty())?;
+        }
+        Ok(())
+    }
+
+    fn write_symlink(&mut self, entry: &Entry) -> Result<()> {
+        self.check_not_failed()?;
+        let name = match export_name_or_problem(entry, &self.report) {
+            Some(name) => name,
+            None => return Ok(()),
+        };
+        let target = match entry.symlink_target() {
+            Some(target) => target,
+            None => {
+                self.report.problem(Problem::new(
+                    ProblemKind::BadIndex,
+                    entry.apath(),
+                    "No target in symlink entry",
+                ));
+                return Ok(());
+            }
+        };
+        let mut header = Self::header(entry, tar::EntryType::Symlink, SYMLINK_MODE, 0);
+        self.builder.append_link(&mut header, name, target)?;
+        Ok(())
+    }
+
+    fn write_file(&mut self, entry: &Entry, content: &mut dyn Read) -> Result<()> {
+        self.check_not_failed()?;
+        let name = match export_name_or_problem(entry, &self.report) {
+            Some(name) => name,
+            None => return Ok(()),
+        };
+        let size = entry.size().unwrap_or(0);
+        let mut header = Self::header(entry, tar::EntryType::Regular, FILE_MODE, size);
+        // The size is in the header before the content, so the content must be exactly
+        // that long.
+        let mut content = content.take(size);
+        let result = self.builder.append_data(&mut header, name, &mut content);
+        if result.is_err() || content.limit() > 0 {
+            self.failed = Some(entry.apath());
+        }
+        result?;
+        self.check_not_failed()
+    }
+}
+
+/// A write-only tree that writes a zip file.
+///
+/// Zip files can't hold symlinks, so they're skipped and reported as problems.
+///
+/// Entries are independent, so if a file can't be completely read, the rest are still
+/// written, but the zip file holds a truncated copy of that file, and `finish` fails.
+pub struct ZipWriteTree<W: Write + Seek> {
+    zip: zip::ZipWriter<W>,
+    report: Report,
+
+    /// The first file that couldn't be completely written, if any.
+    failed: Option<Apath>,
+}
+
+impl<W: Write + Seek> ZipWriteTree<W> {
+    /// Write a zip file to `out`.
+    pub fn new(out: W, report: &Report) -> ZipWriteTree<W> {
+        ZipWriteTree {
+            zip: zip::ZipWriter::new(out),
+            report: report.clone(),
+            failed: None,
+        }
+    }
+
+    fn options(entry: &Entry, mode: u32) -> FileOptions {
+        let options = FileOptions::default().unix_permissions(mode);
+        // Zip stores local times, from 1980 to 2107; others are left as the default.
+        match entry
+            .mtime
+            .and_then(|mtime| Local.timestamp_opt(mtime as i64, 0).earliest())
+            .map(|t| {
+                zip::DateTime::from_date_and_time(
+                    t.year() as u16,
+                    t.month() as u8,
+                    t.day() as u8,
+                    t.hour() as u8,
+                    t.minute() as u8,
+                    t.second() as u8,
+                )
+            }) {
+            Some(Ok(datetime)) => options.last_modified_time(datetime),
+            _ => options,
+        }
+    }
+}
+
+impl<W: Write + Seek> WriteTree for ZipWriteTree<W> {
+    fn finish(&mut self) -> Result<()> {
+        self.zip.finish()?;
+        match &self.failed {
+            Some(apath) => Err(Error::ExportIncomplete(apath.clone())),
+            None => Ok(()),
+        }
+    }
+
+    fn write_dir(&mut self, entry: &Entry) -> Result<()> {
+        if let Some(name) = export_name(entry) {
+            self.zip
+                .add_directory(name, Self::options(entry, DIR_MODE))?;
+        }
+        Ok(())
+    }
+
+    fn write_symlink(&mut self, entry: &Entry) -> Result<()> {
+        self.report.problem(Problem::new(
+            ProblemKind::UnsupportedKind,
+            entry.apath(),
+            "Zip files can't hold symlinks; skipped",
+        ));
+        Ok(())
+    }
+
+    fn write_file(&mut self, entry: &Entry, content: &mut dyn Read) -> Result<()> {
+        let name = match export_name_or_problem(entry, &self.report) {
+            Some(name) => name,
+            None => return Ok(()),
+        };
+        let options = Self::options(entry, FILE_MODE)
+            .large_file(entry.size().unwrap_or(0) >= u64::from(u32::MAX));
+        self.zip.start_file(name, options)?;
+        if let Err(e) = io::copy(content, &mut self.zip) {
+            self.failed.get_or_insert_with(|| entry.apath());
+            return Err(e.into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::test_fixtures::*;
+
+    fn stored_tree() -> (ScratchArchive, StoredTree) {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let st = StoredTree::open_last(&af).unwrap();
+        (af, st)
+    }
+
+    #[test]
+    fn export_tar() {
+        let (_af, st) = stored_tree();
+        let mut buf = Vec::new();
+        copy_tree(&st, &mut TarWriteTree::new(&mut buf, st.report())).unwrap();
+        assert_eq!(st.report().borrow_counts().count_problems(), 0);
+
+        let mut tar = tar::Archive::new(Cursor::new(buf));
+        let mut names = Vec::new();
+        for entry in tar.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let header = entry.header();
+            let name = entry.path().unwrap().to_string_lossy().into_owned();
+            assert!(header.mtime().unwrap() > 0);
+            match header.entry_type() {
+                tar::EntryType::Regular => {
+                    assert_eq!(header.mode().unwrap(), 0o644);
+                    let mut content = String::new();
+                    entry.read_to_string(&mut content).unwrap();
+                    assert_eq!(content, "contents");
+                }
+                tar::EntryType::Directory => assert_eq!(header.mode().unwrap(), 0o755),
+                tar::EntryType::Symlink => {
+                    assert_eq!(entry.link_name().unwrap().unwrap().to_str(), Some("target"))
+                }
+                other => panic!("unexpected entry type {:?}", other),
+            }
+            names.push(name);
+        }
+        let mut expected = vec!["hello", "hello2", "link", "subdir/", "subdir/subfile"];
+        if !SYMLINKS_SUPPORTED {
+            expected.retain(|n| *n != "link");
+        }
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    fn export_tar_stops_after_damaged_file() {
+        let af = ScratchArchive::new();
+        af.store_version_in_hunks();
+        af.damage(Damage::FlipBlockBit);
+        let st = StoredTree::open_last(&af).unwrap();
+        let mut buf = Vec::new();
+        let mut tt = TarWriteTree::new(&mut buf, af.report());
+        copy_tree(&st, &mut tt).unwrap_err();
+        assert!(af.report().borrow_counts().count_problems() > 0);
+    }
+
+    #[test]
+    fn export_zip_fails_after_damaged_file() {
+        let af = ScratchArchive::new();
+        af.store_version_in_hunks();
+        af.damage(Damage::FlipBlockBit);
+        let st = StoredTree::open_last(&af).unwrap();
+        let mut buf = Cursor::new(Vec::new());
+        match copy_tree(&st, &mut ZipWriteTree::new(&mut buf, af.report())) {
+            Err(Error::ExportIncomplete(_)) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(af.report().borrow_counts().count_problems() > 0);
+        // The rest of the zip is still complete and readable.
+        zip::ZipArchive::new(buf).unwrap();
+    }
+
+    #[test]
+    fn export_zip() {
+        let (af, st) = stored_tree();
+        let mut buf = Cursor::new(Vec::new());
+        copy_tree(&st, &mut ZipWriteTree::new(&mut buf, af.report())).unwrap();
+        let expected_problems = if SYMLINKS_SUPPORTED { 1 } else { 0 };
+        assert_eq!(
+            af.report()
+                .borrow_counts()
+                .count_problems_of_kind(ProblemKind::UnsupportedKind),
+            expected_problems
+        );
+
+        let mut zip = zip::ZipArchive::new(buf).unwrap();
+        let mut names = Vec::new();
+        for i in 0..zip.len() {
+            let mut file = zip.by_index(i).unwrap();
+            names.push(file.name().to_owned());
+            if file.is_file() {
+                assert_eq!(file.unix_mode(), Some(0o100644));
+                let mut content = String::new();
+                file.read_to_string(&mut content).unwrap();
+                assert_eq!(content, "contents");
+            } else {
+                assert_eq!(file.unix_mode(), Some(0o40755));
+            }
+        }
+        assert_eq!(names, ["hello", "hello2", "subdir/", "subdir/subfile"]);
+    }
+
+    #[test]
+    fn damaged_entries_are_skipped() {
+        let report = Report::new();
+        let top_as_file = Entry {
+            apath: Apath::from("/"),
+            kind: Kind::File,
+            mtime: None,
+            addrs: Vec::new(),
+            target: None,
+            size: Some(0),
+        };
+        let symlink_without_target = Entry {
+            apath: Apath::from("/link"),
+            kind: Kind::Symlink,
+            ..top_as_file.clone()
+        };
+        let mut buf = Vec::new();
+        {
+            let mut tt = TarWriteTree::new(&mut buf, &report);
+            tt.write_file(&top_as_file, &mut io::empty()).unwrap();
+            tt.write_symlink(&symlink_without_target).unwrap();
+            tt.finish().unwrap();
+        }
+        let mut zt = ZipWriteTree::new(Cursor::new(Vec::new()), &report);
+        zt.write_file(&top_as_file, &mut io::empty()).unwrap();
+        assert_eq!(
+            report
+                .borrow_counts()
+                .count_problems_of_kind(ProblemKind::BadIndex),
+            3
+        );
+
+        let mut tar = tar::Archive::new(Cursor::new(buf));
+        assert_eq!(tar.entries().unwrap().count(), 0);
+    }
+}
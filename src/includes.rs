@@ -0,0 +1,353 @@
+// Conserve backup system.
+// Copyright 2019 Martin Pool.
+
+//! Select the parts of a tree to be included, by glob patterns or by subtrees.
+//!
+//! Unlike exclusions, which can simply drop any entry that matches, including
+//! an entry also requires including the directories above it, and descending
+//! into directories that might contain included entries.
+
+use std::cmp::Ordering;
+
+use globset::{Glob, GlobMatcher};
+
+use super::*;
+
+/// How an entry relates to an `Includes`.
+///
+/// These are ordered from least to most included.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Selection {
+    /// Not included, and nothing beneath it can be.
+    Excluded,
+    /// Not included itself, but it's a directory that might contain included entries.
+    Ancestor,
+    /// Included, along with everything beneath it.
+    Included,
+}
+
+/// One component of an include pattern, used to decide which directories might contain
+/// matches.
+#[derive(Clone, Debug)]
+enum Component {
+    Literal(String),
+    Glob(GlobMatcher),
+    /// Matches any number of directories.
+    AnyDepth,
+}
+
+#[derive(Clone, Debug)]
+enum Rule {
+    /// Entries matching a glob pattern, and everything inside them.
+    Glob {
+        matcher: GlobMatcher,
+        /// Components of the pattern, or None if the pattern is not anchored at the root.
+        components: Option<Vec<Component>>,
+    },
+    /// An apath and everything inside it.
+    Subtree(Apath),
+}
+
+/// A set of rules selecting which entries are included in a tree.
+///
+/// An entry is included if any rule includes it, or if it's inside an included directory.
+/// An `Includes` with no rules includes everything.
+#[derive(Clone, Debug, Default)]
+pub struct Includes {
+    rules: Vec<Rule>,
+}
+
+impl Includes {
+    /// Include everything.
+    pub fn everything() -> Includes {
+        Includes::default()
+    }
+
+    /// Include entries matching any of some glob patterns, and everything inside them.
+    ///
+    /// Patterns use the same syntax as excludes.
+    pub fn from_strings<I: IntoIterator<Item = S>, S: AsRef<str>>(patterns: I) -> Result<Includes> {
+        let mut rules = Vec::new();
+        for p in patterns {
+            let p = p.as_ref();
+            let matcher = Glob::new(p)?.compile_matcher();
+            let components = if let Some(rooted) = p.strip_prefix('/') {
+                Some(
+                    rooted
+                        .split('/')
+                        .map(|c| {
+                            if c.contains("**") {
+                                Ok(Component::AnyDepth)
+                            } else if c.contains(|ch| "*?[]{}\\".contains(ch)) {
+                                Ok(Component::Glob(Glob::new(c)?.compile_matcher()))
+                            } else {
+                                Ok(Component::Literal(c.to_owned()))
+                            }
+                        })
+                        .collect::<Result<Vec<Component>>>()?,
+                )
+            } else {
+                None
+            };
+            rules.push(Rule::Glob {
+                matcher,
+                components,
+            });
+        }
+        Ok(Includes { rules })
+    }
+
+    /// Include only the given subtrees, and the directories above them.
+    pub fn from_subtrees<I: IntoIterator<Item = Apath>>(subtrees: I) -> Includes {
+        Includes {
+            rules: subtrees.into_iter().map(Rule::Subtree).collect(),
+        }
+    }
+
+    /// Also include the given subtrees, and the directories above them.
+    pub fn with_subtrees<I: IntoIterator<Item = Apath>>(mut self, subtrees: I) -> Includes {
+        self.rules.extend(subtrees.into_iter().map(Rule::Subtree));
+        self
+    }
+
+    /// True if this includes everything.
+    pub fn is_everything(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Decide whether an apath is included.
+    pub fn select(&self, apath: &str) -> Selection {
+        if self.rules.is_empty() {
+            return Selection::Included;
+        }
+        self.rules
+            .iter()
+            .map(|r| r.select(apath))
+            .max()
+            .unwrap_or(Selection::Excluded)
+    }
+
+    /// True if any apath after `after` (or from the start, if it's None), up to and
+    /// including `upto`, might be selected.
+    ///
+    /// This is used to skip over parts of an index that can't contain anything selected.
+    /// It may return true even if nothing in that range is selected, but never false
+    /// if something is.
+    pub(crate) fn might_select_between(&self, after: Option<&Apath>, upto: &Apath) -> bool {
+        self.rules.is_empty()
+            || self
+                .rules
+                .iter()
+                .any(|r| r.might_select_between(after, upto))
+    }
+}
+
+impl Rule {
+    fn select(&self, apath: &str) -> Selection {
+        match self {
+            Rule::Subtree(root) => {
+                if is_within(apath, root) {
+                    Selection::Included
+                } else if is_within(root, apath) {
+                    Selection::Ancestor
+                } else {
+                    Selection::Excluded
+                }
+            }
+            Rule::Glob {
+                matcher,
+                components,
+            } => {
+                if self_and_parents(apath).any(|a| matcher.is_match(a)) {
+                    Selection::Included
+                } else if could_contain_match(components, apath) {
+                    Selection::Ancestor
+                } else {
+                    Selection::Excluded
+                }
+            }
+        }
+    }
+
+    fn might_select_between(&self, after: Option<&Apath>, upto: &Apath) -> bool {
+        let root = match self {
+            Rule::Subtree(root) => root,
+            // Matches of a glob could be anywhere.
+            Rule::Glob { .. } => return true,
+        };
+        let is_after = |a: &Apath| match after {
+            Some(after) => a > after,
+            None => true,
+        };
+        // The root and the directories above it are selected individually.
+        if self_and_parents(root)
+            .map(Apath::from)
+            .any(|a| is_after(&a) && a <= *upto)
+        {
+            return true;
+        }
+        // Everything inside the root is contiguous in apath order, so some of it is in
+        // the range unless the range is entirely before or entirely after it.
+        match contents_position(upto, root) {
+            Ordering::Less => false,
+            Ordering::Equal => is_after(upto),
+            Ordering::Greater => match after {
+                Some(after) => contents_position(after, root) != Ordering::Greater,
+                None => true,
+            },
+        }
+    }
+}
+
+/// Compare an apath to the contents of the directory `dir`, which are contiguous in
+/// apath order.
+///
+/// Returns `Equal` if `apath` is inside `dir`, otherwise whether it's before or after all
+/// the contents of `dir`.
+fn contents_position(apath: &Apath, dir: &Apath) -> Ordering {
+    if apath != dir && is_within(apath, dir) {
+        Ordering::Equal
+    } else {
+        // Any apath inside the directory is ordered the same way relative to one outside it.
+        let child = if *dir == *"/" {
+            "/a".to_owned()
+        } else {
+            format!("{}/a", dir)
+        };
+        apath.cmp(&Apath::from(child))
+    }
+}
+
+/// True if `apath` is `dir` or inside it.
+pub(crate) fn is_within(apath: &str, dir: &str) -> bool {
+    dir == "/" || apath == dir || (apath.starts_with(dir) && apath[dir.len()..].starts_with('/'))
+}
+
+/// Return the apaths of all the directories containing `apath`, and then `apath` itself.
+fn self_and_parents(apath: &str) -> impl Iterator<Item = &str> {
+    apath
+        .match_indices('/')
+        .map(move |(i, _)| if i == 0 { "/" } else { &apath[..i] })
+        .chain(std::iter::once(apath))
+}
+
+/// True if a directory could contain entries matched by a pattern with these components.
+fn could_contain_match(components: &Option<Vec<Component>>, dir_apath: &str) -> bool {
+    let components = match components {
+        // Not anchored, so could match anywhere.
+        None => return true,
+        Some(c) => c,
+    };
+    let names: Vec<&str> = dir_apath.split('/').filter(|n| !n.is_empty()).collect();
+    for (i, name) in names.iter().enumerate() {
+        match components.get(i) {
+            None => return false,
+            Some(Component::AnyDepth) => return true,
+            Some(Component::Literal(l)) if l != name => return false,
+            Some(Component::Glob(g)) if !g.is_match(name) => return false,
+            Some(_) => (),
+        }
+    }
+    names.len() < components.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Selection::*;
+    use super::*;
+
+    #[test]
+    fn everything() {
+        let inc = Includes::everything();
+        assert!(inc.is_everything());
+        assert_eq!(inc.select("/"), Included);
+        assert_eq!(inc.select("/a/b"), Included);
+    }
+
+    #[test]
+    fn glob_patterns() {
+        let inc = Includes::from_strings(&["/home/*/Documents/**", "/etc"]).unwrap();
+        assert_eq!(inc.select("/"), Ancestor);
+        assert_eq!(inc.select("/etc"), Included);
+        assert_eq!(inc.select("/etc/passwd"), Included);
+        assert_eq!(inc.select("/etc.old"), Excluded);
+        assert_eq!(inc.select("/home"), Ancestor);
+        assert_eq!(inc.select("/home/mbp"), Ancestor);
+        assert_eq!(inc.select("/home/mbp/Documents"), Included);
+        assert_eq!(inc.select("/home/mbp/Documents/cv.txt"), Included);
+        assert_eq!(inc.select("/home/mbp/Music"), Excluded);
+        assert_eq!(inc.select("/home/mbp/Music/x.mp3"), Excluded);
+        assert_eq!(inc.select("/srv"), Excluded);
+    }
+
+    #[test]
+    fn unanchored_pattern_may_be_anywhere() {
+        let inc = Includes::from_strings(&["*.txt"]).unwrap();
+        assert_eq!(inc.select("/a"), Ancestor);
+        assert_eq!(inc.select("/a/b.txt"), Included);
+    }
+
+    #[test]
+    fn subtrees() {
+        let inc = Includes::from_subtrees(vec![Apath::from("/etc"), Apath::from("/home/mbp")]);
+        assert_eq!(inc.select("/"), Ancestor);
+        assert_eq!(inc.select("/etc"), Included);
+        assert_eq!(inc.select("/etc/hosts"), Included);
+        assert_eq!(inc.select("/etcetera"), Excluded);
+        assert_eq!(inc.select("/home"), Ancestor);
+        assert_eq!(inc.select("/home/mbp"), Included);
+        assert_eq!(inc.select("/home/mbp/src"), Included);
+        assert_eq!(inc.select("/home/other"), Excluded);
+        assert_eq!(inc.select("/srv"), Excluded);
+
+        let all = Includes::from_subtrees(vec![Apath::from("/")]);
+        assert_eq!(all.select("/"), Included);
+        assert_eq!(all.select("/a"), Included);
+    }
+
+    #[test]
+    fn with_subtrees() {
+        let inc = Includes::from_strings(&["/*.txt"])
+            .unwrap()
+            .with_subtrees(vec![Apath::from("/src")]);
+        assert_eq!(inc.select("/a.txt"), Included);
+        assert_eq!(inc.select("/src/main.rs"), Included);
+        assert_eq!(inc.select("/a.jpg"), Excluded);
+        assert!(
+            Includes::everything()
+                .with_subtrees(vec![Apath::from("/src")])
+                .select("/a.txt")
+                == Excluded
+        );
+    }
+
+    #[test]
+    fn might_select_between() {
+        let inc = Includes::from_subtrees(vec![Apath::from("/b/c")]);
+        let between = |after: Option<&str>, upto: &str| {
+            inc.might_select_between(after.map(Apath::from).as_ref(), &Apath::from(upto))
+        };
+        // The root and /b are needed.
+        assert!(between(None, "/"));
+        assert!(!between(Some("/"), "/a"));
+        assert!(between(Some("/a"), "/b"));
+        assert!(!between(Some("/b"), "/b/a"));
+        assert!(between(Some("/b"), "/b/c"));
+        // Other subdirectories of /b come before the contents of /b/c...
+        assert!(!between(Some("/b/c"), "/b/bb/z"));
+        // ... but /b/c/x is in the range, even though it's not named.
+        assert!(between(Some("/b/bb/z"), "/b/d/z"));
+        assert!(between(Some("/b/c/x"), "/b/c/y"));
+        assert!(!between(Some("/b/d/z"), "/c/d"));
+
+        // Globs might match anywhere.
+        let inc = Includes::from_strings(&["/b/c"]).unwrap();
+        assert!(inc.might_select_between(Some(&Apath::from("/x")), &Apath::from("/y")));
+        assert!(Includes::everything().might_select_between(None, &Apath::from("/")));
+    }
+
+    #[test]
+    fn bad_pattern() {
+        assert!(Includes::from_strings(&["/a/[b"]).is_err());
+    }
+}
@@ -6,6 +6,7 @@
 use std::fmt;
 use std::fs;
 use std::io;
+use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 use std::str;
 use std::vec;
@@ -15,6 +16,8 @@ use super::*;
 
 use globset::GlobSet;
 
+use crate::includes::{Includes, Selection};
+
 pub const MAX_ENTRIES_PER_HUNK: usize = 1000;
 
 /// Accumulates ordered changes to the index and streams them out to index files.
@@ -145,6 +148,154 @@ impl ReadIndex {
     pub fn iter(&self, excludes: &GlobSet, report: &Report) -> Result<index::Iter> {
         index::Iter::open(&self.dir, excludes, report)
     }
+
+    /// Check the index is well-formed, and report any problems to `report`.
+    ///
+    /// The hunks must be numbered contiguously from 0, each in the right subdirectory,
+    /// with nothing else in the index directory. Every hunk must be readable, and the
+    /// apaths across all of them must be valid, and in order without duplicates.
+    pub fn validate(&self, report: &Report) -> Result<()> {
+        let hunks = self.validate_hunk_names(report)?;
+        let mut last_apath: Option<Apath> = None;
+        for hunk_number in hunks {
+            let hunk_path = path_for_hunk(&self.dir, hunk_number);
+            let entries = match read_hunk(&self.dir, hunk_number, report) {
+                Ok(Some(entries)) => entries,
+                Ok(None) => continue,
+                Err(e) => {
+                    report.problem(
+                        Problem::for_path(
+                            ProblemKind::BadIndex,
+                            &hunk_path,
+                            "Can't read index hunk",
+                        )
+                        .with_error(e),
+                    );
+                    continue;
+                }
+            };
+            for entry in entries {
+                let apath = entry.apath;
+                let message = if !Apath::is_valid(&apath) {
+                    format!("Invalid apath {:?} in index hunk", apath.to_string())
+                } else {
+                    match last_apath.replace(apath.clone()) {
+                        Some(ref last) if *last == apath => {
+                            format!("Duplicated apath {:?} in index hunk", apath.to_string())
+                        }
+                        Some(ref last) if *last > apath => format!(
+                            "Apath {:?} is out of order after {:?} in index hunk",
+                            apath.to_string(),
+                            last.to_string()
+                        ),
+                        _ => continue,
+                    }
+                };
+                report.problem(Problem::for_path(
+                    ProblemKind::BadIndex,
+                    &hunk_path,
+                    &message,
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the paths of all the hunks in the index, in order.
+    pub(crate) fn hunk_paths(&self) -> Result<Vec<PathBuf>> {
+        Ok(list_hunks(&self.dir, &mut |_, _| ())?
+            .into_iter()
+            .map(|n| path_for_hunk(&self.dir, n))
+            .collect())
+    }
+
+    /// Copy every hunk file, unchanged, into another index directory.
+    pub(crate) fn copy_hunks_to(&self, dest_dir: &Path, report: &Report) -> Result<()> {
+        for hunk_number in list_hunks(&self.dir, &mut |_, _| ())? {
+            let content = fs::read(path_for_hunk(&self.dir, hunk_number))?;
+            ensure_dir_exists(&subdir_for_hunk(dest_dir, hunk_number))?;
+            let mut f = AtomicFile::new(&path_for_hunk(dest_dir, hunk_number))?;
+            f.write_all(&content)?;
+            f.close(report)?;
+        }
+        Ok(())
+    }
+
+    /// Return the paths of the hunks at the end of the index that can't be decompressed or
+    /// deserialized, such as might be left by an interrupted backup, in order.
+    ///
+    /// Other errors reading the hunks are returned, rather than counted as damage.
+    pub fn damaged_trailing_hunks(&self) -> Result<Vec<PathBuf>> {
+        let report = Report::with_ui(Box::new(ui::null::NullUI::new()));
+        let mut damaged = Vec::new();
+        for hunk_number in list_hunks(&self.dir, &mut |_, _| ())?.into_iter().rev() {
+            match read_hunk(&self.dir, hunk_number, &report) {
+                Ok(_) => break,
+                Err(Error::IndexCorrupt(_)) | Err(Error::JsonDeserialize(_)) => {
+                    damaged.insert(0, path_for_hunk(&self.dir, hunk_number))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(damaged)
+    }
+
+    /// Check the names of files and directories in the index directory, reporting any
+    /// that are unexpected or missing, and return the sorted numbers of all the hunks.
+    fn validate_hunk_names(&self, report: &Report) -> Result<Vec<u32>> {
+        let hunks = list_hunks(&self.dir, &mut |path, message| {
+            report.problem(Problem::for_path(
+                ProblemKind::UnexpectedFile,
+                path,
+                message,
+            ))
+        })?;
+        let mut expected = 0;
+        for &n in &hunks {
+            for missing in expected..n {
+                report.problem(Problem::for_path(
+                    ProblemKind::MissingFile,
+                    path_for_hunk(&self.dir, missing),
+                    "Missing index hunk",
+                ));
+            }
+            expected = n + 1;
+        }
+        Ok(hunks)
+    }
+}
+
+/// Return the sorted numbers of all the hunks present in an index directory.
+///
+/// Any other files or directories are passed to `unexpected`, with a description.
+fn list_hunks(dir: &Path, unexpected: &mut dyn FnMut(PathBuf, &str)) -> Result<Vec<u32>> {
+    let (files, subdirs) = list_dir(dir)?;
+    for f in files {
+        unexpected(dir.join(f), "Unexpected file in index directory");
+    }
+    let mut hunks = Vec::new();
+    for subdir in subdirs {
+        let subdir_path = dir.join(&subdir);
+        if subdir.len() != 5 || subdir.parse::<u32>().is_err() {
+            unexpected(subdir_path, "Unexpected directory in index directory");
+            continue;
+        }
+        let (files, dirs) = list_dir(&subdir_path)?;
+        for d in dirs {
+            unexpected(
+                subdir_path.join(d),
+                "Unexpected directory in index subdirectory",
+            );
+        }
+        for f in files {
+            match f.parse::<u32>() {
+                Ok(n) if f.len() == 9 && subdir_for_hunk(dir, n) == subdir_path => hunks.push(n),
+                _ => unexpected(subdir_path.join(f), "Unexpected file in index subdirectory"),
+            }
+        }
+    }
+    hunks.sort_unstable();
+    Ok(hunks)
 }
 
 /// Read out all the entries from an existing index, continuing across multiple
@@ -156,6 +307,9 @@ pub struct Iter {
     next_hunk_number: u32,
     pub report: Report,
     excludes: GlobSet,
+    includes: Includes,
+    /// The last apath in the most recently read hunk.
+    last_apath: Option<Apath>,
 }
 
 impl fmt::Debug for Iter {
@@ -197,48 +351,51 @@ impl Iter {
             next_hunk_number: 0,
             report: report.clone(),
             excludes: excludes.clone(),
+            includes: Includes::everything(),
+            last_apath: None,
         })
     }
 
+    /// Return a new iterator that returns only entries selected by `includes`, and the
+    /// directories above them.
+    ///
+    /// If only some subtrees are included, hunks of the index that can't contain any
+    /// of them are skipped without being read.
+    pub fn with_includes(self, includes: Includes) -> Iter {
+        Iter { includes, ..self }
+    }
+
     /// Read another hunk file and put it into buffered_entries.
     /// Returns true if another hunk could be found, otherwise false.
     /// (It's possible though unlikely the hunks can be empty.)
     fn refill_entry_buffer(&mut self) -> Result<bool> {
-        // Load the next index hunk into buffered_entries.
-        let hunk_path = path_for_hunk(&self.dir, self.next_hunk_number);
-        let mut f = match fs::File::open(&hunk_path) {
-            Ok(f) => f,
-            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
-                // No (more) index hunk files.
-                return Ok(false);
-            }
-            Err(e) => {
-                return Err(e.into());
-            }
+        // Load the next index hunk into buffered_entries. The hunk number is advanced
+        // first, so that a hunk that can't be read is returned as an error only once.
+        let hunk_number = self.next_hunk_number;
+        self.next_hunk_number += 1;
+        let mut entries = match self.read_hunk(hunk_number)? {
+            Some(entries) => entries,
+            None => return self.skip_missing_hunks(hunk_number),
         };
-        let (comp_len, index_bytes) = Snappy::decompress_read(&mut f)?;
-        self.report.increment_size(
-            "index",
-            Sizes {
-                uncompressed: index_bytes.len() as u64,
-                compressed: comp_len as u64,
-            },
-        );
-        self.report.increment("index.hunk", 1);
-
-        // TODO: More specific error messages including the filename.
-        let index_json = str::from_utf8(&index_bytes)
-            .or_else(|_| Err(Error::IndexCorrupt(hunk_path.clone())))?;
-        let entries: Vec<Entry> = serde_json::from_str(index_json)?;
-        if entries.is_empty() {
-            self.report
-                .problem(&format!("Index hunk {} is empty", hunk_path.display()));
+        if !self.hunk_might_be_selected(&entries) {
+            entries = match self.seek_selected_hunk()? {
+                Some(entries) => entries,
+                None => return Ok(false),
+            };
+        }
+        if let Some(last) = entries.last() {
+            self.last_apath = Some(last.apath.clone());
         }
 
         self.buffered_entries = entries
             .into_iter()
             .filter(|entry| {
-                if self.excludes.is_match(Path::new(&entry.apath.to_string())) {
+                let selected = match self.includes.select(&entry.apath) {
+                    Selection::Included => true,
+                    Selection::Ancestor => entry.kind() == Kind::Dir,
+                    Selection::Excluded => false,
+                };
+                if !selected || self.excludes.is_match(Path::new(&entry.apath.to_string())) {
                     match entry.kind() {
                         Kind::Dir => self.report.increment("skipped.excluded.directories", 1),
                         Kind::Symlink => self.report.increment("skipped.excluded.symlinks", 1),
@@ -252,15 +409,139 @@ impl Iter {
             })
             .collect::<Vec<Entry>>()
             .into_iter();
-
-        self.next_hunk_number += 1;
         Ok(true)
     }
+
+    /// Hunk `hunk_number` doesn't exist: if there are any later hunks, report the missing
+    /// ones as problems and carry on from the next one that exists.
+    ///
+    /// Returns true if there are more hunks to read.
+    fn skip_missing_hunks(&mut self, hunk_number: u32) -> Result<bool> {
+        let next = list_hunks(&self.dir, &mut |_, _| ())?
+            .into_iter()
+            .find(|&n| n > hunk_number);
+        match next {
+            None => Ok(false),
+            Some(next) => {
+                for missing in hunk_number..next {
+                    self.report.problem(Problem::for_path(
+                        ProblemKind::MissingFile,
+                        path_for_hunk(&self.dir, missing),
+                        "Missing index hunk",
+                    ));
+                }
+                self.next_hunk_number = next;
+                self.buffered_entries = Vec::new().into_iter();
+                Ok(true)
+            }
+        }
+    }
+
+    /// True if a hunk, following the one most recently read, might contain selected entries.
+    fn hunk_might_be_selected(&self, entries: &[Entry]) -> bool {
+        match entries.last() {
+            Some(last) => self
+                .includes
+                .might_select_between(self.last_apath.as_ref(), &last.apath),
+            None => true,
+        }
+    }
+
+    /// Binary search the remaining hunks for the first that might contain selected
+    /// entries, and return its entries, or None if there is none.
+    ///
+    /// This relies on all the entries selected by the includes, between the last hunk read
+    /// and the end of a hunk, being in a contiguous range.
+    fn seek_selected_hunk(&mut self) -> Result<Option<Vec<Entry>>> {
+        let mut lo = self.next_hunk_number;
+        let mut hi = ReadIndex::new(&self.dir).count_hunks()?;
+        let mut found = None;
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            match self.read_hunk(mid)? {
+                Some(entries) if self.hunk_might_be_selected(&entries) => {
+                    hi = mid;
+                    found = Some(entries);
+                }
+                Some(_) => lo = mid + 1,
+                None => hi = mid,
+            }
+        }
+        // Either `found` is from hunk `hi`, or there's nothing more to read.
+        self.next_hunk_number = hi + 1;
+        Ok(found)
+    }
+
+    /// Read and return the entries from one hunk, or None if it doesn't exist.
+    ///
+    /// Entries with invalid apaths are reported as problems and skipped, so that they're
+    /// never restored outside the destination.
+    fn read_hunk(&self, hunk_number: u32) -> Result<Option<Vec<Entry>>> {
+        let mut entries = match read_hunk(&self.dir, hunk_number, &self.report)? {
+            Some(entries) => entries,
+            None => return Ok(None),
+        };
+        entries.retain(|entry| {
+            let valid = Apath::is_valid(&entry.apath);
+            if !valid {
+                self.report.problem(
+                    Problem::for_path(
+                        ProblemKind::BadIndex,
+                        path_for_hunk(&self.dir, hunk_number),
+                        "Skipping invalid apath in index hunk",
+                    )
+                    .with_error(Error::InvalidApath(entry.apath.to_string())),
+                );
+            }
+            valid
+        });
+        Ok(Some(entries))
+    }
+}
+
+/// Read and return the entries from one hunk, or None if it doesn't exist.
+fn read_hunk(dir: &Path, hunk_number: u32, report: &Report) -> Result<Option<Vec<Entry>>> {
+    let hunk_path = path_for_hunk(dir, hunk_number);
+    let compressed = match fs::read(&hunk_path) {
+        Ok(compressed) => compressed,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            // No (more) index hunk files.
+            return Ok(None);
+        }
+        Err(e) => {
+            return Err(e.into());
+        }
+    };
+    // The file's already been read, so any error here is from decompression.
+    let (comp_len, index_bytes) = Snappy::decompress_read(&mut compressed.as_slice())
+        .map_err(|_| Error::IndexCorrupt(hunk_path.clone()))?;
+    report.increment_size(
+        "index",
+        Sizes {
+            uncompressed: index_bytes.len() as u64,
+            compressed: comp_len as u64,
+        },
+    );
+    report.increment("index.hunk", 1);
+
+    // TODO: Include the filename in JSON errors.
+    let index_json =
+        str::from_utf8(&index_bytes).map_err(|_| Error::IndexCorrupt(hunk_path.clone()))?;
+    let entries: Vec<Entry> = serde_json::from_str(index_json)?;
+    if entries.is_empty() {
+        report.problem(Problem::for_path(
+            ProblemKind::BadIndex,
+            &hunk_path,
+            "Index hunk is empty",
+        ));
+    }
+    Ok(Some(entries))
 }
 
 #[cfg(test)]
 mod tests {
-    use std::path::Path;
+    use std::fs;
+    use std::path::{Path, PathBuf};
 
     use tempfile::TempDir;
 
@@ -420,6 +701,41 @@ mod tests {
         assert_eq!(names, &["/1.1", "/1.2", "/2.1", "/2.2"]);
     }
 
+    #[test]
+    fn includes_skip_hunks() {
+        let (_testdir, mut ib, report) = scratch_indexbuilder();
+        let mut apaths = vec!["/".to_owned(), "/a".to_owned(), "/b".to_owned()];
+        for i in 0..100 {
+            apaths.push(format!("/a/{:02}", i));
+        }
+        for i in 0..100 {
+            apaths.push(format!("/b/{:02}", i));
+        }
+        for chunk in apaths.chunks(3) {
+            for a in chunk {
+                add_an_entry(&mut ib, a);
+            }
+            ib.finish_hunk(&report).unwrap();
+        }
+
+        let read_report = Report::new();
+        let includes = Includes::from_subtrees(vec![Apath::from("/b/07")]);
+        let it = super::Iter::open(&ib.dir, &excludes::excludes_nothing(), &read_report)
+            .unwrap()
+            .with_includes(includes);
+        let names: Vec<String> = it.map(|x| x.unwrap().apath.into()).collect();
+        assert_eq!(names, &["/b/07"]);
+        // Reading all 68 hunks is avoided.
+        assert!(read_report.get_count("index.hunk") < 20);
+
+        let includes = Includes::from_subtrees(vec![Apath::from("/a/99"), Apath::from("/b/00")]);
+        let it = super::Iter::open(&ib.dir, &excludes::excludes_nothing(), &report)
+            .unwrap()
+            .with_includes(includes);
+        let names: Vec<String> = it.map(|x| x.unwrap().apath.into()).collect();
+        assert_eq!(names, &["/a/99", "/b/00"]);
+    }
+
     #[test]
     #[should_panic]
     fn no_duplicate_paths() {
@@ -458,4 +774,169 @@ mod tests {
         let names: Vec<String> = it.map(|x| x.unwrap().apath.into()).collect();
         assert_eq!(names, &["/bar"]);
     }
+
+    fn problem_messages(report: &Report) -> Vec<(ProblemKind, String)> {
+        report
+            .borrow_counts()
+            .problems()
+            .iter()
+            .map(|p| (p.kind, p.message.clone()))
+            .collect()
+    }
+
+    /// Write a hunk file directly, compressed or not, without checking its content.
+    fn write_raw_hunk(dir: &Path, hunk_number: u32, content: &[u8], compress: bool) {
+        ensure_dir_exists(&super::subdir_for_hunk(dir, hunk_number)).unwrap();
+        let hunk_path = super::path_for_hunk(dir, hunk_number);
+        if compress {
+            let mut f = fs::File::create(&hunk_path).unwrap();
+            Snappy::compress_and_write(content, &mut f).unwrap();
+        } else {
+            fs::write(&hunk_path, content).unwrap();
+        }
+    }
+
+    #[test]
+    fn iter_skips_invalid_apaths() {
+        let (_testdir, ib, report) = scratch_indexbuilder();
+        let json = r#"[{"apath":"/a","kind":"File"},
+            {"apath":"/b/../../etc/passwd","kind":"File"},
+            {"apath":"","kind":"File"},
+            {"apath":"/c","kind":"File"}]"#;
+        write_raw_hunk(&ib.dir, 0, json.as_bytes(), true);
+
+        let it = super::Iter::open(&ib.dir, &excludes::excludes_nothing(), &report).unwrap();
+        let names: Vec<String> = it.map(|x| x.unwrap().apath.into()).collect();
+        assert_eq!(names, &["/a", "/c"]);
+        let problems = report.borrow_counts();
+        let problems = problems.problems();
+        assert_eq!(problems.len(), 2);
+        assert_eq!(problems[0].kind, ProblemKind::BadIndex);
+        match problems[0].error {
+            Some(Error::InvalidApath(ref a)) => assert_eq!(a, "/b/../../etc/passwd"),
+            ref other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn iter_continues_past_corrupt_hunk() {
+        let (_testdir, mut ib, report) = scratch_indexbuilder();
+        add_an_entry(&mut ib, "/a");
+        ib.finish_hunk(&report).unwrap();
+        add_an_entry(&mut ib, "/b");
+        ib.finish_hunk(&report).unwrap();
+        add_an_entry(&mut ib, "/c");
+        ib.finish_hunk(&report).unwrap();
+        // Not valid Snappy data.
+        write_raw_hunk(&ib.dir, 1, b"\xff\xff\xff\xff\xff\xff", false);
+
+        let mut it = super::Iter::open(&ib.dir, &excludes::excludes_nothing(), &report).unwrap();
+        assert_eq!(it.next().unwrap().unwrap().apath, Apath::from("/a"));
+        match it.next().unwrap() {
+            Err(Error::IndexCorrupt(path)) => {
+                assert_eq!(path, super::path_for_hunk(&ib.dir, 1))
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(it.next().unwrap().unwrap().apath, Apath::from("/c"));
+        assert!(it.next().is_none());
+    }
+
+    #[test]
+    fn damaged_trailing_hunks() {
+        let (_testdir, mut ib, report) = scratch_indexbuilder();
+        for apath in &["/a", "/b", "/c", "/d"] {
+            add_an_entry(&mut ib, apath);
+            ib.finish_hunk(&report).unwrap();
+        }
+        let index = ReadIndex::new(&ib.dir);
+        assert_eq!(
+            index.damaged_trailing_hunks().unwrap(),
+            Vec::<PathBuf>::new()
+        );
+
+        // A damaged hunk followed by a good one isn't trailing.
+        write_raw_hunk(&ib.dir, 2, b"\xff\xff\xff\xff", false);
+        assert_eq!(
+            index.damaged_trailing_hunks().unwrap(),
+            Vec::<PathBuf>::new()
+        );
+
+        write_raw_hunk(&ib.dir, 3, b"[{\"apath\": ", true);
+        assert_eq!(
+            index.damaged_trailing_hunks().unwrap(),
+            vec![
+                super::path_for_hunk(&ib.dir, 2),
+                super::path_for_hunk(&ib.dir, 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_good_index() {
+        let (_testdir, mut ib, report) = scratch_indexbuilder();
+        add_an_entry(&mut ib, "/apple");
+        ib.finish_hunk(&report).unwrap();
+        add_an_entry(&mut ib, "/banana");
+        ib.finish_hunk(&report).unwrap();
+
+        ReadIndex::new(&ib.dir).validate(&report).unwrap();
+        assert_eq!(problem_messages(&report), vec![]);
+    }
+
+    #[test]
+    fn validate_finds_missing_hunk_and_unexpected_files() {
+        let (_testdir, mut ib, report) = scratch_indexbuilder();
+        for name in &["/a", "/b", "/c"] {
+            add_an_entry(&mut ib, name);
+            ib.finish_hunk(&report).unwrap();
+        }
+        fs::remove_file(super::path_for_hunk(&ib.dir, 1)).unwrap();
+        fs::write(ib.dir.join("00000").join("junk"), b"").unwrap();
+        fs::write(ib.dir.join("junk"), b"").unwrap();
+
+        ReadIndex::new(&ib.dir).validate(&report).unwrap();
+        let problems = report.borrow_counts();
+        let problems = problems.problems();
+        assert_eq!(problems.len(), 3);
+        assert_eq!(problems[0].kind, ProblemKind::UnexpectedFile);
+        assert_eq!(problems[0].path, ib.dir.join("junk").display().to_string());
+        assert_eq!(problems[1].kind, ProblemKind::UnexpectedFile);
+        assert_eq!(problems[2].kind, ProblemKind::MissingFile);
+        assert_eq!(problems[2].message, "Missing index hunk");
+        assert_eq!(
+            problems[2].path,
+            super::path_for_hunk(&ib.dir, 1).display().to_string()
+        );
+    }
+
+    #[test]
+    fn validate_finds_bad_apaths() {
+        let (_testdir, ib, report) = scratch_indexbuilder();
+        let json = r#"[{"apath":"/b","kind":"File"},
+            {"apath":"/a","kind":"File"},
+            {"apath":"/a","kind":"File"},
+            {"apath":"/c/../d","kind":"File"},
+            {"apath":"/e","kind":"File"}]"#;
+        write_raw_hunk(&ib.dir, 0, json.as_bytes(), true);
+
+        ReadIndex::new(&ib.dir).validate(&report).unwrap();
+        assert_eq!(
+            problem_messages(&report),
+            vec![
+                (
+                    ProblemKind::BadIndex,
+                    "Apath \"/a\" is out of order after \"/b\" in index hunk".to_owned()
+                ),
+                (
+                    ProblemKind::BadIndex,
+                    "Duplicated apath \"/a\" in index hunk".to_owned()
+                ),
+                (
+                    ProblemKind::BadIndex,
+                    "Invalid apath \"/c/../d\" in index hunk".to_owned()
+                ),
+            ]
+        );
+    }
 }
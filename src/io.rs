@@ -125,23 +125,35 @@ pub fn require_empty_directory(path: &Path) -> Result<()> {
     }
 }
 
+/// Check that a directory is empty or doesn't exist, without creating it.
+pub fn require_empty_or_missing_directory(path: &Path) -> Result<()> {
+    match fs::read_dir(path) {
+        Ok(mut it) => {
+            if it.next().is_some() {
+                Err(Error::DestinationNotEmpty(path.into()))
+            } else {
+                Ok(())
+            }
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(Error::IoError(e)),
+    }
+}
+
 /// List a directory.
 ///
 /// Returns a list of filenames and a list of directory names respectively, forced to UTF-8, and
-/// sorted naively as UTF-8.
+/// sorted naively as UTF-8. Symlinks and other special files are listed with the files.
 pub fn list_dir(path: &Path) -> Result<(Vec<String>, Vec<String>)> {
     let mut file_names = Vec::<String>::new();
     let mut dir_names = Vec::<String>::new();
     for entry in fs::read_dir(path)? {
-        let entry = entry.unwrap();
-        let entry_filename = entry.file_name().into_string().unwrap();
-        let entry_type = entry.file_type()?;
-        if entry_type.is_file() {
-            file_names.push(entry_filename);
-        } else if entry_type.is_dir() {
+        let entry = entry?;
+        let entry_filename = entry.file_name().to_string_lossy().into_owned();
+        if entry.file_type()?.is_dir() {
             dir_names.push(entry_filename);
         } else {
-            panic!("don't recognize file type of {:?}", entry_filename);
+            file_names.push(entry_filename);
         }
     }
     file_names.sort_unstable();
@@ -149,8 +161,58 @@ pub fn list_dir(path: &Path) -> Result<(Vec<String>, Vec<String>)> {
     Ok((file_names, dir_names))
 }
 
+/// True if two readers produce the same bytes.
+pub(crate) fn same_content(a: &mut dyn Read, b: &mut dyn Read) -> Result<bool> {
+    const BUF_SIZE: usize = 64 << 10;
+    let mut abuf = vec![0u8; BUF_SIZE];
+    let mut bbuf = vec![0u8; BUF_SIZE];
+    loop {
+        let alen = read_fully(a, &mut abuf)?;
+        let blen = read_fully(b, &mut bbuf)?;
+        if abuf[..alen] != bbuf[..blen] {
+            return Ok(false);
+        } else if alen < BUF_SIZE {
+            return Ok(true);
+        }
+    }
+}
+
+/// Read until the buffer is full or the end of the input, and return the number of bytes read.
+fn read_fully(r: &mut dyn Read, buf: &mut [u8]) -> Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match r.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(len)
+}
+
 #[cfg(test)]
 mod tests {
     // TODO: Somehow test the error cases.
     // TODO: Specific test for write_compressed_bytes.
+
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn list_dir_lists_symlinks_and_undecodable_names_as_files() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::symlink;
+
+        let tf = crate::test_fixtures::TreeFixture::new();
+        tf.create_dir("dir");
+        tf.create_file("file");
+        symlink("dir", tf.path().join("link")).unwrap();
+        fs::write(tf.path().join(OsStr::from_bytes(b"bad\xff")), b"").unwrap();
+
+        let (files, dirs) = list_dir(tf.path()).unwrap();
+        assert_eq!(files, ["bad\u{fffd}", "file", "link"]);
+        assert_eq!(dirs, ["dir"]);
+    }
 }
@@ -4,27 +4,38 @@
 
 extern crate blake2_rfc;
 extern crate chrono;
+extern crate filetime;
 extern crate hex;
 extern crate isatty;
 extern crate rayon;
+extern crate reed_solomon_erasure;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
 
 extern crate snap;
+extern crate tar;
 extern crate tempfile;
 extern crate term;
 extern crate terminal_size;
 extern crate thousands;
 extern crate unicode_segmentation;
 extern crate walkdir;
+extern crate zip;
 
 #[cfg(test)]
 extern crate spectral;
 
 extern crate globset;
 
+#[cfg(all(unix, feature = "fuse"))]
+extern crate ctrlc;
+#[cfg(all(unix, feature = "fuse"))]
+extern crate fuser;
+#[cfg(all(unix, feature = "fuse"))]
+extern crate libc;
+
 // Conserve implementation modules.
 mod apath;
 mod archive;
@@ -33,43 +44,63 @@ mod band;
 mod bandid;
 mod blockdir;
 pub mod compress;
+mod copy_band;
 mod copy_tree;
 mod entry;
 pub mod errors;
 pub mod excludes;
+mod export;
+pub mod includes;
 pub mod index;
 mod io;
 mod jsonio;
 pub mod live_tree;
 mod merge;
 mod misc;
+#[cfg(all(unix, feature = "fuse"))]
+mod mount;
 pub mod output;
+pub mod parity;
+mod problem;
+mod repair;
 pub mod report;
 mod restore;
 mod stored_file;
 mod stored_tree;
+mod tar_tree;
 pub mod test_fixtures;
 mod tree;
 pub mod ui;
 
 pub use crate::apath::Apath;
-pub use crate::archive::Archive;
-pub use crate::backup::BackupWriter;
+pub use crate::archive::{Archive, ArchiveConfig, ValidateDepth};
+pub use crate::backup::{backup_stream, BackupWriter};
 pub use crate::band::Band;
 pub use crate::bandid::BandId;
 pub use crate::blockdir::BlockDir;
 pub use crate::compress::snappy::Snappy;
 pub use crate::compress::Compression;
+pub use crate::copy_band::{copy_band, BandNaming};
 pub use crate::copy_tree::copy_tree;
 pub use crate::entry::{Entry, Kind};
 pub use crate::errors::*;
+pub use crate::export::{TarWriteTree, ZipWriteTree};
+pub use crate::includes::Includes;
 pub use crate::index::{IndexBuilder, ReadIndex};
 pub use crate::io::{ensure_dir_exists, list_dir, AtomicFile};
 pub use crate::live_tree::LiveTree;
-pub use crate::merge::{iter_merged_entries, MergedEntryKind};
+pub use crate::merge::{iter_merged_entries, Change, MergedEntry, MergedEntryKind};
+pub use crate::misc::{parse_date, parse_size};
+#[cfg(all(unix, feature = "fuse"))]
+pub use crate::mount::mount;
+pub use crate::parity::{find_parity_damage, reconstruct_from_parity, write_parity};
+pub use crate::problem::{Problem, ProblemKind};
+pub use crate::repair::{plan_repair, repair, Repair};
 pub use crate::report::{HasReport, Report, Sizes};
-pub use crate::restore::RestoreTree;
+pub use crate::restore::{Overwrite, RestoreTree};
+pub use crate::stored_file::{ReadStoredFile, StoredFile};
 pub use crate::stored_tree::StoredTree;
+pub use crate::tar_tree::TarTree;
 pub use crate::tree::{ReadBlocks, ReadTree, TreeSize, WriteTree};
 pub use crate::ui::UI;
 
@@ -13,12 +13,23 @@ use super::*;
 
 use globset::GlobSet;
 
+use crate::excludes::MetadataExcludes;
+use crate::includes::{Includes, Selection};
+
 /// A real tree on the filesystem, for use as a backup source or restore destination.
 #[derive(Clone)]
 pub struct LiveTree {
     path: PathBuf,
     report: Report,
     excludes: GlobSet,
+    metadata_excludes: MetadataExcludes,
+    includes: Includes,
+    /// Subtrees of `path` that are sources, if not all of it.
+    sources: Includes,
+    /// Apaths of the source roots, which are `/` for a single source.
+    source_roots: Vec<Apath>,
+    /// If true, don't descend into directories on other filesystems.
+    one_file_system: bool,
 }
 
 impl LiveTree {
@@ -28,6 +39,58 @@ impl LiveTree {
             path: path.as_ref().to_path_buf(),
             report: report.clone(),
             excludes: excludes::excludes_nothing(),
+            metadata_excludes: MetadataExcludes::nothing(),
+            includes: Includes::everything(),
+            sources: Includes::everything(),
+            source_roots: vec![Apath::from("/")],
+            one_file_system: false,
+        })
+    }
+
+    /// Open a LiveTree covering several source files or directories.
+    ///
+    /// The tree is rooted at the deepest directory containing all the sources, and
+    /// contains only the sources, and the directories above them.
+    /// For example, sources `/etc` and `/home/mbp` give a tree rooted at `/`
+    /// containing `/etc`, `/home`, and `/home/mbp`, and everything within `/etc`
+    /// and `/home/mbp`.
+    pub fn open_sources<P: AsRef<Path>>(sources: &[P], report: &Report) -> Result<LiveTree> {
+        let mut source_paths = Vec::<PathBuf>::with_capacity(sources.len());
+        for s in sources {
+            source_paths.push(fs::canonicalize(s.as_ref())?);
+        }
+        let mut base = match source_paths.first() {
+            Some(p) => p.clone(),
+            None => return Err(Error::NoSources),
+        };
+        if source_paths.len() == 1 {
+            // A single source is the root of the tree.
+            return LiveTree::open(base, report);
+        }
+        while !source_paths.iter().all(|p| p.starts_with(&base)) {
+            base.pop();
+        }
+        let mut subtrees = Vec::<Apath>::with_capacity(source_paths.len());
+        for p in &source_paths {
+            let mut apath = String::new();
+            for c in p.strip_prefix(&base).unwrap().components() {
+                match c.as_os_str().to_str() {
+                    Some(name) => {
+                        apath.push('/');
+                        apath.push_str(name);
+                    }
+                    None => return Err(Error::InvalidSourcePath(p.clone())),
+                }
+            }
+            if apath.is_empty() {
+                apath.push('/');
+            }
+            subtrees.push(Apath::from(apath));
+        }
+        Ok(LiveTree {
+            sources: Includes::from_subtrees(subtrees.clone()),
+            source_roots: subtrees,
+            ..LiveTree::open(base, report)?
         })
     }
 
@@ -38,6 +101,41 @@ impl LiveTree {
         LiveTree { excludes, ..self }
     }
 
+    /// Return a new LiveTree which when listed will ignore entries by their size,
+    /// modification time, or kind.
+    ///
+    /// This replaces any previous metadata exclusions.
+    pub fn with_metadata_excludes(self, metadata_excludes: MetadataExcludes) -> LiveTree {
+        LiveTree {
+            metadata_excludes,
+            ..self
+        }
+    }
+
+    /// Return a new LiveTree which when listed will contain only entries selected by
+    /// `includes`, and the directories above them.
+    ///
+    /// Exclusions take precedence over inclusions. This replaces any previous inclusions.
+    pub fn with_includes(self, includes: Includes) -> LiveTree {
+        LiveTree { includes, ..self }
+    }
+
+    /// Return a new LiveTree which when listed will not descend into directories on
+    /// filesystems other than the one containing their source root.
+    ///
+    /// Mount points are still listed, as empty directories.
+    pub fn with_one_file_system(self, one_file_system: bool) -> LiveTree {
+        LiveTree {
+            one_file_system,
+            ..self
+        }
+    }
+
+    /// Return the directory at the root of this tree.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
     fn relative_path(&self, apath: &Apath) -> PathBuf {
         relative_path(&self.path, apath)
     }
@@ -65,7 +163,8 @@ impl tree::ReadTree for LiveTree {
         let root_metadata = match fs::symlink_metadata(&self.path) {
             Ok(metadata) => metadata,
             Err(e) => {
-                self.report.problem(&format!(
+                // Nothing can be read, so this is an error rather than a problem.
+                self.report.error(&format!(
                     "Couldn't get tree root metadata for {:?}: {}",
                     &self.path, e
                 ));
@@ -77,8 +176,19 @@ impl tree::ReadTree for LiveTree {
         entry_deque.push_back(entry_from_fs(Apath::from("/"), &root_metadata, None));
         // TODO: Consider the case where the root is not actually a directory?
         // Should that be supported?
-        let mut dir_deque = VecDeque::<Apath>::new();
-        dir_deque.push_back("/".into());
+        let mut dir_deque = VecDeque::<(Apath, bool)>::new();
+        let root_included = self.includes.select("/") == Selection::Included
+            && self.sources.select("/") == Selection::Included;
+        dir_deque.push_back(("/".into(), root_included));
+        let mut source_devices = Vec::new();
+        if self.one_file_system {
+            for root in &self.source_roots {
+                let metadata = fs::symlink_metadata(self.relative_path(root))?;
+                if let Some(device) = device_id(&metadata) {
+                    source_devices.push((root.clone(), device));
+                }
+            }
+        }
         Ok(Iter {
             root_path: self.path.clone(),
             entry_deque,
@@ -86,6 +196,10 @@ impl tree::ReadTree for LiveTree {
             report: report.clone(),
             check_order: apath::CheckOrder::new(),
             excludes: self.excludes.clone(),
+            metadata_excludes: self.metadata_excludes.clone(),
+            includes: self.includes.clone(),
+            sources: self.sources.clone(),
+            source_devices,
         })
     }
 
@@ -154,14 +268,26 @@ fn entry_from_fs(apath: Apath, metadata: &fs::Metadata, target: Option<String>)
     }
 }
 
+/// Return the id of the device containing a file, if this platform has them.
+#[cfg(unix)]
+fn device_id(metadata: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.dev())
+}
+
+#[cfg(not(unix))]
+fn device_id(_metadata: &fs::Metadata) -> Option<u64> {
+    None
+}
+
 /// Recursive iterator of the contents of a live tree.
 #[derive(Debug)]
 pub struct Iter {
     /// Root of the source tree.
     root_path: PathBuf,
 
-    /// Directories yet to be visited.
-    dir_deque: VecDeque<Apath>,
+    /// Directories yet to be visited, and whether they're entirely included.
+    dir_deque: VecDeque<(Apath, bool)>,
 
     /// All entries that have been seen but not yet returned by the iterator, in the order they
     /// should be returned.
@@ -175,30 +301,67 @@ pub struct Iter {
 
     /// glob pattern to skip in iterator
     excludes: GlobSet,
+
+    /// Skip entries by their metadata.
+    metadata_excludes: MetadataExcludes,
+
+    /// Patterns selecting entries to include.
+    includes: Includes,
+
+    /// Source subtrees to include.
+    sources: Includes,
+
+    /// Source roots and their devices: directories on any other device than the
+    /// source containing them aren't descended into.
+    source_devices: Vec<(Apath, u64)>,
 }
 
 impl Iter {
-    fn visit_next_directory(&mut self, parent_apath: &Apath) -> Result<()> {
+    /// True if a directory is on a different device from the source containing it.
+    ///
+    /// Directories above the sources are always descended into, to reach the sources.
+    fn is_mount_point(&self, apath: &Apath, metadata: &fs::Metadata) -> bool {
+        let source_device = self
+            .source_devices
+            .iter()
+            .filter(|(root, _)| includes::is_within(apath, root))
+            .max_by_key(|(root, _)| root.len())
+            .map(|(_, device)| *device);
+        source_device.is_some() && device_id(metadata) != source_device
+    }
+
+    fn visit_next_directory(&mut self, parent_apath: &Apath, parent_included: bool) -> Result<()> {
         self.report.increment("source.visited.directories", 1);
         let mut children = Vec::<Entry>::new();
-        let mut child_dirs = Vec::<Apath>::new();
+        let mut child_dirs = Vec::<(Apath, bool)>::new();
         let dir_path = relative_path(&self.root_path, parent_apath);
         let dir_iter = match fs::read_dir(&dir_path) {
             Ok(dir_iter) => dir_iter,
             Err(e) => {
-                self.report
-                    .problem(&format!("Error reading directory {:?}: {}", &dir_path, e));
-                return Err(e.into());
+                // Carry on with the rest of the tree.
+                self.report.problem(
+                    Problem::for_path(
+                        ProblemKind::SourceRead,
+                        &dir_path,
+                        "Error reading directory",
+                    )
+                    .with_error(e),
+                );
+                return Ok(());
             }
         };
         for dir_entry in dir_iter {
             let dir_entry = match dir_entry {
                 Ok(dir_entry) => dir_entry,
                 Err(e) => {
-                    self.report.problem(&format!(
-                        "Error reading next entry from directory {:?}: {}",
-                        &dir_path, e
-                    ));
+                    self.report.problem(
+                        Problem::for_path(
+                            ProblemKind::SourceRead,
+                            &dir_path,
+                            "Error reading next entry from directory",
+                        )
+                        .with_error(e),
+                    );
                     continue;
                 }
             };
@@ -212,9 +375,10 @@ impl Iter {
                 let child_name = match child_osstr.to_str() {
                     Some(c) => c,
                     None => {
-                        self.report.problem(&format!(
-                            "Can't decode filename {:?} in {:?}",
-                            child_osstr, dir_path,
+                        self.report.problem(Problem::for_path(
+                            ProblemKind::UndecodableName,
+                            dir_path.join(child_osstr),
+                            "Can't decode filename",
                         ));
                         continue;
                     }
@@ -224,15 +388,32 @@ impl Iter {
             let ft = match dir_entry.file_type() {
                 Ok(ft) => ft,
                 Err(e) => {
-                    self.report.problem(&format!(
-                        "Error getting type of {:?} during iteration: {}",
-                        child_apath, e
-                    ));
+                    self.report.problem(
+                        Problem::new(
+                            ProblemKind::SourceRead,
+                            &child_apath,
+                            "Error getting file type during iteration",
+                        )
+                        .with_error(e),
+                    );
                     continue;
                 }
             };
 
-            if self.excludes.is_match(&child_apath) {
+            let selection = if parent_included {
+                Selection::Included
+            } else {
+                std::cmp::min(
+                    self.includes.select(&child_apath),
+                    self.sources.select(&child_apath),
+                )
+            };
+            let not_selected = match selection {
+                Selection::Included => false,
+                Selection::Ancestor => !ft.is_dir(),
+                Selection::Excluded => true,
+            };
+            if not_selected || self.excludes.is_match(&child_apath) {
                 if ft.is_file() {
                     self.report.increment("skipped.excluded.files", 1);
                 } else if ft.is_dir() {
@@ -249,16 +430,24 @@ impl Iter {
                         ErrorKind::NotFound => {
                             // Fairly harmless, and maybe not even worth logging. Just a race
                             // between listing the directory and looking at the contents.
-                            self.report.problem(&format!(
-                                "File disappeared during iteration: {:?}: {}",
-                                child_apath, e
-                            ));
+                            self.report.problem(
+                                Problem::new(
+                                    ProblemKind::SourceVanished,
+                                    &child_apath,
+                                    "File disappeared during iteration",
+                                )
+                                .with_error(e),
+                            );
                         }
                         _ => {
-                            self.report.problem(&format!(
-                                "Failed to read source metadata from {:?}: {}",
-                                child_apath, e
-                            ));
+                            self.report.problem(
+                                Problem::new(
+                                    ProblemKind::SourceRead,
+                                    &child_apath,
+                                    "Failed to read source metadata",
+                                )
+                                .with_error(e),
+                            );
                             self.report.increment("source.error.metadata", 1);
                         }
                     };
@@ -270,19 +459,24 @@ impl Iter {
                 let t = match dir_path.join(dir_entry.file_name()).read_link() {
                     Ok(t) => t,
                     Err(e) => {
-                        self.report.problem(&format!(
-                            "Failed to read target of symlink {:?}: {}",
-                            child_apath, e
-                        ));
+                        self.report.problem(
+                            Problem::new(
+                                ProblemKind::SourceRead,
+                                &child_apath,
+                                "Failed to read target of symlink",
+                            )
+                            .with_error(e),
+                        );
                         continue;
                     }
                 };
                 match t.into_os_string().into_string() {
                     Ok(t) => Some(t),
                     Err(e) => {
-                        self.report.problem(&format!(
-                            "Failed to decode target of symlink {:?}: {:?}",
-                            child_apath, e
+                        self.report.problem(Problem::new(
+                            ProblemKind::UndecodableName,
+                            &child_apath,
+                            &format!("Failed to decode target of symlink {:?}", e),
                         ));
                         continue;
                     }
@@ -291,11 +485,20 @@ impl Iter {
                 None
             };
 
-            let child_apath = Apath::from(child_apath);
+            let child_entry = entry_from_fs(Apath::from(child_apath), &metadata, target);
+            if let Some(counter) = self.metadata_excludes.check(&child_entry) {
+                self.report.increment(counter, 1);
+                continue;
+            }
             if ft.is_dir() {
-                child_dirs.push(child_apath.clone());
+                if self.is_mount_point(&child_entry.apath, &metadata) {
+                    // A mount point: store the directory but not its contents.
+                    self.report.increment("skipped.mount_points", 1);
+                } else {
+                    child_dirs.push((child_entry.apath(), selection == Selection::Included));
+                }
             }
-            children.push(entry_from_fs(child_apath, &metadata, target));
+            children.push(child_entry);
         }
 
         // Names might come back from the fs in arbitrary order, but sort them by apath
@@ -306,8 +509,8 @@ impl Iter {
         if !child_dirs.is_empty() {
             child_dirs.sort_unstable();
             self.dir_deque.reserve(child_dirs.len());
-            for child_dir_apath in child_dirs.into_iter().rev() {
-                self.dir_deque.push_front(child_dir_apath);
+            for child_dir in child_dirs.into_iter().rev() {
+                self.dir_deque.push_front(child_dir);
             }
         }
 
@@ -342,8 +545,8 @@ impl Iterator for Iter {
             }
 
             // No entries already queued, visit a new directory to try to refill the queue.
-            if let Some(entry) = self.dir_deque.pop_front() {
-                if let Err(e) = self.visit_next_directory(&entry) {
+            if let Some((apath, included)) = self.dir_deque.pop_front() {
+                if let Err(e) = self.visit_next_directory(&apath, included) {
                     return Some(Err(e));
                 }
             } else {
@@ -451,6 +654,247 @@ mod tests {
         );
     }
 
+    #[test]
+    fn include_entries() {
+        let tf = TreeFixture::new();
+        tf.create_dir("etc");
+        tf.create_file("etc/hosts");
+        tf.create_dir("home");
+        tf.create_dir("home/mbp");
+        tf.create_dir("home/mbp/Documents");
+        tf.create_file("home/mbp/Documents/cv.txt");
+        tf.create_file("home/mbp/Documents/cv.txt~");
+        tf.create_dir("home/mbp/Music");
+        tf.create_file("home/mbp/Music/song.mp3");
+        tf.create_file("home/mbp/notes");
+        tf.create_dir("srv");
+        tf.create_file("srv/data");
+        let report = Report::new();
+
+        let lt = LiveTree::open(tf.path(), &report)
+            .unwrap()
+            .with_includes(Includes::from_strings(&["/home/*/Documents/**", "/etc"]).unwrap())
+            .with_excludes(excludes::from_strings(&["/**/*~"]).unwrap());
+        let names = lt
+            .iter_entries(&report)
+            .unwrap()
+            .map(|e| e.unwrap().apath.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            &[
+                "/",
+                "/etc",
+                "/home",
+                "/etc/hosts",
+                "/home/mbp",
+                "/home/mbp/Documents",
+                "/home/mbp/Documents/cv.txt",
+            ]
+        );
+        // "/srv" and "/home/mbp/Music" aren't visited.
+        assert_eq!(report.get_count("source.visited.directories"), 5);
+        assert_eq!(report.get_count("skipped.excluded.files"), 2);
+        assert_eq!(report.get_count("skipped.excluded.directories"), 2);
+    }
+
+    #[test]
+    fn open_several_sources() {
+        let tf = TreeFixture::new();
+        tf.create_dir("etc");
+        tf.create_file("etc/hosts");
+        tf.create_dir("home");
+        tf.create_dir("home/mbp");
+        tf.create_file("home/mbp/notes");
+        tf.create_dir("home/other");
+        tf.create_file("home/other/secrets");
+        tf.create_dir("srv");
+        tf.create_file("srv/data");
+        tf.create_file("toplevel");
+        let report = Report::new();
+
+        let lt = LiveTree::open_sources(
+            &[
+                tf.path().join("etc"),
+                tf.path().join("home").join("mbp"),
+                tf.path().join("srv"),
+            ],
+            &report,
+        )
+        .unwrap();
+        assert_eq!(lt.path(), tf.path().canonicalize().unwrap());
+        let names = lt
+            .iter_entries(&report)
+            .unwrap()
+            .map(|e| e.unwrap().apath.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            &[
+                "/",
+                "/etc",
+                "/home",
+                "/srv",
+                "/etc/hosts",
+                "/home/mbp",
+                "/home/mbp/notes",
+                "/srv/data",
+            ]
+        );
+    }
+
+    #[test]
+    fn metadata_excludes() {
+        let tf = TreeFixture::new();
+        tf.create_file_with_contents("big", &[0; 2000]);
+        tf.create_file_with_contents("small", &[0; 20]);
+        tf.create_dir("sub");
+        tf.create_file_with_contents("sub/big", &[0; 2000]);
+        let report = Report::new();
+        let lt = LiveTree::open(tf.path(), &report)
+            .unwrap()
+            .with_metadata_excludes(excludes::MetadataExcludes {
+                larger_than: Some(1000),
+                ..excludes::MetadataExcludes::nothing()
+            });
+        let names: Vec<String> = lt
+            .iter_entries(&report)
+            .unwrap()
+            .map(|e| e.unwrap().apath.to_string())
+            .collect();
+        assert_eq!(names, ["/", "/small", "/sub"]);
+        assert_eq!(report.get_count("skipped.large_files"), 2);
+
+        // Excluding directories skips everything inside them.
+        let report = Report::new();
+        let lt = LiveTree::open(tf.path(), &report)
+            .unwrap()
+            .with_metadata_excludes(excludes::MetadataExcludes {
+                kinds: vec![Kind::Dir],
+                ..excludes::MetadataExcludes::nothing()
+            });
+        let names: Vec<String> = lt
+            .iter_entries(&report)
+            .unwrap()
+            .map(|e| e.unwrap().apath.to_string())
+            .collect();
+        assert_eq!(names, ["/", "/big", "/small"]);
+        assert_eq!(report.get_count("skipped.kind.directories"), 1);
+    }
+
+    #[test]
+    fn one_file_system_same_device() {
+        let tf = TreeFixture::new();
+        tf.create_dir("a");
+        tf.create_file("a/b");
+        let report = Report::new();
+        let lt = LiveTree::open(tf.path(), &report)
+            .unwrap()
+            .with_one_file_system(true);
+        let names: Vec<String> = lt
+            .iter_entries(&report)
+            .unwrap()
+            .map(|e| e.unwrap().apath.to_string())
+            .collect();
+        assert_eq!(names, ["/", "/a", "/a/b"]);
+        assert_eq!(report.get_count("skipped.mount_points"), 0);
+    }
+
+    /// Files whose names can't be decoded are skipped and reported as problems.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn undecodable_name_is_a_problem() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let tf = TreeFixture::new();
+        tf.create_file("a");
+        std::fs::write(tf.path().join(OsStr::from_bytes(b"bad\xff")), b"").unwrap();
+        let report = Report::new();
+        let names: Vec<String> = tf
+            .live_tree()
+            .iter_entries(&report)
+            .unwrap()
+            .map(|e| e.unwrap().apath.to_string())
+            .collect();
+        assert_eq!(names, ["/", "/a"]);
+        let counts = report.borrow_counts();
+        assert_eq!(counts.count_problems(), 1);
+        assert_eq!(counts.problems()[0].kind, ProblemKind::UndecodableName);
+        assert_eq!(counts.get_count("problem.undecodable_name"), 1);
+    }
+
+    /// /proc is normally a separate filesystem, so it should be listed but not entered.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn one_file_system_stops_at_mount_point() {
+        use std::os::unix::fs::MetadataExt;
+        let root_dev = std::fs::metadata("/").unwrap().dev();
+        match std::fs::metadata("/proc") {
+            Ok(m) if m.dev() != root_dev => (),
+            _ => return,
+        }
+        let report = Report::new();
+        let lt = LiveTree::open("/", &report)
+            .unwrap()
+            .with_includes(Includes::from_strings(&["/proc"]).unwrap())
+            .with_one_file_system(true);
+        let names: Vec<String> = lt
+            .iter_entries(&report)
+            .unwrap()
+            .map(|e| e.unwrap().apath.to_string())
+            .collect();
+        assert_eq!(names, ["/", "/proc"]);
+        assert_eq!(report.get_count("skipped.mount_points"), 1);
+    }
+
+    /// Each source is compared to its own device, so sources on other filesystems than
+    /// their common parent are still stored.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn one_file_system_several_devices() {
+        use std::os::unix::fs::MetadataExt;
+        let tf = TreeFixture::new();
+        tf.create_dir("a");
+        tf.create_file("a/b");
+        // /dev/shm is normally a separate tmpfs.
+        let other = match tempfile::TempDir::new_in("/dev/shm") {
+            Ok(other) => other,
+            Err(_) => return,
+        };
+        if other.path().metadata().unwrap().dev() == tf.path().metadata().unwrap().dev() {
+            return;
+        }
+        std::fs::write(other.path().join("c"), b"").unwrap();
+        let report = Report::new();
+        let lt = LiveTree::open_sources(&[tf.path().join("a"), other.path().to_owned()], &report)
+            .unwrap()
+            .with_one_file_system(true);
+        let names: Vec<String> = lt
+            .iter_entries(&report)
+            .unwrap()
+            .map(|e| e.unwrap().apath.to_string())
+            .collect();
+        assert!(names.iter().any(|n| n.ends_with("/a/b")), "{:?}", names);
+        assert!(names.iter().any(|n| n.ends_with("/c")), "{:?}", names);
+        assert_eq!(report.get_count("skipped.mount_points"), 0);
+    }
+
+    #[test]
+    fn open_one_source() {
+        let tf = TreeFixture::new();
+        tf.create_dir("etc");
+        tf.create_file("etc/hosts");
+        let report = Report::new();
+        let lt = LiveTree::open_sources(&[tf.path().join("etc")], &report).unwrap();
+        let names = lt
+            .iter_entries(&report)
+            .unwrap()
+            .map(|e| e.unwrap().apath.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(names, &["/", "/hosts"]);
+    }
+
     #[cfg(unix)]
     #[test]
     fn symlinks() {
@@ -4,6 +4,8 @@
 
 use std::cmp::Ordering;
 
+use crate::io::same_content;
+
 use crate::*;
 
 #[derive(Debug, PartialEq, Eq)]
@@ -19,15 +21,91 @@ use self::MergedEntryKind::*;
 #[derive(Debug, PartialEq, Eq)]
 pub struct MergedEntry {
     // TODO: Add accessors rather than making these public?
-    // TODO: Include the original entries from either side?
     pub apath: Apath,
     pub kind: MergedEntryKind,
+    /// The entry from the left tree, if present there.
+    pub left: Option<Entry>,
+    /// The entry from the right tree, if present there.
+    pub right: Option<Entry>,
+}
+
+/// How an entry present in both trees differs between them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Change {
+    /// Same kind, content and mtime.
+    Unchanged,
+    /// The content is the same but the mtime differs.
+    MetadataChanged,
+    /// The file contents or symlink target differ.
+    ContentChanged,
+    /// The entry changed from one kind to another, such as from a file to a directory.
+    KindChanged,
+}
+
+impl MergedEntry {
+    /// Classify how this entry differs between tree `a` (on the left) and tree `b`, or
+    /// return None if it's only present on one side.
+    ///
+    /// Files stored in the same archive are compared by their block addresses, without
+    /// reading the blocks. Other files of the same size are assumed to have unchanged
+    /// content if their mtimes are the same, and to have changed content otherwise.
+    /// If `compare_content` is true, files of the same size whose content isn't known to
+    /// be the same are instead read from both trees and compared.
+    pub fn change<AT, BT>(&self, a: &AT, b: &BT, compare_content: bool) -> Result<Option<Change>>
+    where
+        AT: ReadTree,
+        BT: ReadTree,
+    {
+        let (ae, be) = match (&self.left, &self.right) {
+            (Some(ae), Some(be)) => (ae, be),
+            _ => return Ok(None),
+        };
+        let same_mtime = ae.unix_mtime() == be.unix_mtime();
+        let change = if ae.kind() != be.kind() {
+            Change::KindChanged
+        } else if ae.symlink_target() != be.symlink_target() {
+            Change::ContentChanged
+        } else if ae.kind() == Kind::File {
+            // Stored files refer to their content by block address, so two files in the same
+            // archive with the same addresses have the same content. Files with different
+            // addresses could still have the same content, if it was stored twice.
+            let both_stored = !ae.addrs.is_empty() && !be.addrs.is_empty();
+            let size = ae.size().unwrap_or(0);
+            let content_same = if size != be.size().unwrap_or(0) {
+                Some(false)
+            } else if size == 0 || (both_stored && ae.addrs == be.addrs) {
+                Some(true)
+            } else if compare_content {
+                Some(same_content(
+                    &mut a.file_contents(ae)?,
+                    &mut b.file_contents(be)?,
+                )?)
+            } else if both_stored {
+                Some(false)
+            } else {
+                None
+            };
+            match content_same {
+                Some(false) => Change::ContentChanged,
+                Some(true) if same_mtime => Change::Unchanged,
+                Some(true) => Change::MetadataChanged,
+                // Without reading the content, assume it changed if the mtime did.
+                None if same_mtime => Change::Unchanged,
+                None => Change::ContentChanged,
+            }
+        } else if same_mtime {
+            Change::Unchanged
+        } else {
+            Change::MetadataChanged
+        };
+        Ok(Some(change))
+    }
 }
 
 /// Zip together entries from two trees, into an iterator of MergedEntryKind.
 ///
-/// Note that at present this only says whether files are absent from either
-/// side, not whether there is a content difference.
+/// This only says whether entries are absent from either side; use
+/// `MergedEntry::change` to find out how entries present on both sides differ.
 pub fn iter_merged_entries<AT, BT>(a: &AT, b: &BT, report: &Report) -> Result<MergeTrees<AT, BT>>
 where
     AT: ReadTree,
@@ -77,55 +155,31 @@ where
                 Some(Ok(i)) => Some(i),
             }
         }
-        if self.na.is_none() {
-            if self.nb.is_none() {
-                None
-            } else {
-                let tb = self.nb.take().unwrap();
-                Some(Ok(MergedEntry {
-                    apath: tb.apath(),
-                    kind: RightOnly,
-                }))
-            }
-        } else if self.nb.is_none() {
-            Some(Ok(MergedEntry {
-                apath: self.na.take().unwrap().apath(),
-                kind: LeftOnly,
-            }))
-        } else {
-            let pa = self.na.as_ref().unwrap().apath();
-            let pb = self.nb.as_ref().unwrap().apath();
-            match pa.cmp(&pb) {
-                Ordering::Equal => {
-                    self.na.take();
-                    self.nb.take();
-                    Some(Ok(MergedEntry {
-                        apath: pa,
-                        kind: Both,
-                    }))
-                }
-                Ordering::Less => {
-                    self.na.take().unwrap();
-                    Some(Ok(MergedEntry {
-                        apath: pa,
-                        kind: LeftOnly,
-                    }))
-                }
-                Ordering::Greater => {
-                    self.nb.take().unwrap();
-                    Some(Ok(MergedEntry {
-                        apath: pb,
-                        kind: RightOnly,
-                    }))
-                }
-            }
-        }
+        let (kind, left, right) = match (&self.na, &self.nb) {
+            (None, None) => return None,
+            (Some(_), None) => (LeftOnly, self.na.take(), None),
+            (None, Some(_)) => (RightOnly, None, self.nb.take()),
+            (Some(ea), Some(eb)) => match ea.apath.cmp(&eb.apath) {
+                Ordering::Equal => (Both, self.na.take(), self.nb.take()),
+                Ordering::Less => (LeftOnly, self.na.take(), None),
+                Ordering::Greater => (RightOnly, None, self.nb.take()),
+            },
+        };
+        let apath = left.as_ref().or(right.as_ref()).unwrap().apath();
+        Some(Ok(MergedEntry {
+            apath,
+            kind,
+            left,
+            right,
+        }))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::MergedEntry;
+    use filetime::{set_file_mtime, FileTime};
+
+    use super::Change::*;
     use super::MergedEntryKind::*;
     use crate::test_fixtures::*;
     use crate::*;
@@ -140,14 +194,119 @@ mod tests {
             .unwrap()
             .collect::<Vec<_>>();
         assert_eq!(di.len(), 1);
-        assert_eq!(
-            *di[0].as_ref().unwrap(),
-            MergedEntry {
-                apath: "/".into(),
-                kind: Both,
+        let me = di[0].as_ref().unwrap();
+        assert_eq!(me.apath, Apath::from("/"));
+        assert_eq!(me.kind, Both);
+        assert_eq!(me.left.as_ref().unwrap().kind(), Kind::Dir);
+        assert_eq!(me.right.as_ref().unwrap().kind(), Kind::Dir);
+    }
+
+    #[test]
+    fn classify_changes() {
+        let ta = TreeFixture::new();
+        let tb = TreeFixture::new();
+        for (name, a_content, b_content) in &[
+            ("same", "hello", "hello"),
+            ("longer", "hello", "hello world"),
+            ("edited", "hello", "jello"),
+            ("touched", "hello", "hello"),
+        ] {
+            ta.create_file_with_contents(name, a_content.as_bytes());
+            tb.create_file_with_contents(name, b_content.as_bytes());
+        }
+        ta.create_file("kind");
+        tb.create_dir("kind");
+        ta.create_file("left");
+        tb.create_file("right");
+        let mtime = FileTime::from_unix_time(1_500_000_000, 0);
+        for t in &[&ta, &tb] {
+            for name in &["same", "longer", "edited", "touched"] {
+                set_file_mtime(t.path().join(name), mtime).unwrap();
             }
+            set_file_mtime(t.path(), mtime).unwrap();
+        }
+        set_file_mtime(
+            tb.path().join("touched"),
+            FileTime::from_unix_time(1_600_000_000, 0),
+        )
+        .unwrap();
+
+        let report = Report::new();
+        let (la, lb) = (ta.live_tree(), tb.live_tree());
+        let changes = |compare_content: bool| -> Vec<(String, MergedEntryKind, Option<Change>)> {
+            iter_merged_entries(&la, &lb, &report)
+                .unwrap()
+                .map(|me| {
+                    let me = me.unwrap();
+                    let change = me.change(&la, &lb, compare_content).unwrap();
+                    (me.apath.to_string(), me.kind, change)
+                })
+                .collect()
+        };
+        assert_eq!(
+            changes(false),
+            vec![
+                ("/".to_owned(), Both, Some(Unchanged)),
+                ("/edited".to_owned(), Both, Some(Unchanged)),
+                ("/kind".to_owned(), Both, Some(KindChanged)),
+                ("/left".to_owned(), LeftOnly, None),
+                ("/longer".to_owned(), Both, Some(ContentChanged)),
+                ("/right".to_owned(), RightOnly, None),
+                ("/same".to_owned(), Both, Some(Unchanged)),
+                ("/touched".to_owned(), Both, Some(ContentChanged)),
+            ]
+        );
+        // Reading the content finds the edit that kept the same size and mtime, and
+        // notices that the touched file didn't really change.
+        let with_content = changes(true);
+        assert_eq!(
+            with_content[1],
+            ("/edited".to_owned(), Both, Some(ContentChanged))
+        );
+        assert_eq!(
+            with_content[7],
+            ("/touched".to_owned(), Both, Some(MetadataChanged))
         );
     }
 
-    // TODO: More tests of various diff situations.
+    #[test]
+    fn compare_stored_trees_by_address() {
+        let af = ScratchArchive::new();
+        let srcdir = TreeFixture::new();
+        srcdir.create_file_with_contents("edited", b"hello");
+        srcdir.create_file_with_contents("same", b"same");
+        srcdir.create_file_with_contents("touched", b"touch");
+        let report = Report::new();
+        let lt = LiveTree::open(srcdir.path(), &report).unwrap();
+        copy_tree(&lt, &mut BackupWriter::begin(&af).unwrap()).unwrap();
+
+        srcdir.create_file_with_contents("edited", b"jello");
+        set_file_mtime(
+            srcdir.path().join("touched"),
+            FileTime::from_unix_time(1_500_000_000, 0),
+        )
+        .unwrap();
+        copy_tree(&lt, &mut BackupWriter::begin(&af).unwrap()).unwrap();
+
+        let st0 = StoredTree::open_version(&af, &BandId::new(&[0])).unwrap();
+        let st1 = StoredTree::open_version(&af, &BandId::new(&[1])).unwrap();
+        let changes: Vec<(String, Option<Change>)> = iter_merged_entries(&st0, &st1, &report)
+            .unwrap()
+            .map(|me| {
+                let me = me.unwrap();
+                (me.apath.to_string(), me.change(&st0, &st1, false).unwrap())
+            })
+            .skip(1)
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                ("/edited".to_owned(), Some(ContentChanged)),
+                ("/same".to_owned(), Some(Unchanged)),
+                ("/touched".to_owned(), Some(MetadataChanged)),
+            ]
+        );
+        // Nothing needed to be read to decide that.
+        assert_eq!(af.report().borrow_counts().get_count("block.read"), 0);
+    }
 }
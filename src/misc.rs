@@ -3,9 +3,111 @@
 
 //! Generally useful functions.
 
+use chrono::{DateTime, Duration, Local, NaiveDate, NaiveDateTime, TimeZone, UTC};
+
+use crate::*;
+
 /// Remove and return an item from a vec, if it's present.
 pub(crate) fn remove_item<T, U: PartialEq<T>>(v: &mut Vec<T>, item: &U) {
     if let Some(pos) = v.iter().position(|x| *item == *x) {
         v.remove(pos);
     }
 }
+
+/// Parse a size in bytes, optionally followed by a binary unit suffix: `K`, `M`, `G` or `T`.
+///
+/// For example `"1500"`, `"64K"`, or `"4G"`.
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let (digits, shift) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 10),
+        Some('M') => (&s[..s.len() - 1], 20),
+        Some('G') => (&s[..s.len() - 1], 30),
+        Some('T') => (&s[..s.len() - 1], 40),
+        _ => (s, 0),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| Error::InvalidSize(s.to_owned()))
+}
+
+/// Parse a date and time.
+///
+/// This accepts RFC 3339 times like `2019-03-01T17:00:00+11:00`; local times like
+/// `2019-03-01 17:00` or `2019-03-01 17:00:00`; dates like `2019-03-01`, meaning the
+/// start of that day in the local timezone; `now`; and `yesterday`, meaning 24 hours ago.
+pub fn parse_date(s: &str) -> Result<DateTime<UTC>> {
+    let s = s.trim();
+    match s {
+        "now" => return Ok(UTC::now()),
+        "yesterday" => return Ok(UTC::now() - Duration::days(1)),
+        _ => (),
+    }
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Ok(t.with_timezone(&UTC));
+    }
+    let local_time = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S")
+        .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M"))
+        .or_else(|_| NaiveDate::parse_from_str(s, "%Y-%m-%d").map(|d| d.and_hms(0, 0, 0)));
+    local_time
+        .ok()
+        .and_then(|t| Local.from_local_datetime(&t).earliest())
+        .map(|t| t.with_timezone(&UTC))
+        .ok_or_else(|| Error::InvalidDate(s.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("0").unwrap(), 0);
+        assert_eq!(parse_size("1500").unwrap(), 1500);
+        assert_eq!(parse_size("64K").unwrap(), 64 << 10);
+        assert_eq!(parse_size("3m").unwrap(), 3 << 20);
+        assert_eq!(parse_size("4G").unwrap(), 4 << 30);
+        assert_eq!(parse_size("2T").unwrap(), 2 << 40);
+        assert!(parse_size("").is_err());
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("-1").is_err());
+        assert!(parse_size("1.5G").is_err());
+        assert!(parse_size("99999999999T").is_err());
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(
+            parse_date("2019-03-01T17:00:00+11:00").unwrap(),
+            UTC.ymd(2019, 3, 1).and_hms(6, 0, 0)
+        );
+        let local_day = parse_date("2019-03-01").unwrap().with_timezone(&Local);
+        assert_eq!(
+            local_day.naive_local(),
+            NaiveDate::from_ymd(2019, 3, 1).and_hms(0, 0, 0)
+        );
+        let local_time = parse_date("2019-03-01 14:00")
+            .unwrap()
+            .with_timezone(&Local);
+        assert_eq!(
+            local_time.naive_local(),
+            NaiveDate::from_ymd(2019, 3, 1).and_hms(14, 0, 0)
+        );
+        let local_time = parse_date("2019-03-01 14:00:05")
+            .unwrap()
+            .with_timezone(&Local);
+        assert_eq!(
+            local_time.naive_local(),
+            NaiveDate::from_ymd(2019, 3, 1).and_hms(14, 0, 5)
+        );
+        let yesterday = parse_date("yesterday").unwrap();
+        let now = parse_date("now").unwrap();
+        assert!(yesterday < now);
+        assert!(now - yesterday >= Duration::hours(23));
+        assert!(parse_date("last week").is_err());
+        assert!(parse_date("2019-02-30").is_err());
+        assert!(parse_date("2019-03-01 25:00").is_err());
+    }
+}
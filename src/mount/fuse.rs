@@ -0,0 +1,205 @@
+// Conserve backup system.
+// Copyright 2019 Martin Pool.
+
+//! Serve an `ArchiveFs` to the kernel through FUSE.
+//!
+//! Requests are handled one at a time. The filesystem is mounted read-only, so the kernel
+//! refuses any changes before they get here.
+
+use std::ffi::OsStr;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+use fuser::consts::FOPEN_KEEP_CACHE;
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
+    ReplyOpen, Request, Session,
+};
+use libc::{c_int, EINVAL, EIO, EISDIR, ENOENT, ENOTDIR};
+
+use super::{ArchiveFs, Attr};
+use crate::*;
+
+/// How long the kernel may cache names and attributes. Versions never change, so this
+/// only limits how long stale entries are kept.
+const TTL: Duration = Duration::from_secs(60);
+
+const BLOCK_SIZE: u32 = 4096;
+
+/// Mount `archive` on `mountpoint`, and serve requests until it's unmounted or the process
+/// is interrupted.
+pub fn mount(archive: &Archive, mountpoint: &Path) -> Result<()> {
+    let mount_error = |error| Error::MountFailed {
+        mountpoint: mountpoint.to_owned(),
+        error,
+    };
+    // Everything is shown as owned by the owner of the mountpoint.
+    let metadata = std::fs::metadata(mountpoint).map_err(mount_error)?;
+    let fs = FuseFs {
+        fs: ArchiveFs::new(archive)?,
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+    };
+    let options = [
+        MountOption::RO,
+        MountOption::NoDev,
+        MountOption::NoSuid,
+        MountOption::DefaultPermissions,
+        MountOption::FSName("conserve".to_owned()),
+        MountOption::Subtype("conserve".to_owned()),
+    ];
+    let mut session = Session::new(fs, mountpoint, &options).map_err(mount_error)?;
+    // Unmount when interrupted, rather than leaving the mountpoint disconnected. Only one
+    // handler can be set in a process, so if there's already one, the caller must unmount
+    // it.
+    let mut unmounter = session.unmount_callable();
+    ctrlc::set_handler(move || {
+        unmounter.unmount().ok();
+    })
+    .ok();
+    archive.report().note(&format!(
+        "Mounted on {}; unmount it or interrupt to stop",
+        mountpoint.display()
+    ));
+    session.run()?;
+    Ok(())
+}
+
+/// Map an error from the filesystem to an errno.
+fn errno(error: &Error) -> c_int {
+    match error {
+        Error::NotADirectory(_) => ENOTDIR,
+        Error::NotAFile(_) => EISDIR,
+        _ => EIO,
+    }
+}
+
+fn file_type(kind: Kind) -> FileType {
+    match kind {
+        Kind::Dir => FileType::Directory,
+        Kind::Symlink => FileType::Symlink,
+        _ => FileType::RegularFile,
+    }
+}
+
+/// Adapt an `ArchiveFs` to `fuser`.
+struct FuseFs {
+    fs: ArchiveFs,
+    uid: u32,
+    gid: u32,
+}
+
+impl FuseFs {
+    fn file_attr(&self, attr: &Attr) -> FileAttr {
+        let mtime = UNIX_EPOCH + Duration::from_secs(attr.mtime);
+        let (perm, nlink) = match attr.kind {
+            Kind::Dir => (0o555, 2),
+            Kind::Symlink => (0o777, 1),
+            _ => (0o444, 1),
+        };
+        FileAttr {
+            ino: attr.ino,
+            size: attr.size,
+            blocks: attr.size.div_ceil(512),
+            atime: mtime,
+            mtime,
+            ctime: mtime,
+            crtime: mtime,
+            kind: file_type(attr.kind),
+            perm,
+            nlink,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: BLOCK_SIZE,
+            flags: 0,
+        }
+    }
+}
+
+impl Filesystem for FuseFs {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        // Every stored name is UTF-8.
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => return reply.error(ENOENT),
+        };
+        match self.fs.lookup(parent, name) {
+            Ok(Some(attr)) => reply.entry(&TTL, &self.file_attr(&attr), 0),
+            Ok(None) => reply.error(ENOENT),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        match self.fs.getattr(ino) {
+            Some(attr) => reply.attr(&TTL, &self.file_attr(&attr)),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        match self.fs.readlink(ino) {
+            Some(target) => reply.data(target.as_bytes()),
+            None => reply.error(EINVAL),
+        }
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        match self.fs.getattr(ino) {
+            // Contents never change, so they can stay cached after the file is closed.
+            Some(Attr {
+                kind: Kind::File, ..
+            }) => reply.opened(0, FOPEN_KEEP_CACHE),
+            Some(_) => reply.error(EISDIR),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        match self.fs.read(ino, offset as u64, size) {
+            Ok(data) => reply.data(&data),
+            Err(e) => {
+                let errno = errno(&e);
+                reply.error(errno);
+                if errno == EIO {
+                    self.fs.report_damaged(ino, e);
+                }
+            }
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        match self.fs.readdir(ino) {
+            Ok(entries) => {
+                // The offset of each entry is the offset of the entry after it, from which
+                // the kernel will continue listing.
+                for (i, entry) in entries.iter().enumerate().skip(offset as usize) {
+                    if reply.add(entry.ino, i as i64 + 1, file_type(entry.kind), &entry.name) {
+                        break;
+                    }
+                }
+                reply.ok();
+            }
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+}
@@ -0,0 +1,510 @@
+// Conserve backup system.
+// Copyright 2019 Martin Pool.
+
+//! Mount an archive as a read-only filesystem, so that files from any version can be
+//! browsed and copied with normal tools.
+//!
+//! The top directory holds a directory for each complete version, named by its band id,
+//! and `latest`, a symlink to the last complete version. The versions are listed when
+//! the archive is mounted.
+//!
+//! A version's index is read the first time its directory is listed or looked into, and
+//! its entries are then kept in memory until the archive is unmounted. Files are read
+//! through `StoredFile`. The most recently read files are kept open, each holding its last
+//! decompressed block, so that reading a file in pages decompresses each block only once.
+//!
+//! This is only built with the `fuse` Cargo feature, on Unix.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io::prelude::*;
+use std::io::SeekFrom;
+
+use chrono::UTC;
+
+use crate::blockdir::Address;
+use crate::*;
+
+mod fuse;
+
+pub use self::fuse::mount;
+
+/// Inode number of the top directory of the mount.
+pub(crate) const ROOT_INO: u64 = 1;
+
+/// Name of the symlink to the last complete version.
+const LATEST_NAME: &str = "latest";
+
+/// Number of files kept open for reading, each holding its last decompressed block.
+const OPEN_FILES: usize = 16;
+
+/// What the filesystem knows about one file, directory, or symlink.
+#[derive(Debug)]
+struct Inode {
+    kind: Kind,
+
+    /// Path within its version, or `/` for the top directory of the mount and of each
+    /// version.
+    apath: Apath,
+
+    /// Inode of the directory containing this one.
+    parent: u64,
+
+    /// Inode of the top directory of the version holding this, or the root for the top
+    /// of the mount.
+    version: u64,
+
+    mtime: u64,
+    size: u64,
+    target: Option<String>,
+    addrs: Vec<Address>,
+
+    /// Names and inodes of a directory's children, or None for the top directory of a
+    /// version whose index hasn't yet been read.
+    children: Option<Vec<(String, u64)>>,
+
+    /// For the top directory of a version, its band id.
+    band_id: Option<BandId>,
+}
+
+/// The attributes of an inode, as reported to the kernel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Attr {
+    pub ino: u64,
+    pub kind: Kind,
+    pub size: u64,
+    pub mtime: u64,
+}
+
+/// A directory entry, as listed by `ArchiveFs::readdir`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct DirEntry {
+    pub name: String,
+    pub ino: u64,
+    pub kind: Kind,
+}
+
+/// The contents of an archive, arranged as a filesystem of inodes.
+///
+/// Inodes are numbered from `ROOT_INO`, and stay valid while the archive is mounted.
+#[derive(Debug)]
+pub(crate) struct ArchiveFs {
+    archive: Archive,
+    report: Report,
+
+    /// Inodes by number, starting from `ROOT_INO`.
+    inodes: Vec<Inode>,
+
+    /// Files open for reading, by inode, most recently used last.
+    open_files: VecDeque<(u64, ReadStoredFile)>,
+}
+
+impl ArchiveFs {
+    /// List the complete versions in an archive, without yet reading their indexes.
+    ///
+    /// Bands that can't be read are reported as problems and left out.
+    pub fn new(archive: &Archive) -> Result<ArchiveFs> {
+        let report = archive.report().clone();
+        let now = UTC::now().timestamp() as u64;
+        let mut inodes = vec![Inode {
+            kind: Kind::Dir,
+            apath: "/".into(),
+            parent: ROOT_INO,
+            version: ROOT_INO,
+            mtime: now,
+            size: 0,
+            target: None,
+            addrs: Vec::new(),
+            children: Some(Vec::new()),
+            band_id: None,
+        }];
+        let mut children = Vec::new();
+        let mut latest = None;
+        for band_id in archive.list_bands()? {
+            let info = match Band::open(archive, &band_id).and_then(|b| b.get_info(&report)) {
+                Ok(info) => info,
+                Err(e) => {
+                    report.problem(
+                        Problem::new(ProblemKind::BadBand, &band_id, "Failed to read band")
+                            .with_error(e),
+                    );
+                    continue;
+                }
+            };
+            let end_time = match info.end_time {
+                Some(end_time) => end_time,
+                None => continue,
+            };
+            let ino = inodes.len() as u64 + 1;
+            inodes.push(Inode {
+                kind: Kind::Dir,
+                apath: "/".into(),
+                parent: ROOT_INO,
+                version: ino,
+                mtime: end_time.timestamp() as u64,
+                size: 0,
+                target: None,
+                addrs: Vec::new(),
+                children: None,
+                band_id: Some(band_id.clone()),
+            });
+            children.push((band_id.to_string(), ino));
+            latest = Some(band_id);
+        }
+        if let Some(latest) = latest {
+            let ino = inodes.len() as u64 + 1;
+            let target = latest.to_string();
+            inodes.push(Inode {
+                kind: Kind::Symlink,
+                apath: "/".into(),
+                parent: ROOT_INO,
+                version: ROOT_INO,
+                mtime: now,
+                size: target.len() as u64,
+                target: Some(target),
+                addrs: Vec::new(),
+                children: None,
+                band_id: None,
+            });
+            children.push((LATEST_NAME.to_owned(), ino));
+        }
+        inodes[0].children = Some(children);
+        Ok(ArchiveFs {
+            archive: archive.clone(),
+            report,
+            inodes,
+            open_files: VecDeque::with_capacity(OPEN_FILES),
+        })
+    }
+
+    fn inode(&self, ino: u64) -> Option<&Inode> {
+        if ino >= ROOT_INO {
+            self.inodes.get((ino - ROOT_INO) as usize)
+        } else {
+            None
+        }
+    }
+
+    fn attr(&self, ino: u64, inode: &Inode) -> Attr {
+        Attr {
+            ino,
+            kind: inode.kind,
+            size: inode.size,
+            mtime: inode.mtime,
+        }
+    }
+
+    /// Return the attributes of an inode, or None if there's no such inode.
+    pub fn getattr(&self, ino: u64) -> Option<Attr> {
+        self.inode(ino).map(|inode| self.attr(ino, inode))
+    }
+
+    /// Read the index of a version, if it's not already read, to list its entries.
+    fn load_version(&mut self, ino: u64) -> Result<()> {
+        let band_id = match self.inode(ino) {
+            Some(Inode {
+                children: None,
+                band_id: Some(band_id),
+                ..
+            }) => band_id.clone(),
+            _ => return Ok(()),
+        };
+        self.report.note(&format!("Read index of {}...", band_id));
+        let st = StoredTree::open_version(&self.archive, &band_id)?;
+        // New inodes are collected separately, so that nothing changes if the index
+        // can't be read.
+        let first_new = self.inodes.len() as u64 + ROOT_INO;
+        let mut new_inodes: Vec<Inode> = Vec::new();
+        let mut top_children = Vec::new();
+        let mut dirs: BTreeMap<Apath, u64> = BTreeMap::new();
+        dirs.insert("/".into(), ino);
+        for entry in st.iter_entries(&self.report)? {
+            let entry = entry?;
+            if &entry.apath[..] == "/" {
+                continue;
+            }
+            let i = entry.apath.rfind('/').unwrap();
+            let parent_apath = Apath::from(if i == 0 { "/" } else { &entry.apath[..i] });
+            let parent = match dirs.get(&parent_apath) {
+                Some(parent) => *parent,
+                None => {
+                    self.report.problem(Problem::new(
+                        ProblemKind::BadIndex,
+                        &entry.apath,
+                        "Entry's directory isn't in the index",
+                    ));
+                    continue;
+                }
+            };
+            let new_ino = first_new + new_inodes.len() as u64;
+            let name = entry.apath[i + 1..].to_owned();
+            if parent == ino {
+                top_children.push((name, new_ino));
+            } else {
+                new_inodes[(parent - first_new) as usize]
+                    .children
+                    .as_mut()
+                    .unwrap()
+                    .push((name, new_ino));
+            }
+            if entry.kind == Kind::Dir {
+                dirs.insert(entry.apath.clone(), new_ino);
+            }
+            new_inodes.push(Inode {
+                kind: entry.kind,
+                size: match entry.kind {
+                    Kind::File => entry.addrs.iter().map(|a| a.len).sum(),
+                    Kind::Symlink => entry.target.as_ref().map_or(0, |t| t.len() as u64),
+                    _ => 0,
+                },
+                apath: entry.apath,
+                parent,
+                version: ino,
+                mtime: entry.mtime.unwrap_or(0),
+                target: entry.target,
+                addrs: entry.addrs,
+                children: if entry.kind == Kind::Dir {
+                    Some(Vec::new())
+                } else {
+                    None
+                },
+                band_id: None,
+            });
+        }
+        self.inodes.extend(new_inodes);
+        self.inodes[(ino - ROOT_INO) as usize].children = Some(top_children);
+        Ok(())
+    }
+
+    /// The children of a directory, reading its version's index if necessary.
+    fn children(&mut self, ino: u64) -> Result<&[(String, u64)]> {
+        self.load_version(ino)?;
+        match self.inode(ino) {
+            Some(Inode {
+                kind: Kind::Dir,
+                children: Some(children),
+                ..
+            }) => Ok(children),
+            _ => Err(Error::NotADirectory(format!("inode {}", ino).into())),
+        }
+    }
+
+    /// Find a name in a directory, and return its attributes, or None if it's not there.
+    pub fn lookup(&mut self, parent: u64, name: &str) -> Result<Option<Attr>> {
+        let ino = match self.children(parent)?.iter().find(|(n, _)| n == name) {
+            Some((_, ino)) => *ino,
+            None => return Ok(None),
+        };
+        Ok(self.getattr(ino))
+    }
+
+    /// List a directory, including `.` and `..`.
+    pub fn readdir(&mut self, ino: u64) -> Result<Vec<DirEntry>> {
+        let parent = self.inode(ino).map_or(ROOT_INO, |i| i.parent);
+        let mut entries = vec![
+            DirEntry {
+                name: ".".to_owned(),
+                ino,
+                kind: Kind::Dir,
+            },
+            DirEntry {
+                name: "..".to_owned(),
+                ino: parent,
+                kind: Kind::Dir,
+            },
+        ];
+        let children = self.children(ino)?.to_vec();
+        for (name, child) in children {
+            entries.push(DirEntry {
+                name,
+                ino: child,
+                kind: self.inodes[(child - ROOT_INO) as usize].kind,
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Return the target of a symlink, or None if it's not a symlink.
+    pub fn readlink(&self, ino: u64) -> Option<&str> {
+        self.inode(ino).and_then(|i| i.target.as_deref())
+    }
+
+    /// Return a reader for a file, reusing the one last used for it if it's still open.
+    fn open_file(&mut self, ino: u64) -> Result<&mut ReadStoredFile> {
+        match self.open_files.iter().position(|(i, _)| *i == ino) {
+            Some(i) => {
+                let open = self.open_files.remove(i).unwrap();
+                self.open_files.push_back(open);
+            }
+            None => {
+                let addrs = match self.inode(ino) {
+                    Some(inode) if inode.kind == Kind::File => inode.addrs.clone(),
+                    _ => return Err(Error::NotAFile(format!("inode {}", ino).into())),
+                };
+                let block_dir = self.archive.block_dir().clone();
+                let file = StoredFile::open(block_dir, addrs, &self.report).into_read();
+                if self.open_files.len() >= OPEN_FILES {
+                    self.open_files.pop_front();
+                }
+                self.open_files.push_back((ino, file));
+            }
+        }
+        Ok(&mut self.open_files.back_mut().unwrap().1)
+    }
+
+    /// Read up to `size` bytes from a file, starting at `offset`.
+    ///
+    /// If a block can't be read, an error is returned, which the caller should report
+    /// through `report_damaged`.
+    pub fn read(&mut self, ino: u64, offset: u64, size: u32) -> Result<Vec<u8>> {
+        let file = self.open_file(ino)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut buf = Vec::with_capacity(size as usize);
+        file.take(u64::from(size)).read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Report that a file couldn't be read.
+    pub fn report_damaged(&self, ino: u64, error: Error) {
+        let path = match self.inode(ino) {
+            Some(inode) => match self.inode(inode.version).and_then(|v| v.band_id.as_ref()) {
+                Some(band_id) => format!("{}{}", band_id, inode.apath),
+                None => inode.apath.to_string(),
+            },
+            None => format!("inode {}", ino),
+        };
+        self.report.problem(
+            Problem::new(ProblemKind::DamagedFile, path, "Can't read mounted file")
+                .with_error(error),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::*;
+
+    fn lookup_path(fs: &mut ArchiveFs, path: &str) -> Option<Attr> {
+        let mut attr = fs.getattr(ROOT_INO);
+        for name in path.split('/').filter(|n| !n.is_empty()) {
+            attr = fs.lookup(attr?.ino, name).unwrap();
+        }
+        attr
+    }
+
+    #[test]
+    fn list_versions_and_latest() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let mut fs = ArchiveFs::new(&af).unwrap();
+        let names: Vec<String> = fs
+            .readdir(ROOT_INO)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        assert_eq!(names, [".", "..", "b0000", "b0001", "latest"]);
+        let latest = lookup_path(&mut fs, "/latest").unwrap();
+        assert_eq!(latest.kind, Kind::Symlink);
+        assert_eq!(fs.readlink(latest.ino), Some("b0001"));
+        // Versions aren't read until they're looked into.
+        assert_eq!(fs.inodes.len(), 4);
+    }
+
+    #[test]
+    fn look_up_and_read_files() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let mut fs = ArchiveFs::new(&af).unwrap();
+
+        let names: Vec<String> = {
+            let b1 = lookup_path(&mut fs, "/b0001").unwrap();
+            fs.readdir(b1.ino)
+                .unwrap()
+                .into_iter()
+                .map(|e| e.name)
+                .collect()
+        };
+        let mut expected = vec![".", "..", "hello", "hello2", "link", "subdir"];
+        if !SYMLINKS_SUPPORTED {
+            expected.retain(|n| *n != "link");
+        }
+        assert_eq!(names, expected);
+        assert!(lookup_path(&mut fs, "/b0000/hello2").is_none());
+        assert!(lookup_path(&mut fs, "/b0001/nothing").is_none());
+
+        let subfile = lookup_path(&mut fs, "/b0001/subdir/subfile").unwrap();
+        assert_eq!(subfile.kind, Kind::File);
+        assert_eq!(subfile.size, 8);
+        assert_eq!(fs.read(subfile.ino, 0, 3).unwrap(), b"con");
+        assert_eq!(fs.read(subfile.ino, 3, 100).unwrap(), b"tents");
+        // The file is kept open, so its block is read only once.
+        assert_eq!(af.report().get_count("block.read"), 1);
+        assert_eq!(fs.read(subfile.ino, 3, 2).unwrap(), b"te");
+        assert_eq!(fs.read(subfile.ino, 8, 10).unwrap(), b"");
+        // Seeking back within the block doesn't read it again.
+        assert_eq!(af.report().get_count("block.read"), 1);
+        let hello = lookup_path(&mut fs, "/b0001/hello").unwrap();
+        assert_eq!(fs.read(hello.ino, 0, 100).unwrap(), b"contents");
+
+        let subdir = lookup_path(&mut fs, "/b0001/subdir").unwrap();
+        let dotdot = &fs.readdir(subdir.ino).unwrap()[1];
+        assert_eq!(dotdot.ino, lookup_path(&mut fs, "/b0001").unwrap().ino);
+    }
+
+    #[test]
+    fn read_across_blocks() {
+        let af = ScratchArchive::new();
+        let report = af.report();
+        let band = Band::create(&af).unwrap();
+        let mut block_dir = af.block_dir().clone();
+        let mut addrs = block_dir.store(&mut &b"first block, "[..], report).unwrap();
+        let second = block_dir
+            .store(&mut &b"..second block.."[..], report)
+            .unwrap();
+        addrs.push(Address {
+            hash: second[0].hash.clone(),
+            start: 2,
+            len: 12,
+        });
+        let mut ib = band.index_builder();
+        for (apath, kind, addrs) in [("/", Kind::Dir, vec![]), ("/multi", Kind::File, addrs)] {
+            ib.push(Entry {
+                apath: apath.into(),
+                kind,
+                mtime: None,
+                addrs,
+                target: None,
+                size: None,
+            });
+        }
+        ib.finish_hunk(report).unwrap();
+        band.close(report).unwrap();
+
+        let mut fs = ArchiveFs::new(&af).unwrap();
+        let multi = lookup_path(&mut fs, "/b0000/multi").unwrap();
+        assert_eq!(multi.size, 25);
+        let mut content = Vec::new();
+        while (content.len() as u64) < multi.size {
+            let page = fs.read(multi.ino, content.len() as u64, 7).unwrap();
+            assert!(!page.is_empty());
+            content.extend(page);
+        }
+        assert_eq!(content, b"first block, second block");
+        assert_eq!(fs.read(multi.ino, 11, 6).unwrap(), b", seco");
+    }
+
+    #[test]
+    fn damaged_block_is_an_error() {
+        let af = ScratchArchive::new();
+        af.store_version_in_hunks();
+        af.damage(Damage::FlipBlockBit);
+        let mut fs = ArchiveFs::new(&af).unwrap();
+        let b = lookup_path(&mut fs, "/b0000/b").unwrap();
+        let err = fs.read(b.ino, 0, 1 << 20).unwrap_err();
+        fs.report_damaged(b.ino, err);
+        let counts = af.report().borrow_counts();
+        assert_eq!(counts.count_problems_of_kind(ProblemKind::DamagedFile), 1);
+        assert_eq!(counts.problems().last().unwrap().path, "b0000/b");
+    }
+}
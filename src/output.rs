@@ -5,10 +5,15 @@
 //!
 //! These are objects that accept iterators of different types of content, and write it to a
 //! file (typically stdout).
+//!
+//! The `json_*` functions describe objects as a single line of JSON, for `--json` output.
+//! Optional fields are always present, and null when they're not known.
 
 use super::*;
 
 use chrono::Local;
+use serde::Serialize;
+use serde_json::json;
 
 /// Show something about an archive.
 pub trait ShowArchive {
@@ -43,23 +48,10 @@ impl VerboseVersionList {
 
 impl ShowArchive for VerboseVersionList {
     fn show_archive(&self, archive: &Archive) -> Result<()> {
-        let report = archive.report();
-        for band_id in archive.list_bands()? {
-            let band = match Band::open(&archive, &band_id) {
-                Ok(band) => band,
-                Err(e) => {
-                    report.problem(&format!("Failed to open band {:?}: {:?}", band_id, e));
-                    continue;
-                }
-            };
-            let info = match band.get_info(archive.report()) {
-                Ok(info) => info,
-                Err(e) => {
-                    report.problem(&format!("Failed to read band tail {:?}: {:?}", band_id, e));
-                    continue;
-                }
-            };
-            let is_complete_str = if info.is_closed {
+        for_each_band_info(archive, |band, info| {
+            let is_complete_str = if info.is_partial {
+                "partial"
+            } else if info.is_closed {
                 "complete"
             } else {
                 "incomplete"
@@ -72,7 +64,7 @@ impl ShowArchive for VerboseVersionList {
                 let disk_bytes = band.get_disk_size()?;
                 println!(
                     "{:<26} {:<10} {} {:>7} {:>8}MB",
-                    band_id,
+                    info.id,
                     is_complete_str,
                     start_time_str,
                     duration_str,
@@ -81,10 +73,137 @@ impl ShowArchive for VerboseVersionList {
             } else {
                 println!(
                     "{:<26} {:<10} {} {:>7}",
-                    band_id, is_complete_str, start_time_str, duration_str,
+                    info.id, is_complete_str, start_time_str, duration_str,
                 );
             }
-        }
-        Ok(())
+            Ok(())
+        })
+    }
+}
+
+/// List versions as JSON, one per line.
+#[derive(Debug, Default)]
+pub struct JsonVersionList {
+    show_sizes: bool,
+}
+
+impl JsonVersionList {
+    /// Control whether to include the disk usage of each version, as `disk_bytes`.
+    pub fn show_sizes(self, show_sizes: bool) -> JsonVersionList {
+        JsonVersionList { show_sizes }
+    }
+}
+
+impl ShowArchive for JsonVersionList {
+    fn show_archive(&self, archive: &Archive) -> Result<()> {
+        for_each_band_info(archive, |band, info| {
+            let mut j = json!({
+                "id": info.id.to_string(),
+                "complete": info.is_closed,
+                "partial": info.is_partial,
+                "start_time": info.start_time.to_rfc3339(),
+                "end_time": info.end_time.map(|t| t.to_rfc3339()),
+                "duration_secs": info.end_time.map(|t| (t - info.start_time).num_seconds()),
+            });
+            if self.show_sizes {
+                j["disk_bytes"] = band.get_disk_size()?.into();
+            }
+            println!("{}", j);
+            Ok(())
+        })
+    }
+}
+
+/// Call `f` on each band in the archive that can be read, reporting problems with the others.
+fn for_each_band_info<F>(archive: &Archive, mut f: F) -> Result<()>
+where
+    F: FnMut(&Band, &band::Info) -> Result<()>,
+{
+    let report = archive.report();
+    for band_id in archive.list_bands()? {
+        let band = match Band::open(&archive, &band_id) {
+            Ok(band) => band,
+            Err(e) => {
+                report.problem(
+                    Problem::new(ProblemKind::BadBand, &band_id, "Failed to open band")
+                        .with_error(e),
+                );
+                continue;
+            }
+        };
+        let info = match band.get_info(archive.report()) {
+            Ok(info) => info,
+            Err(e) => {
+                report.problem(
+                    Problem::new(ProblemKind::BadBand, &band_id, "Failed to read band tail")
+                        .with_error(e),
+                );
+                continue;
+            }
+        };
+        f(&band, &info)?;
     }
+    Ok(())
+}
+
+/// The name of an entry kind, as used in JSON output.
+pub fn kind_name(kind: Kind) -> &'static str {
+    match kind {
+        Kind::File => "file",
+        Kind::Dir => "dir",
+        Kind::Symlink => "symlink",
+        Kind::Unknown => "unknown",
+    }
+}
+
+/// Describe an entry in a tree.
+///
+/// `size` is only set for files, and `target` only for symlinks.
+pub fn json_entry(entry: &Entry) -> String {
+    json_line(&json!({
+        "apath": entry.apath(),
+        "kind": kind_name(entry.kind()),
+        "size": match entry.kind() {
+            Kind::File => Some(entry.size().unwrap_or(0)),
+            _ => None,
+        },
+        "mtime": entry.unix_mtime(),
+        "target": entry.symlink_target(),
+    }))
+}
+
+/// Describe one line of a diff: `change` is one of `left`, `right`, `same`, `changed`,
+/// `metadata` or `kind`.
+pub fn json_diff(apath: &Apath, change: &str) -> String {
+    json_line(&json!({
+        "apath": apath,
+        "change": change,
+    }))
+}
+
+/// Describe the size of a tree.
+pub fn json_tree_size(file_bytes: u64) -> String {
+    json_line(&json!({ "file_bytes": file_bytes }))
+}
+
+/// Describe a problem, such as those found by validation.
+///
+/// `kind` is the name of the problem's counter, such as `problem.bad_block`, and `error` is
+/// the underlying error, if any.
+pub fn json_problem(problem: &Problem) -> String {
+    json_line(&json!({
+        "problem": problem.message,
+        "kind": problem.kind.counter_name(),
+        "path": problem.path,
+        "error": problem.error.as_ref().map(ToString::to_string),
+    }))
+}
+
+/// Describe an error that stopped the command.
+pub fn json_error(message: &str) -> String {
+    json_line(&json!({ "error": message }))
+}
+
+fn json_line<T: Serialize>(value: &T) -> String {
+    serde_json::to_string(value).expect("failed to serialize JSON")
 }
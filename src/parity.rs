@@ -0,0 +1,471 @@
+// Conserve backup system.
+// Copyright 2019 Martin Pool.
+
+//! Parity data, from which damaged or missing blocks and index hunks can be reconstructed.
+//!
+//! Files are protected in groups of up to `DATA_SHARDS`, each with `PARITY_SHARDS`
+//! Reed-Solomon parity shards, so that up to that many damaged files in a group can be
+//! reconstructed.
+//!
+//! Each group is stored in the archive's parity directory as a JSON header, such as
+//! `000000000.json`, listing the files and their hashes, and a `000000000.parity` file
+//! holding the parity shards. Every shard is as long as the longest file in the group, so
+//! files of similar lengths are grouped together.
+//!
+//! Blocks and the index hunks of complete bands are protected. They're never changed after
+//! they're written, so a group stays valid as long as its files are present.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use blake2_rfc::blake2b;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+use crate::blockdir::BLAKE_HASH_SIZE_BYTES;
+use crate::jsonio;
+use crate::*;
+
+/// Maximum number of files protected by one parity group.
+const DATA_SHARDS: usize = 16;
+
+/// Number of parity shards in each group, which is how many of its files can be
+/// reconstructed.
+const PARITY_SHARDS: usize = 2;
+
+const HEADER_EXTENSION: &str = "json";
+const PARITY_EXTENSION: &str = "parity";
+
+/// Format of the on-disk group header.
+#[derive(Debug, Serialize, Deserialize)]
+struct GroupHeader {
+    /// Files protected by this group, in shard order.
+    members: Vec<Member>,
+
+    /// Hashes of the parity shards, in the order they're stored in the parity file.
+    parity_hashes: Vec<String>,
+
+    /// Length of every shard, which is the length of the longest member.
+    shard_len: u64,
+}
+
+/// One file protected by a parity group.
+#[derive(Debug, Serialize, Deserialize)]
+struct Member {
+    /// Path relative to the archive directory, separated by `/`.
+    path: String,
+
+    /// Length of the file, before it's padded to the shard length.
+    len: u64,
+
+    /// Hex BLAKE2b hash of the file content.
+    hash: String,
+}
+
+impl Member {
+    /// True if the path names a block, such as `d/123/1234...`, or an index hunk, such as
+    /// `b0000/i/00000/000000000`, so that a damaged header can't direct a reconstructed
+    /// file anywhere else.
+    fn is_valid(&self) -> bool {
+        let is_hex = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_hexdigit());
+        let is_digits =
+            |s: &str, len: usize| s.len() == len && s.bytes().all(|b| b.is_ascii_digit());
+        match self.path.split('/').collect::<Vec<_>>().as_slice() {
+            ["d", subdir, hash] => subdir.len() == 3 && is_hex(hash) && hash.starts_with(subdir),
+            [band, "i", subdir, hunk] => {
+                BandId::from_string(band).is_ok_and(|id| id.to_string() == *band)
+                    && is_digits(subdir, 5)
+                    && is_digits(hunk, 9)
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Group {
+    id: u32,
+    header: GroupHeader,
+}
+
+/// A file protected by parity that is missing, or doesn't match its hash.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParityDamage {
+    pub path: PathBuf,
+
+    /// True if enough of the rest of its group is intact to reconstruct it.
+    pub recoverable: bool,
+}
+
+/// Write parity for every block, and every index hunk in a complete band, that isn't already
+/// protected.
+///
+/// Returns the number of parity groups written.
+pub fn write_parity(archive: &Archive) -> Result<usize> {
+    let report = archive.report();
+    ensure_dir_exists(&archive.parity_path())?;
+    let groups = read_groups(archive, report)?;
+    let protected: HashSet<PathBuf> = groups
+        .iter()
+        .flat_map(|g| g.header.members.iter())
+        .map(|m| member_path(archive, m))
+        .collect();
+    let mut unprotected = Vec::new();
+    for path in protectable_files(archive)? {
+        if !protected.contains(&path) {
+            unprotected.push((fs::metadata(&path)?.len(), path));
+        }
+    }
+    // Group files of similar lengths, so that little of each shard is padding.
+    unprotected.sort();
+    report.note(&format!("Write parity for {} files...", unprotected.len()));
+    let first_id = groups.iter().map(|g| g.id + 1).max().unwrap_or(0);
+    let mut n_groups = 0;
+    for (id, files) in (first_id..).zip(unprotected.chunks(DATA_SHARDS)) {
+        write_group(archive, id, files)?;
+        n_groups += 1;
+    }
+    Ok(n_groups)
+}
+
+/// Check every file protected by parity, and return those that are missing or damaged.
+///
+/// Damage to the parity data itself is reported to `report`.
+pub fn find_parity_damage(archive: &Archive, report: &Report) -> Result<Vec<ParityDamage>> {
+    let mut damage = Vec::new();
+    for group in read_groups(archive, report)? {
+        let shards = read_shards(archive, &group, report)?;
+        let recoverable =
+            shards.iter().filter(|s| s.is_some()).count() >= group.header.members.len();
+        for (member, shard) in group.header.members.iter().zip(&shards) {
+            if shard.is_none() {
+                damage.push(ParityDamage {
+                    path: member_path(archive, member),
+                    recoverable,
+                });
+            }
+        }
+    }
+    Ok(damage)
+}
+
+/// Reconstruct a missing or damaged file from its parity group, and write it back.
+///
+/// Nothing is written if the file is already intact.
+pub fn reconstruct_from_parity(archive: &Archive, path: &Path, report: &Report) -> Result<()> {
+    for group in read_groups(archive, report)? {
+        let members = &group.header.members;
+        let i = match members.iter().position(|m| member_path(archive, m) == path) {
+            Some(i) => i,
+            None => continue,
+        };
+        let mut shards = read_shards(archive, &group, report)?;
+        if shards[i].is_some() {
+            return Ok(());
+        }
+        let unrecoverable = || Error::ParityUnrecoverable(path.to_path_buf());
+        ReedSolomon::new(members.len(), group.header.parity_hashes.len())
+            .and_then(|rs| rs.reconstruct_data(&mut shards))
+            .map_err(|_| unrecoverable())?;
+        let mut content = shards[i].take().ok_or_else(unrecoverable)?;
+        content.truncate(members[i].len as usize);
+        if hash_hex(&content) != members[i].hash {
+            return Err(unrecoverable());
+        }
+        ensure_dir_exists(path.parent().unwrap())?;
+        let mut f = AtomicFile::new(path)?;
+        f.write_all(&content)?;
+        return f.close(report);
+    }
+    Err(Error::NotProtectedByParity(path.to_path_buf()))
+}
+
+/// Return the paths of all the files that should be protected by parity.
+fn protectable_files(archive: &Archive) -> Result<Vec<PathBuf>> {
+    // Problems with the blockdir or bands are found by `validate`, not here.
+    let quiet_report = Report::with_ui(Box::new(ui::null::NullUI::new()));
+    let block_dir = archive.block_dir();
+    let mut paths: Vec<PathBuf> = block_dir
+        .block_names(&quiet_report)?
+        .iter()
+        .map(|hash| block_dir.path_for_file(hash))
+        .collect();
+    for band_id in archive.list_bands()? {
+        let band = match Band::open(archive, &band_id) {
+            Ok(band) => band,
+            Err(_) => continue,
+        };
+        // Hunks of an incomplete band might still be removed by `repair`.
+        if band.is_closed()? {
+            paths.extend(band.index().hunk_paths()?);
+        }
+    }
+    Ok(paths)
+}
+
+fn write_group(archive: &Archive, id: u32, files: &[(u64, PathBuf)]) -> Result<()> {
+    let mut members = Vec::with_capacity(files.len());
+    let mut shards = Vec::with_capacity(files.len() + PARITY_SHARDS);
+    for (_len, path) in files {
+        let content = fs::read(path)?;
+        members.push(Member {
+            path: relative_path(archive, path),
+            len: content.len() as u64,
+            hash: hash_hex(&content),
+        });
+        shards.push(content);
+    }
+    let shard_len = shards.iter().map(Vec::len).max().unwrap_or(0).max(1);
+    for shard in &mut shards {
+        shard.resize(shard_len, 0);
+    }
+    shards.resize(files.len() + PARITY_SHARDS, vec![0; shard_len]);
+    ReedSolomon::new(files.len(), PARITY_SHARDS)
+        .and_then(|rs| rs.encode(&mut shards))
+        .expect("Failed to encode parity shards");
+    let parity = shards.split_off(files.len());
+
+    let report = archive.report();
+    let mut f = AtomicFile::new(&group_path(archive, id, PARITY_EXTENSION))?;
+    for shard in &parity {
+        f.write_all(shard)?;
+    }
+    f.close(report)?;
+    // The header is written last, so that the group is only used once it's complete.
+    let header = GroupHeader {
+        members,
+        parity_hashes: parity.iter().map(|p| hash_hex(p)).collect(),
+        shard_len: shard_len as u64,
+    };
+    jsonio::write_serde(&group_path(archive, id, HEADER_EXTENSION), &header, report)
+}
+
+/// Read the headers of all parity groups, in order, reporting any that can't be read.
+fn read_groups(archive: &Archive, report: &Report) -> Result<Vec<Group>> {
+    let dir = archive.parity_path();
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut groups = Vec::new();
+    for name in list_dir(&dir)?.0 {
+        let path = dir.join(&name);
+        // Ignore parity files, and temporary files left by an interrupted write.
+        let id = match name.split('.').collect::<Vec<_>>().as_slice() {
+            [id, HEADER_EXTENSION] => match id.parse::<u32>() {
+                Ok(id) => id,
+                Err(_) => continue,
+            },
+            _ => continue,
+        };
+        match jsonio::read_serde::<GroupHeader>(&path, report) {
+            Ok(ref header) if !header.members.iter().all(Member::is_valid) => {
+                report.problem(Problem::for_path(
+                    ProblemKind::BadParity,
+                    &path,
+                    "Parity header names a file that isn't a block or index hunk",
+                ))
+            }
+            Ok(header) => groups.push(Group { id, header }),
+            Err(e) => report.problem(
+                Problem::for_path(ProblemKind::BadParity, &path, "Can't read parity header")
+                    .with_error(e),
+            ),
+        }
+    }
+    Ok(groups)
+}
+
+/// Read the members and parity shards of a group, padded to the shard length.
+///
+/// Shards that are missing or damaged are None. Damaged parity data is reported.
+fn read_shards(archive: &Archive, group: &Group, report: &Report) -> Result<Vec<Option<Vec<u8>>>> {
+    let shard_len = group.header.shard_len as usize;
+    let mut shards = Vec::new();
+    for member in &group.header.members {
+        // Any error reading the file counts as damage, which might be repaired.
+        shards.push(match fs::read(member_path(archive, member)) {
+            Ok(mut content) if hash_hex(&content) == member.hash => {
+                content.resize(shard_len, 0);
+                Some(content)
+            }
+            _ => None,
+        });
+    }
+    let parity_path = group_path(archive, group.id, PARITY_EXTENSION);
+    let parity = fs::read(&parity_path).unwrap_or_default();
+    for (i, hash) in group.header.parity_hashes.iter().enumerate() {
+        let shard = parity.get(i * shard_len..(i + 1) * shard_len);
+        if shard.map(hash_hex).as_ref() == Some(hash) {
+            shards.push(shard.map(<[u8]>::to_vec));
+        } else {
+            report.problem(Problem::for_path(
+                ProblemKind::BadParity,
+                &parity_path,
+                &format!("Parity shard {} is missing or damaged", i),
+            ));
+            shards.push(None);
+        }
+    }
+    Ok(shards)
+}
+
+fn group_path(archive: &Archive, id: u32, extension: &str) -> PathBuf {
+    archive
+        .parity_path()
+        .join(format!("{:09}.{}", id, extension))
+}
+
+fn member_path(archive: &Archive, member: &Member) -> PathBuf {
+    member
+        .path
+        .split('/')
+        .fold(archive.path().to_path_buf(), |p, c| p.join(c))
+}
+
+fn relative_path(archive: &Archive, path: &Path) -> String {
+    path.strip_prefix(archive.path())
+        .expect("Protected file is inside the archive")
+        .iter()
+        .map(|c| c.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn hash_hex(content: &[u8]) -> String {
+    hex::encode(blake2b::blake2b(BLAKE_HASH_SIZE_BYTES, &[], content).as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_fixtures::*;
+
+    #[test]
+    fn write_parity_protects_each_file_once() {
+        let af = ScratchArchive::new();
+        af.store_version_in_hunks();
+        assert!(!af.parity_path().exists());
+        // Three blocks, and four index hunks.
+        assert_eq!(write_parity(&af).unwrap(), 1);
+        assert_eq!(write_parity(&af).unwrap(), 0);
+        assert_eq!(find_parity_damage(&af, af.report()).unwrap(), vec![]);
+
+        af.store_version_in_hunks();
+        // Only the new index hunks are unprotected: the blocks are shared.
+        assert_eq!(write_parity(&af).unwrap(), 1);
+        let (files, _) = list_dir(&af.parity_path()).unwrap();
+        assert_eq!(
+            files,
+            [
+                "000000000.json",
+                "000000000.parity",
+                "000000001.json",
+                "000000001.parity"
+            ]
+        );
+    }
+
+    #[test]
+    fn reconstruct_damaged_files() {
+        let af = ScratchArchive::new();
+        af.store_version_in_hunks();
+        write_parity(&af).unwrap();
+        let hunk_path = af.path().join("b0000/i/00000/000000001");
+        let hunk = fs::read(&hunk_path).unwrap();
+        fs::remove_file(&hunk_path).unwrap();
+        let block_path = af.damage(Damage::FlipBlockBit);
+        let block = fs::read(&block_path).unwrap();
+
+        let report = Report::new();
+        let damage = find_parity_damage(&af, &report).unwrap();
+        assert_eq!(damage.len(), 2);
+        assert!(damage.iter().all(|d| d.recoverable));
+        for d in &damage {
+            reconstruct_from_parity(&af, &d.path, &report).unwrap();
+        }
+        assert_eq!(fs::read(&hunk_path).unwrap(), hunk);
+        assert_ne!(fs::read(&block_path).unwrap(), block);
+        assert_eq!(find_parity_damage(&af, &report).unwrap(), vec![]);
+        assert_eq!(report.borrow_counts().count_problems(), 0);
+    }
+
+    #[test]
+    fn too_much_damage_is_unrecoverable() {
+        let af = ScratchArchive::new();
+        af.store_version_in_hunks();
+        write_parity(&af).unwrap();
+        let report = Report::new();
+        for i in 0..3 {
+            fs::remove_file(af.path().join(format!("b0000/i/00000/00000000{}", i))).unwrap();
+        }
+        let damage = find_parity_damage(&af, &report).unwrap();
+        assert_eq!(damage.len(), 3);
+        assert!(damage.iter().all(|d| !d.recoverable));
+        match reconstruct_from_parity(&af, &damage[0].path, &report) {
+            Err(Error::ParityUnrecoverable(path)) => assert_eq!(path, damage[0].path),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn member_paths_outside_blocks_and_index_are_rejected() {
+        let member = |path: &str| Member {
+            path: path.to_owned(),
+            len: 0,
+            hash: String::new(),
+        };
+        for path in &["d/abc/abcdef0123", "b0000/i/00000/000000001"] {
+            assert!(member(path).is_valid(), "{}", path);
+        }
+        for path in &[
+            "../outside",
+            "/etc/passwd",
+            "d/abc/../../../outside",
+            "d/abc/def012",
+            "d/../abc",
+            "b0000/i/00000/../../../outside",
+            "b0000/i/../000000001",
+            "b0/i/00000/000000001",
+            "CONSERVE",
+        ] {
+            assert!(!member(path).is_valid(), "{}", path);
+        }
+
+        let af = ScratchArchive::new();
+        af.store_version_in_hunks();
+        write_parity(&af).unwrap();
+        let header_path = af.parity_path().join("000000000.json");
+        let header = fs::read_to_string(&header_path).unwrap();
+        let header = header.replacen("\"path\":\"", "\"path\":\"../", 1);
+        fs::write(&header_path, header).unwrap();
+
+        let report = Report::new();
+        assert_eq!(find_parity_damage(&af, &report).unwrap(), vec![]);
+        assert_eq!(
+            report
+                .borrow_counts()
+                .count_problems_of_kind(ProblemKind::BadParity),
+            1
+        );
+    }
+
+    #[test]
+    fn damaged_parity_is_reported() {
+        let af = ScratchArchive::new();
+        af.store_version_in_hunks();
+        write_parity(&af).unwrap();
+        let parity_path = af.parity_path().join("000000000.parity");
+        let mut parity = fs::read(&parity_path).unwrap();
+        parity[0] ^= 1;
+        fs::write(&parity_path, &parity).unwrap();
+
+        let report = Report::new();
+        assert_eq!(find_parity_damage(&af, &report).unwrap(), vec![]);
+        let counts = report.borrow_counts();
+        assert_eq!(counts.count_problems_of_kind(ProblemKind::BadParity), 1);
+        assert_eq!(counts.problems()[0].path, parity_path.display().to_string());
+    }
+}
@@ -0,0 +1,178 @@
+// Conserve backup system.
+// Copyright 2019 Martin Pool.
+
+//! Problems that are reported and counted, but don't necessarily stop an operation.
+//!
+//! For example a backup carries on past a source file that can't be read, and validation
+//! carries on past a corrupt block. Each problem is sent to the UI, and also kept in the
+//! `Report` so that the caller can decide afterwards whether the result is acceptable.
+
+use std::fmt;
+
+use crate::*;
+
+/// Category of a `Problem`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ProblemKind {
+    /// A source directory, file or symlink couldn't be read.
+    SourceRead,
+    /// A source file disappeared between listing its directory and reading it.
+    SourceVanished,
+    /// A source file name or symlink target isn't valid UTF-8.
+    UndecodableName,
+    /// An entry is of a kind that can't be stored or restored here.
+    UnsupportedKind,
+    /// An entry couldn't be copied into the destination tree.
+    CopyFailed,
+    /// A file or directory that shouldn't be there was found in the archive.
+    UnexpectedFile,
+    /// A file that should be in the archive is missing.
+    MissingFile,
+    /// A band couldn't be read.
+    BadBand,
+    /// An index hunk or entry is damaged.
+    BadIndex,
+    /// A data block couldn't be read, or its content doesn't match its hash.
+    BadBlock,
+    /// A stored file can't be read back, because some of its blocks are missing or bad.
+    DamagedFile,
+    /// Parity data is missing or damaged, so can't be used to reconstruct other files.
+    BadParity,
+    /// A file couldn't be written or removed while restoring.
+    RestoreFailed,
+}
+
+/// All problem kinds, in order.
+pub(crate) static ALL_PROBLEM_KINDS: &[ProblemKind] = &[
+    ProblemKind::SourceRead,
+    ProblemKind::SourceVanished,
+    ProblemKind::UndecodableName,
+    ProblemKind::UnsupportedKind,
+    ProblemKind::CopyFailed,
+    ProblemKind::UnexpectedFile,
+    ProblemKind::MissingFile,
+    ProblemKind::BadBand,
+    ProblemKind::BadIndex,
+    ProblemKind::BadBlock,
+    ProblemKind::DamagedFile,
+    ProblemKind::BadParity,
+    ProblemKind::RestoreFailed,
+];
+
+impl ProblemKind {
+    /// The name of the `Report` counter for problems of this kind, which is also the name
+    /// used in JSON output.
+    pub fn counter_name(self) -> &'static str {
+        match self {
+            ProblemKind::SourceRead => "problem.source_read",
+            ProblemKind::SourceVanished => "problem.source_vanished",
+            ProblemKind::UndecodableName => "problem.undecodable_name",
+            ProblemKind::UnsupportedKind => "problem.unsupported_kind",
+            ProblemKind::CopyFailed => "problem.copy_failed",
+            ProblemKind::UnexpectedFile => "problem.unexpected_file",
+            ProblemKind::MissingFile => "problem.missing_file",
+            ProblemKind::BadBand => "problem.bad_band",
+            ProblemKind::BadIndex => "problem.bad_index",
+            ProblemKind::BadBlock => "problem.bad_block",
+            ProblemKind::DamagedFile => "problem.damaged_file",
+            ProblemKind::BadParity => "problem.bad_parity",
+            ProblemKind::RestoreFailed => "problem.restore_failed",
+        }
+    }
+}
+
+/// Something that went wrong, concerning one file or directory.
+#[derive(Debug)]
+pub struct Problem {
+    pub kind: ProblemKind,
+    /// The apath or filesystem path of the entry or file with the problem.
+    pub path: String,
+    /// What went wrong, such as "Error reading directory".
+    pub message: String,
+    /// The underlying error, if there was one.
+    pub error: Option<Error>,
+}
+
+impl Problem {
+    pub fn new<P: fmt::Display>(kind: ProblemKind, path: P, message: &str) -> Problem {
+        Problem {
+            kind,
+            path: path.to_string(),
+            message: message.to_owned(),
+            error: None,
+        }
+    }
+
+    /// Make a Problem concerning a filesystem path.
+    pub fn for_path<P: AsRef<std::path::Path>>(
+        kind: ProblemKind,
+        path: P,
+        message: &str,
+    ) -> Problem {
+        Problem::new(kind, path.as_ref().display(), message)
+    }
+
+    /// Attach the underlying error.
+    pub fn with_error<E: Into<Error>>(self, error: E) -> Problem {
+        Problem {
+            error: Some(error.into()),
+            ..self
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.message, self.path)?;
+        if let Some(ref e) = self.error {
+            write!(f, ": {}", e)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+
+    #[test]
+    fn display() {
+        let p = Problem::new(ProblemKind::SourceRead, "/a/b", "Error reading directory");
+        assert_eq!(p.to_string(), "Error reading directory: /a/b");
+        let p = p.with_error(io::Error::from(io::ErrorKind::NotFound));
+        assert_eq!(
+            p.to_string(),
+            "Error reading directory: /a/b: IO Error: entity not found"
+        );
+    }
+
+    #[test]
+    fn counters_are_registered_and_unique() {
+        let report = Report::new();
+        for k in ALL_PROBLEM_KINDS {
+            assert_eq!(report.borrow_counts().count_problems_of_kind(*k), 0);
+        }
+        let mut names: Vec<&str> = ALL_PROBLEM_KINDS.iter().map(|k| k.counter_name()).collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), ALL_PROBLEM_KINDS.len());
+    }
+
+    #[test]
+    fn report_keeps_and_counts_problems() {
+        let report = Report::new();
+        report.problem(Problem::new(ProblemKind::BadBlock, "aaaa", "Bad block"));
+        report.problem(Problem::new(ProblemKind::BadBlock, "bbbb", "Bad block"));
+        report.problem(Problem::new(ProblemKind::DamagedFile, "/a", "Can't read"));
+        let counts = report.borrow_counts();
+        assert_eq!(counts.count_problems(), 3);
+        assert_eq!(counts.count_problems_of_kind(ProblemKind::BadBlock), 2);
+        assert_eq!(counts.problems()[2].path, "/a");
+        assert_eq!(
+            counts.summary_of_problems(),
+            "           3 problems: 2 bad block, 1 damaged file.\n"
+        );
+    }
+}
@@ -0,0 +1,111 @@
+// Conserve backup system.
+// Copyright 2019 Martin Pool.
+
+//! Repair damage to an archive, such as is left by an interrupted backup.
+//!
+//! Damaged blocks and index hunks are reconstructed from parity data if possible, and
+//! otherwise damaged blocks are moved aside.
+//!
+//! Repairs are planned first, by `plan_repair`, so that they can be shown before
+//! anything is changed, and then made by `repair`.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::*;
+
+/// One change to an archive, proposed by `plan_repair`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Repair {
+    /// Remove an index hunk that can't be read, from the end of an incomplete band.
+    RemoveHunk(PathBuf),
+    /// Close an incomplete band, marking it as partial, so that it can be read.
+    CloseBand(BandId),
+    /// Reconstruct a missing or damaged block or index hunk from parity data.
+    Reconstruct(PathBuf),
+    /// Move a block that can't be read, or doesn't match its hash, into the archive's
+    /// quarantine directory.
+    QuarantineBlock(String),
+}
+
+impl fmt::Display for Repair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Repair::RemoveHunk(path) => {
+                write!(f, "Remove damaged index hunk {}", path.display())
+            }
+            Repair::CloseBand(band_id) => {
+                write!(f, "Close incomplete band {} as partial", band_id)
+            }
+            Repair::Reconstruct(path) => {
+                write!(f, "Reconstruct {} from parity", path.display())
+            }
+            Repair::QuarantineBlock(hash) => write!(f, "Quarantine damaged block {}", hash),
+        }
+    }
+}
+
+/// Find the repairs needed to the archive, without changing anything.
+///
+/// Every block, and every file protected by parity, is read to find those that are
+/// damaged. Bands whose head can't be read are left alone, because they can't be repaired
+/// here.
+pub fn plan_repair(archive: &Archive) -> Result<Vec<Repair>> {
+    let report = archive.report();
+    // Damage found while planning is described by the plan, rather than as problems.
+    let quiet_report = Report::with_ui(Box::new(ui::null::NullUI::new()));
+    let mut repairs = Vec::new();
+    report.note("Check bands...");
+    for band_id in archive.list_bands()? {
+        let band = match Band::open(archive, &band_id) {
+            Ok(band) => band,
+            Err(_) => continue,
+        };
+        if !band.is_closed()? {
+            for path in band.index().damaged_trailing_hunks()? {
+                repairs.push(Repair::RemoveHunk(path));
+            }
+            repairs.push(Repair::CloseBand(band_id));
+        }
+    }
+    report.note("Check parity...");
+    let mut reconstructed = HashSet::new();
+    for damage in find_parity_damage(archive, &quiet_report)? {
+        if damage.recoverable {
+            reconstructed.insert(damage.path.clone());
+            repairs.push(Repair::Reconstruct(damage.path));
+        }
+    }
+    report.note("Check blockdir...");
+    let block_dir = archive.block_dir();
+    let good_blocks = block_dir.validate(&quiet_report)?;
+    for hash in block_dir.block_names(&quiet_report)? {
+        if !good_blocks.contains_key(&hash)
+            && !reconstructed.contains(&block_dir.path_for_file(&hash))
+        {
+            repairs.push(Repair::QuarantineBlock(hash));
+        }
+    }
+    Ok(repairs)
+}
+
+/// Make repairs planned by `plan_repair`.
+///
+/// This shouldn't be run while a backup is writing to the archive, because its band
+/// would be closed.
+pub fn repair(archive: &Archive, repairs: &[Repair]) -> Result<()> {
+    let report = archive.report();
+    for r in repairs {
+        match r {
+            Repair::RemoveHunk(path) => fs::remove_file(path)?,
+            Repair::CloseBand(band_id) => Band::open(archive, band_id)?.close_partial(report)?,
+            Repair::Reconstruct(path) => reconstruct_from_parity(archive, path, report)?,
+            Repair::QuarantineBlock(hash) => archive
+                .block_dir()
+                .quarantine(hash, &archive.quarantine_path())?,
+        }
+    }
+    Ok(())
+}
@@ -25,6 +25,7 @@ use super::ui;
 use super::ui::plain::PlainUI;
 use super::ui::{compression_ratio, mbps_rate, UI};
 use super::*;
+use crate::problem::ALL_PROBLEM_KINDS;
 
 const M: u64 = 1_000_000;
 
@@ -42,6 +43,7 @@ static KNOWN_COUNTERS: &[&str] = &[
     "block.corrupt",
     "block.misplaced",
     "block.already_present",
+    "block.copied",
     "index.hunk",
     "source.error.metadata",
     "source.selected",
@@ -51,8 +53,24 @@ static KNOWN_COUNTERS: &[&str] = &[
     "skipped.excluded.files",
     "skipped.excluded.symlinks",
     "skipped.excluded.unknown",
+    "skipped.mount_points",
+    "skipped.large_files",
+    "skipped.old_files",
+    "skipped.new_files",
+    "skipped.kind.directories",
+    "skipped.kind.files",
+    "skipped.kind.symlinks",
+    "skipped.kind.unknown",
+    "restore.unchanged",
+    "restore.kept",
+    "restore.replaced",
+    "restore.deleted",
 ];
 
+/// The most problems kept in a report. Past this they're still shown and counted, but
+/// not kept, so that a badly damaged archive can't exhaust memory.
+pub const MAX_KEPT_PROBLEMS: usize = 10_000;
+
 /// Describes sizes of data read or written, with both the
 /// compressed and uncompressed size.
 #[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
@@ -81,12 +99,16 @@ pub struct Counts {
     pub total_work: u64,
     /// Amount of work done so far, to indicate percentage completion.
     pub done_work: u64,
+
+    /// Problems reported so far, in the order they occurred.
+    problems: Vec<Problem>,
 }
 
 /// A Report is notified of problems or non-problematic events that occur while Conserve is
 /// running.
 ///
-/// A Report holds counters, identified by a name.  The name must be in `KNOWN_COUNTERS`.
+/// A Report holds counters, identified by a name.  The name must be in `KNOWN_COUNTERS`, or
+/// be the counter of a `ProblemKind`.
 ///
 /// A Report is internally mutable, so a single instance can be shared by multiple objects
 /// or scopes (on the same thread) who all append to it.
@@ -177,14 +199,20 @@ impl Report {
     }
 
     /// Merge the contents of `from_report` into `self`.
+    ///
+    /// The problems kept in `from_report` are moved into `self`, without being shown again.
     pub fn merge_from(&self, from_report: &Report) {
-        let from_counts = from_report.mut_counts();
+        let mut from_counts = from_report.mut_counts();
         for (name, value) in &from_counts.count {
             self.increment(name, *value);
         }
         for (name, s) in &from_counts.sizes {
             self.increment_size(name, s.clone());
         }
+        let mut counts = self.mut_counts();
+        for problem in from_counts.problems.drain(..) {
+            counts.keep_problem(problem);
+        }
     }
 
     pub fn get_size(&self, counter_name: &str) -> Sizes {
@@ -217,14 +245,23 @@ impl Report {
         self.ui.lock().unwrap().print(s)
     }
 
-    /// Report that a problem occurred.
-    ///
-    /// Later this might also count or summarize them.
-    pub fn problem(&self, s: &str) {
-        // TODO: Rather than taking an arbitrary string, take an enum plus a path and perhaps an
-        // underlying IO error. Keep counts.
-        // <https://github.com/sourcefrog/conserve/issues/72>.
-        self.ui.lock().unwrap().problem(s)
+    /// Show an informational message that isn't part of the results of the command, such
+    /// as what's about to be checked.
+    pub fn note(&self, s: &str) {
+        self.ui.lock().unwrap().note(s)
+    }
+
+    /// Report that a problem occurred: show it, count it by kind, and keep it so that it can
+    /// be inspected later through `Counts::problems`.
+    pub fn problem(&self, problem: Problem) {
+        self.increment(problem.kind.counter_name(), 1);
+        self.ui.lock().unwrap().problem(&problem);
+        self.mut_counts().keep_problem(problem);
+    }
+
+    /// Show an error that stopped the operation.
+    pub fn error(&self, s: &str) {
+        self.ui.lock().unwrap().error(s)
     }
 
     pub fn finish(&self) {
@@ -280,6 +317,9 @@ impl Counts {
         for counter_name in KNOWN_COUNTERS {
             count.insert(*counter_name, 0);
         }
+        for kind in ALL_PROBLEM_KINDS {
+            count.insert(kind.counter_name(), 0);
+        }
         let mut sizes = BTreeMap::new();
         for counter_name in KNOWN_SIZES {
             sizes.insert(*counter_name, Sizes::default());
@@ -292,9 +332,59 @@ impl Counts {
             phase: String::new(),
             total_work: 0,
             done_work: 0,
+            problems: Vec::new(),
         }
     }
 
+    fn keep_problem(&mut self, problem: Problem) {
+        if self.problems.len() < MAX_KEPT_PROBLEMS {
+            self.problems.push(problem);
+        }
+    }
+
+    /// The problems reported so far, up to `MAX_KEPT_PROBLEMS`.
+    pub fn problems(&self) -> &[Problem] {
+        &self.problems
+    }
+
+    /// The total number of problems reported so far, including any that weren't kept.
+    pub fn count_problems(&self) -> u64 {
+        ALL_PROBLEM_KINDS
+            .iter()
+            .map(|k| self.count_problems_of_kind(*k))
+            .sum()
+    }
+
+    /// The number of problems of one kind reported so far.
+    pub fn count_problems_of_kind(&self, kind: ProblemKind) -> u64 {
+        self.get_count(kind.counter_name())
+    }
+
+    /// Describe the number of problems of each kind, as a line to add to a summary, or an
+    /// empty string if there were none.
+    pub fn summary_of_problems(&self) -> String {
+        if self.count_problems() == 0 {
+            return String::new();
+        }
+        let by_kind: Vec<String> = ALL_PROBLEM_KINDS
+            .iter()
+            .map(|k| (k, self.count_problems_of_kind(*k)))
+            .filter(|(_, n)| *n > 0)
+            .map(|(k, n)| {
+                format!(
+                    "{} {}",
+                    n.separate_with_commas(),
+                    k.counter_name()["problem.".len()..].replace('_', " ")
+                )
+            })
+            .collect();
+        format!(
+            "{:>12} problems: {}.\n",
+            self.count_problems().separate_with_commas(),
+            by_kind.join(", ")
+        )
+    }
+
     /// Return the value of a counter.  A counter that has not yet been updated is 0.
     pub fn get_count(&self, counter_name: &str) -> u64 {
         *self
@@ -325,8 +415,9 @@ impl Counts {
     pub fn summary_for_restore(&self) -> String {
         // TODO: Just "index" might not be a good counter name when we both
         // read and write for incremental indexes.
-        format!(
+        let mut s = format!(
             "{:>12} MB in {} files, {} directories, {} symlinks.\n\
+             {:>12} existing entries unchanged, {} replaced, {} kept, {} deleted.\n\
              {:>12} MB/s output rate.\n\
              {:>12} MB after deduplication.\n\
              {:>12} MB in {} blocks after {:.1}x compression.\n\
@@ -336,6 +427,10 @@ impl Counts {
             self.get_count("file").separate_with_commas(),
             self.get_count("dir").separate_with_commas(),
             self.get_count("symlink").separate_with_commas(),
+            self.get_count("restore.unchanged").separate_with_commas(),
+            self.get_count("restore.replaced").separate_with_commas(),
+            self.get_count("restore.kept").separate_with_commas(),
+            self.get_count("restore.deleted").separate_with_commas(),
             (mbps_rate(
                 self.get_size("file.bytes").uncompressed,
                 self.elapsed_time()
@@ -348,13 +443,15 @@ impl Counts {
             (self.get_size("index").compressed / M).separate_with_commas(),
             self.get_count("index.hunk").separate_with_commas(),
             self.elapsed_time().as_secs(),
-        )
+        );
+        s.push_str(&self.summary_of_problems());
+        s
     }
 
     pub fn summary_for_backup(&self) -> String {
         // TODO: Just "index" might not be a good counter name when we both
         // read and write for incremental indexes.
-        format!(
+        let mut s = format!(
             "{:>12} MB in {} files, {} directories, {} symlinks.\n\
              {:>12} MB/s input rate.\n\
              {:>12} MB after deduplication.\n\
@@ -377,11 +474,13 @@ impl Counts {
             (self.get_size("index").compressed / M).separate_with_commas(),
             self.get_count("index.hunk").separate_with_commas(),
             self.elapsed_time().as_secs(),
-        )
+        );
+        s.push_str(&self.summary_of_problems());
+        s
     }
 
     pub fn summary_for_validate(&self) -> String {
-        format!(
+        let mut s = format!(
             "{:>12} MB in {} blocks.\n\
              {:>12} MB/s block validation rate.\n\
              {:>12} s elapsed.\n",
@@ -390,13 +489,17 @@ impl Counts {
             (mbps_rate(self.get_size("block").uncompressed, self.elapsed_time()) as u64)
                 .separate_with_commas(),
             self.elapsed_time().as_secs(),
-        )
+        );
+        s.push_str(&self.summary_of_problems());
+        s
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Report, Sizes};
+    use super::{Report, Sizes, MAX_KEPT_PROBLEMS};
+    use crate::ui::null::NullUI;
+    use crate::{Problem, ProblemKind};
 
     #[test]
     pub fn count() {
@@ -436,6 +539,35 @@ mod tests {
         );
     }
 
+    #[test]
+    pub fn merge_moves_problems() {
+        let r1 = Report::with_ui(Box::new(NullUI::new()));
+        let r2 = Report::with_ui(Box::new(NullUI::new()));
+        r1.problem(Problem::new(ProblemKind::BadBlock, "aaaa", "Bad block"));
+        r2.problem(Problem::new(ProblemKind::DamagedFile, "/a", "Can't read"));
+        r1.merge_from(&r2);
+        let cs = r1.borrow_counts();
+        assert_eq!(cs.count_problems(), 2);
+        assert_eq!(cs.problems().len(), 2);
+        assert_eq!(cs.problems()[1].path, "/a");
+        assert!(r2.borrow_counts().problems().is_empty());
+    }
+
+    #[test]
+    pub fn problems_are_counted_past_the_limit() {
+        let r = Report::with_ui(Box::new(NullUI::new()));
+        for i in 0..(MAX_KEPT_PROBLEMS + 10) {
+            r.problem(Problem::new(ProblemKind::BadBlock, i, "Bad block"));
+        }
+        let cs = r.borrow_counts();
+        assert_eq!(cs.problems().len(), MAX_KEPT_PROBLEMS);
+        assert_eq!(cs.count_problems(), MAX_KEPT_PROBLEMS as u64 + 10);
+        assert_eq!(
+            cs.count_problems_of_kind(ProblemKind::BadBlock),
+            MAX_KEPT_PROBLEMS as u64 + 10
+        );
+    }
+
     #[rustfmt::skip]
     #[test]
     pub fn display() {
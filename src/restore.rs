@@ -6,38 +6,222 @@ use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
 
+use filetime::FileTime;
+
 use super::entry::Entry;
-use super::io::require_empty_directory;
+use super::io::{require_empty_or_missing_directory, same_content};
 use super::*;
 
+/// What to do about entries that already exist in the restore destination.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overwrite {
+    /// Keep existing entries, even if they differ from the backup.
+    Never,
+    /// Replace existing files if their size or modification time differs from the backup.
+    IfChanged,
+    /// Replace existing files if their content differs from the backup.
+    ///
+    /// This reads the whole of both files, but doesn't rewrite identical files.
+    IfContentDiffers,
+    /// Replace all existing entries.
+    Always,
+}
+
+/// What restoring an entry does to the destination.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Action {
+    /// Nothing exists there yet.
+    Create,
+    /// Replace an existing entry.
+    Replace,
+    /// Keep an existing entry, which might be different.
+    Keep,
+    /// The existing entry is the same as the backup.
+    Unchanged,
+}
+
 /// A write-only tree on the filesystem, as a restore destination.
 #[derive(Debug)]
 pub struct RestoreTree {
     path: PathBuf,
     report: Report,
+    overwrite: Overwrite,
+    dry_run: bool,
 }
 
 impl RestoreTree {
     /// Create a RestoreTree.
     ///
-    /// The destination must either not yet exist, or be an empty directory.
+    /// The destination must either not yet exist, or be an empty directory. It's
+    /// created when the top directory of the tree is written, so a dry run doesn't
+    /// create it.
     pub fn create(path: &Path, report: &Report) -> Result<RestoreTree> {
-        require_empty_directory(path)?;
+        require_empty_or_missing_directory(path)?;
         Self::create_overwrite(path, report)
     }
 
     /// Create a RestoreTree, even if the destination directory is not empty.
+    ///
+    /// By default any existing entries are replaced.
     pub fn create_overwrite(path: &Path, report: &Report) -> Result<RestoreTree> {
         Ok(RestoreTree {
             path: path.to_path_buf(),
             report: report.clone(),
+            overwrite: Overwrite::Always,
+            dry_run: false,
         })
     }
 
+    /// Return a new RestoreTree that treats existing entries according to `overwrite`.
+    pub fn with_overwrite(self, overwrite: Overwrite) -> RestoreTree {
+        RestoreTree { overwrite, ..self }
+    }
+
+    /// Return a new RestoreTree that, if `dry_run` is true, only prints what it would
+    /// change, without changing anything.
+    pub fn with_dry_run(self, dry_run: bool) -> RestoreTree {
+        RestoreTree { dry_run, ..self }
+    }
+
+    /// Delete entries from the destination that aren't in `source`.
+    ///
+    /// Entries that were excluded from, or not included in, `source` are left alone.
+    pub fn delete_extra(&self, source: &StoredTree) -> Result<()> {
+        if !self.path.is_dir() {
+            return Ok(());
+        }
+        let dest = LiveTree::open(&self.path, &self.report)?
+            .with_excludes(source.excludes().clone())
+            .with_includes(source.includes().clone());
+        // Find everything before deleting anything, so that the tree isn't changed
+        // while it's being listed.
+        let mut extra = Vec::<Apath>::new();
+        for e in iter_merged_entries(source, &dest, &self.report)? {
+            let e = e?;
+            if e.kind == MergedEntryKind::RightOnly {
+                extra.push(e.apath);
+            }
+        }
+        let mut deleted_dirs = Vec::<Apath>::new();
+        for apath in extra {
+            if deleted_dirs
+                .iter()
+                .any(|d| apath.starts_with(&format!("{}/", d)))
+            {
+                // Already gone along with its directory.
+                continue;
+            }
+            self.report.increment("restore.deleted", 1);
+            self.print_action("delete", &apath);
+            let path = self.path.join(&apath[1..]);
+            deleted_dirs.push(apath);
+            if self.dry_run {
+                continue;
+            }
+            let result = match fs::symlink_metadata(&path) {
+                Ok(ref m) if m.is_dir() => fs::remove_dir_all(&path),
+                Ok(_) => fs::remove_file(&path),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                self.report.problem(
+                    Problem::for_path(ProblemKind::RestoreFailed, &path, "Error deleting")
+                        .with_error(e),
+                );
+            }
+        }
+        Ok(())
+    }
+
     fn entry_path(&self, entry: &Entry) -> PathBuf {
         // Remove initial slash so that the apath is relative to the destination.
         self.path.join(&entry.apath()[1..])
     }
+
+    /// Decide what to do about an entry, given whatever already exists at its path.
+    ///
+    /// `is_same` is called to check whether an existing entry of the same kind is
+    /// the same as the one in the backup.
+    fn plan<F>(&self, entry: &Entry, is_same: F) -> Result<Action>
+    where
+        F: FnOnce(&fs::Metadata) -> Result<bool>,
+    {
+        let metadata = match fs::symlink_metadata(self.entry_path(entry)) {
+            Ok(metadata) => metadata,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Action::Create),
+            Err(e) => return Err(e.into()),
+        };
+        let same_kind = match entry.kind() {
+            Kind::Dir => metadata.is_dir(),
+            Kind::File => metadata.is_file(),
+            Kind::Symlink => metadata.file_type().is_symlink(),
+            Kind::Unknown => false,
+        };
+        Ok(if same_kind && entry.kind() == Kind::Dir {
+            Action::Unchanged
+        } else {
+            match self.overwrite {
+                Overwrite::Never => Action::Keep,
+                Overwrite::Always => Action::Replace,
+                Overwrite::IfChanged | Overwrite::IfContentDiffers => {
+                    if same_kind && is_same(&metadata)? {
+                        Action::Unchanged
+                    } else {
+                        Action::Replace
+                    }
+                }
+            }
+        })
+    }
+
+    /// Count and describe an action, and if it's replacing an existing entry, move that
+    /// entry out of the way.
+    ///
+    /// Returns true if the entry should now be written.
+    fn start_action(&self, entry: &Entry, action: Action) -> Result<bool> {
+        match action {
+            Action::Unchanged => {
+                self.report.increment("restore.unchanged", 1);
+                return Ok(false);
+            }
+            Action::Keep => {
+                self.report.increment("restore.kept", 1);
+                self.print_action("keep", &entry.apath);
+                return Ok(false);
+            }
+            Action::Create => self.print_action("new", &entry.apath),
+            Action::Replace => {
+                self.report.increment("restore.replaced", 1);
+                self.print_action("replace", &entry.apath);
+                if !self.dry_run {
+                    let path = self.entry_path(entry);
+                    let metadata = fs::symlink_metadata(&path)?;
+                    if metadata.is_dir() {
+                        fs::remove_dir_all(&path)?;
+                    } else if entry.kind() != Kind::File || !metadata.is_file() {
+                        // Files are atomically replaced when they're written.
+                        fs::remove_file(&path)?;
+                    }
+                }
+            }
+        }
+        Ok(!self.dry_run)
+    }
+
+    /// In a dry run, print what would be done to an entry.
+    fn print_action(&self, action: &str, apath: &Apath) {
+        if self.dry_run {
+            self.report.print(&format!("{:<8} {}", action, apath));
+        }
+    }
+}
+
+/// True if an existing file has the same size and mtime as a stored entry.
+fn same_size_and_mtime(entry: &Entry, metadata: &fs::Metadata) -> bool {
+    entry.size() == Some(metadata.len())
+        && entry.unix_mtime().is_some()
+        && entry.unix_mtime()
+            == Some(FileTime::from_last_modification_time(metadata).unix_seconds() as u64)
 }
 
 impl tree::WriteTree for RestoreTree {
@@ -47,6 +231,10 @@ impl tree::WriteTree for RestoreTree {
     }
 
     fn write_dir(&mut self, entry: &Entry) -> Result<()> {
+        let action = self.plan(entry, |_| Ok(true))?;
+        if !self.start_action(entry, action)? {
+            return Ok(());
+        }
         self.report.increment("dir", 1);
         match fs::create_dir(self.entry_path(entry)) {
             Ok(_) => Ok(()),
@@ -57,11 +245,14 @@ impl tree::WriteTree for RestoreTree {
 
     fn write_file(&mut self, entry: &Entry, content: &mut dyn std::io::Read) -> Result<()> {
         // TODO: Restore permissions.
-        // TODO: Reset mtime: can probably use lutimes() but it's not in stable yet.
         // TODO: For restore, maybe not necessary to rename into place, and
         // we could just write directly.
+        if self.dry_run {
+            return Ok(());
+        }
         self.report.increment("file", 1);
-        let mut af = AtomicFile::new(&self.entry_path(entry))?;
+        let path = self.entry_path(entry);
+        let mut af = AtomicFile::new(&path)?;
         let bytes = std::io::copy(content, &mut af)?;
         self.report.increment_size(
             "file.bytes",
@@ -70,19 +261,52 @@ impl tree::WriteTree for RestoreTree {
                 compressed: 0,
             },
         );
-        af.close(&self.report)
+        af.close(&self.report)?;
+        if let Some(mtime) = entry.unix_mtime() {
+            filetime::set_file_mtime(&path, FileTime::from_unix_time(mtime as i64, 0))?;
+        }
+        Ok(())
+    }
+
+    /// Copy in the contents of a file, unless there's already a file there that
+    /// should be kept.
+    fn copy_file<R: ReadTree>(&mut self, entry: &Entry, from_tree: &R) -> Result<()> {
+        let path = self.entry_path(entry);
+        let action = self.plan(entry, |metadata| match self.overwrite {
+            Overwrite::IfContentDiffers => same_content(
+                &mut fs::File::open(&path)?,
+                &mut from_tree.file_contents(entry)?,
+            ),
+            _ => Ok(same_size_and_mtime(entry, metadata)),
+        })?;
+        if self.start_action(entry, action)? {
+            self.write_file(entry, &mut from_tree.file_contents(entry)?)
+        } else {
+            Ok(())
+        }
     }
 
     #[cfg(unix)]
     fn write_symlink(&mut self, entry: &Entry) -> Result<()> {
         use std::os::unix::fs as unix_fs;
+        let path = self.entry_path(entry);
+        let action = self.plan(entry, |_| {
+            Ok(fs::read_link(&path)?.to_str()
+                == entry.symlink_target().as_ref().map(String::as_str))
+        })?;
+        if !self.start_action(entry, action)? {
+            return Ok(());
+        }
         self.report.increment("symlink", 1);
         if let Some(ref target) = entry.symlink_target() {
             unix_fs::symlink(target, self.entry_path(entry))?;
         } else {
             // TODO: Treat as an error.
-            self.report
-                .problem(&format!("No target in symlink entry {}", entry.apath()));
+            self.report.problem(Problem::new(
+                ProblemKind::BadIndex,
+                entry.apath(),
+                "No target in symlink entry",
+            ));
         }
         Ok(())
     }
@@ -91,9 +315,10 @@ impl tree::WriteTree for RestoreTree {
     fn write_symlink(&mut self, entry: &Entry) -> Result<()> {
         // TODO: Add a test with a canned index containing a symlink, and expect
         // it cannot be restored on Windows and can be on Unix.
-        self.report.problem(&format!(
-            "Can't restore symlinks on non-Unix: {}",
-            entry.apath()
+        self.report.problem(Problem::new(
+            ProblemKind::UnsupportedKind,
+            entry.apath(),
+            "Can't restore symlinks on non-Unix",
         ));
         self.report.increment("skipped.unsupported_file_kind", 1);
         Ok(())
@@ -201,4 +426,157 @@ mod tests {
         assert_that(&dest.join("subdir").as_path()).is_a_directory();
         assert_eq!(2, restore_report.borrow_counts().get_count("file"));
     }
+
+    /// Restore the last version of `af` into `dest` with `overwrite`, and return the report.
+    fn restore_over(af: &ScratchArchive, dest: &TreeFixture, overwrite: Overwrite) -> Report {
+        let report = Report::new();
+        let archive = Archive::open(af.path(), &report).unwrap();
+        let st = StoredTree::open_last(&archive).unwrap();
+        let mut rt = RestoreTree::create_overwrite(dest.path(), &report)
+            .unwrap()
+            .with_overwrite(overwrite);
+        copy_tree(&st, &mut rt).unwrap();
+        report
+    }
+
+    #[test]
+    pub fn overwrite_never_keeps_existing() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let destdir = TreeFixture::new();
+        destdir.create_file_with_contents("hello", b"mine");
+
+        let report = restore_over(&af, &destdir, Overwrite::Never);
+        assert_eq!(report.get_count("restore.kept"), 1);
+        assert_eq!(report.get_count("file"), 2);
+        assert_eq!(fs::read(destdir.path().join("hello")).unwrap(), b"mine");
+        assert_that(&destdir.path().join("hello2")).is_a_file();
+    }
+
+    #[test]
+    pub fn overwrite_if_changed() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let destdir = TreeFixture::new();
+        let report = restore_over(&af, &destdir, Overwrite::IfChanged);
+        assert_eq!(report.get_count("file"), 3);
+
+        // Restored files get their original mtime, so restoring again changes nothing.
+        let report = restore_over(&af, &destdir, Overwrite::IfChanged);
+        assert_eq!(report.get_count("file"), 0);
+        assert_eq!(report.get_count("restore.replaced"), 0);
+
+        destdir.create_file_with_contents("hello", b"longer contents");
+        let report = restore_over(&af, &destdir, Overwrite::IfChanged);
+        assert_eq!(report.get_count("file"), 1);
+        assert_eq!(report.get_count("restore.replaced"), 1);
+        assert_eq!(fs::read(destdir.path().join("hello")).unwrap(), b"contents");
+    }
+
+    #[test]
+    pub fn overwrite_if_content_differs() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let destdir = TreeFixture::new();
+        // The same content as the backup, but a new mtime.
+        destdir.create_file("hello");
+        destdir.create_file_with_contents("hello2", b"contentz");
+
+        let report = restore_over(&af, &destdir, Overwrite::IfContentDiffers);
+        assert_eq!(report.get_count("restore.replaced"), 1);
+        assert_eq!(report.get_count("file"), 2);
+        assert_eq!(
+            fs::read(destdir.path().join("hello2")).unwrap(),
+            b"contents"
+        );
+    }
+
+    #[test]
+    pub fn replace_file_with_directory() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let destdir = TreeFixture::new();
+        destdir.create_file("subdir");
+
+        let report = restore_over(&af, &destdir, Overwrite::IfChanged);
+        assert_eq!(report.get_count("restore.replaced"), 1);
+        assert_that(&destdir.path().join("subdir/subfile")).is_a_file();
+    }
+
+    #[test]
+    pub fn delete_extra() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let destdir = TreeFixture::new();
+        destdir.create_file("extra");
+        destdir.create_file("extra.keep");
+        destdir.create_dir("extradir");
+        destdir.create_file("extradir/file");
+
+        let report = Report::new();
+        let archive = Archive::open(af.path(), &report).unwrap();
+        let st = StoredTree::open_last(&archive)
+            .unwrap()
+            .with_excludes(excludes::from_strings(&["/*.keep"]).unwrap());
+        let mut rt = RestoreTree::create_overwrite(destdir.path(), &report).unwrap();
+        copy_tree(&st, &mut rt).unwrap();
+        rt.delete_extra(&st).unwrap();
+
+        assert_eq!(report.get_count("restore.deleted"), 2);
+        let dest = destdir.path();
+        assert!(!dest.join("extra").exists());
+        assert!(!dest.join("extradir").exists());
+        assert_that(&dest.join("extra.keep")).is_a_file();
+        assert_that(&dest.join("hello")).is_a_file();
+    }
+
+    #[test]
+    pub fn dry_run_changes_nothing() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let destdir = TreeFixture::new();
+        destdir.create_file_with_contents("hello", b"mine");
+        destdir.create_file("extra");
+        destdir.create_dir("extradir");
+        destdir.create_file("extradir/file");
+
+        let report = Report::new();
+        let archive = Archive::open(af.path(), &report).unwrap();
+        let st = StoredTree::open_last(&archive).unwrap();
+        let mut rt = RestoreTree::create_overwrite(destdir.path(), &report)
+            .unwrap()
+            .with_dry_run(true);
+        copy_tree(&st, &mut rt).unwrap();
+        rt.delete_extra(&st).unwrap();
+
+        assert_eq!(report.get_count("file"), 0);
+        assert_eq!(report.get_count("restore.replaced"), 1);
+        assert_eq!(report.get_count("restore.deleted"), 2);
+        let dest = destdir.path();
+        assert_eq!(fs::read(dest.join("hello")).unwrap(), b"mine");
+        assert!(dest.join("extra").exists());
+        assert!(dest.join("extradir/file").exists());
+        assert!(!dest.join("hello2").exists());
+        assert!(!dest.join("subdir").exists());
+    }
+
+    #[test]
+    pub fn dry_run_does_not_create_destination() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let destdir = TreeFixture::new();
+        let dest = destdir.path().join("new");
+
+        let report = Report::new();
+        let archive = Archive::open(af.path(), &report).unwrap();
+        let st = StoredTree::open_last(&archive).unwrap();
+        let mut rt = RestoreTree::create(&dest, &report)
+            .unwrap()
+            .with_dry_run(true);
+        copy_tree(&st, &mut rt).unwrap();
+        rt.delete_extra(&st).unwrap();
+
+        assert_eq!(report.get_count("file"), 0);
+        assert!(!dest.exists());
+    }
 }
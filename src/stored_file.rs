@@ -1,8 +1,6 @@
 // Copyright 2017, 2018, 2019 Martin Pool.
 
 ///! Access a file stored in the archive.
-use rayon::prelude::*;
-
 use crate::*;
 
 /// Returns the contents of a file stored in the archive, as an iter of byte blocks.
@@ -17,6 +15,10 @@ pub struct StoredFile {
     addrs: Vec<blockdir::Address>,
 
     report: Report,
+
+    /// If set, blocks that can't be read are replaced by zeros, and reported as damage to
+    /// the file with this apath.
+    zero_fill: Option<Apath>,
 }
 
 impl StoredFile {
@@ -26,36 +28,48 @@ impl StoredFile {
             block_dir,
             addrs,
             report: report.clone(),
+            zero_fill: None,
         }
     }
 
-    /// Validate the stored file hash is as expected.
-    pub(crate) fn validate(&self) -> Result<()> {
-        // TODO: Perhaps the file should know its apath and hold its entry.
-        // TODO: Give a more specific message including the band and apath, if
-        // the content can't be loaded.
-        // TODO: Arguably we don't need to actually load the chunks here; it's
-        // enough to remember that all the blocks were loaded before.
-        self.block_range()
-            .unwrap()
-            .into_par_iter()
-            .map(|i| {
-                let c = self.read_block(i)?;
-                self.report.increment_work(c.len() as u64);
-                Ok(())
-            })
-            .find_any(Result::is_err)
-            .unwrap_or(Ok(()))
-    }
-
-    /// Open a cursor on this file that implements `std::io::Read`.
+    /// Read damaged or missing blocks as zeros of the same length, rather than failing.
+    ///
+    /// Each replaced block is reported as a `DamagedFile` problem for `apath`.
+    pub fn with_zero_fill(self, apath: Apath) -> StoredFile {
+        StoredFile {
+            zero_fill: Some(apath),
+            ..self
+        }
+    }
+
+    /// The length of the file, from its addresses, without reading any blocks.
+    pub fn len(&self) -> u64 {
+        self.addrs.iter().map(|a| a.len).sum()
+    }
+
+    /// True if the file has no content.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Open a cursor on this file that implements `std::io::Read` and `std::io::Seek`.
     pub(crate) fn into_read(self) -> ReadStoredFile {
+        let mut block_starts = Vec::with_capacity(self.addrs.len());
+        let mut len = 0;
+        for addr in &self.addrs {
+            block_starts.push(len);
+            len += addr.len;
+        }
         ReadStoredFile {
-            remaining_addrs: self.addrs.into_iter(),
+            addrs: self.addrs,
+            block_starts,
+            len,
+            pos: 0,
             buf: Vec::<u8>::new(),
-            buf_cursor: 0,
+            buf_start: 0,
             block_dir: self.block_dir,
             report: self.report,
+            zero_fill: self.zero_fill,
         }
     }
 }
@@ -70,46 +84,194 @@ impl ReadBlocks for StoredFile {
     }
 }
 
-/// Adapt a StoredFile to `std::io::Read`, which requires keeping a cursor position.
+/// Adapt a StoredFile to `std::io::Read` and `std::io::Seek`, which require keeping a cursor
+/// position.
+///
+/// Seeking only moves the cursor: the block holding the new position is read and
+/// decompressed when it's next read from, and reads within the same block reuse it.
 #[derive(Debug)]
 pub struct ReadStoredFile {
-    /// Block addresses remaining to be read.
-    remaining_addrs: std::vec::IntoIter<blockdir::Address>,
+    /// All addresses for this file.
+    addrs: Vec<blockdir::Address>,
+
+    /// The position in the file where each address's data starts.
+    block_starts: Vec<u64>,
+
+    /// Total length of the file.
+    len: u64,
 
-    // TODO: buf, buf_cursor, remaining_addrs all really belong in some kind of `Read` adapter, not
-    // the StoredFile itself.
-    /// Already-read but not yet returned data.
+    /// Position in the file of the next byte to return.
+    pos: u64,
+
+    /// The most recently read block's data.
     buf: Vec<u8>,
 
-    /// How far through buf has been returned?
-    buf_cursor: usize,
+    /// Position in the file of the start of `buf`.
+    buf_start: u64,
 
     block_dir: BlockDir,
     report: Report,
+    zero_fill: Option<Apath>,
+}
+
+impl ReadStoredFile {
+    /// Read the block containing `pos` into `buf`.
+    fn fill_buf(&mut self) -> std::io::Result<()> {
+        // The last address starting at or before pos; skipping any empty addresses there.
+        let i = self
+            .block_starts
+            .partition_point(|start| *start <= self.pos)
+            - 1;
+        let addr = &self.addrs[i];
+        self.buf = match (self.block_dir.get(addr, &self.report), &self.zero_fill) {
+            (Ok(buf), _) => buf,
+            // The length comes from the index, so check it's sane before allocating.
+            (Err(e), Some(apath)) if addr.len <= MAX_BLOCK_SIZE as u64 => {
+                self.report.problem(
+                    Problem::new(
+                        ProblemKind::DamagedFile,
+                        apath,
+                        "Filled unreadable part of file with zeros",
+                    )
+                    .with_error(e),
+                );
+                vec![0; addr.len as usize]
+            }
+            (Err(Error::IoError(e)), _) => return Err(e),
+            (Err(e), _) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+        };
+        self.buf_start = self.block_starts[i];
+        Ok(())
+    }
 }
 
 impl std::io::Read for ReadStoredFile {
     fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
         // TODO: Readahead n_cpus blocks into memory, using futures-cpupool or similar.
-        loop {
-            // If there's already buffered data, return as much of that as will fit.
-            let avail = self.buf.len() - self.buf_cursor;
-            if avail > 0 {
-                let s = std::cmp::min(out.len(), avail);
-                let r = &self.buf[self.buf_cursor..self.buf_cursor + s];
-                out[..s].copy_from_slice(r);
-                self.buf_cursor += s;
-                return Ok(s);
-            } else if let Some(addr) = self.remaining_addrs.next() {
-                // TODO: Handle errors nicely, but they need to convert to std::io::Error.
-                self.buf = self.block_dir.get(&addr, &self.report).unwrap();
-                self.buf_cursor = 0;
+        if self.pos >= self.len || out.is_empty() {
+            return Ok(0);
+        }
+        if self.pos < self.buf_start || self.pos >= self.buf_start + self.buf.len() as u64 {
             // TODO: Read directly into the caller's buffer, if it will fit. Requires changing
             // BlockDir::get to take a caller-provided buffer.
-            } else {
-                // No data buffered and no more to read, end of file.
-                return Ok(0);
+            self.fill_buf()?;
+        }
+        // Return as much of the buffered block as will fit.
+        let buf_cursor = (self.pos - self.buf_start) as usize;
+        let s = std::cmp::min(out.len(), self.buf.len() - buf_cursor);
+        out[..s].copy_from_slice(&self.buf[buf_cursor..buf_cursor + s]);
+        self.pos += s as u64;
+        Ok(s)
+    }
+}
+
+impl std::io::Seek for ReadStoredFile {
+    fn seek(&mut self, from: std::io::SeekFrom) -> std::io::Result<u64> {
+        use std::io::SeekFrom;
+        let new_pos = match from {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => checked_add_signed(self.len, delta),
+            SeekFrom::Current(delta) => checked_add_signed(self.pos, delta),
+        };
+        match new_pos {
+            Some(pos) => {
+                // As for files, seeking past the end is allowed, and reads there return nothing.
+                self.pos = pos;
+                Ok(pos)
             }
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Seek to a negative or overflowing position",
+            )),
         }
     }
 }
+
+fn checked_add_signed(base: u64, delta: i64) -> Option<u64> {
+    if delta >= 0 {
+        base.checked_add(delta as u64)
+    } else {
+        base.checked_sub(delta.wrapping_neg() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::io::SeekFrom;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// Store a file made of three blocks, "0123456789", "abcdefghij" and "ABCDEFGHIJ".
+    fn setup() -> (TempDir, StoredFile, Report) {
+        let testdir = TempDir::new().unwrap();
+        let mut block_dir = BlockDir::create(&testdir.path().join("d")).unwrap();
+        let report = Report::new();
+        let mut addrs = Vec::new();
+        for content in &["0123456789", "abcdefghij", "ABCDEFGHIJ"] {
+            addrs.extend(block_dir.store(&mut content.as_bytes(), &report).unwrap());
+        }
+        let sf = StoredFile::open(block_dir, addrs, &report);
+        (testdir, sf, report)
+    }
+
+    fn read_string(r: &mut ReadStoredFile, len: usize) -> String {
+        let mut buf = vec![0; len];
+        r.read_exact(&mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn read_across_blocks() {
+        let (_testdir, sf, _report) = setup();
+        assert_eq!(sf.len(), 30);
+        let mut s = String::new();
+        sf.into_read().read_to_string(&mut s).unwrap();
+        assert_eq!(s, "0123456789abcdefghijABCDEFGHIJ");
+    }
+
+    #[test]
+    fn seek_and_read() {
+        let (_testdir, sf, report) = setup();
+        let mut r = sf.into_read();
+        let reads_before = report.get_count("block.read");
+
+        assert_eq!(r.seek(SeekFrom::Start(25)).unwrap(), 25);
+        assert_eq!(read_string(&mut r, 3), "FGH");
+        // Only the block holding the data was read.
+        assert_eq!(report.get_count("block.read"), reads_before + 1);
+
+        assert_eq!(r.seek(SeekFrom::Current(-5)).unwrap(), 23);
+        assert_eq!(read_string(&mut r, 2), "DE");
+        // Still within the same block.
+        assert_eq!(report.get_count("block.read"), reads_before + 1);
+
+        assert_eq!(r.seek(SeekFrom::End(-22)).unwrap(), 8);
+        assert_eq!(read_string(&mut r, 4), "89ab");
+
+        assert_eq!(r.seek(SeekFrom::Start(0)).unwrap(), 0);
+        assert_eq!(read_string(&mut r, 1), "0");
+    }
+
+    #[test]
+    fn seek_past_end() {
+        let (_testdir, sf, _report) = setup();
+        let mut r = sf.into_read();
+        assert_eq!(r.seek(SeekFrom::End(10)).unwrap(), 40);
+        let mut buf = [0; 4];
+        assert_eq!(r.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn seek_before_start_is_an_error() {
+        let (_testdir, sf, _report) = setup();
+        let mut r = sf.into_read();
+        r.seek(SeekFrom::Start(5)).unwrap();
+        let err = r.seek(SeekFrom::Current(-6)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        // The position is unchanged.
+        assert_eq!(r.stream_position().unwrap(), 5);
+    }
+}
@@ -8,10 +8,8 @@
 //! across incremental backups, hiding from the caller that data may be distributed across
 //! multiple index files, bands, and blocks.
 
-use rayon::iter::ParallelBridge;
-use rayon::prelude::*;
+use std::collections::HashMap;
 
-use crate::stored_file::{ReadStoredFile, StoredFile};
 use crate::*;
 
 /// Read index and file contents for a version stored in the archive.
@@ -20,6 +18,8 @@ pub struct StoredTree {
     archive: Archive,
     band: Band,
     excludes: GlobSet,
+    includes: Includes,
+    zero_fill: bool,
 }
 
 impl StoredTree {
@@ -30,6 +30,8 @@ impl StoredTree {
             archive: archive.clone(),
             band,
             excludes: excludes::excludes_nothing(),
+            includes: Includes::everything(),
+            zero_fill: false,
         })
     }
 
@@ -45,6 +47,8 @@ impl StoredTree {
             archive: archive.clone(),
             band,
             excludes: excludes::excludes_nothing(),
+            includes: Includes::everything(),
+            zero_fill: false,
         })
     }
 
@@ -58,6 +62,8 @@ impl StoredTree {
             archive: archive.clone(),
             band,
             excludes: excludes::excludes_nothing(),
+            includes: Includes::everything(),
+            zero_fill: false,
         })
     }
 
@@ -65,6 +71,32 @@ impl StoredTree {
         StoredTree { excludes, ..self }
     }
 
+    /// Return a new StoredTree which when listed will contain only entries selected by
+    /// `includes`, and the directories above them.
+    ///
+    /// Selecting only a few subtrees is efficient, even in a large tree.
+    pub fn with_includes(self, includes: Includes) -> StoredTree {
+        StoredTree { includes, ..self }
+    }
+
+    /// Return a new StoredTree which reads damaged or missing blocks in files as zeros,
+    /// reporting a `DamagedFile` problem for each, rather than failing to read the file.
+    ///
+    /// This salvages as much as possible of a damaged file.
+    pub fn with_zero_fill(self, zero_fill: bool) -> StoredTree {
+        StoredTree { zero_fill, ..self }
+    }
+
+    /// Return the patterns excluded from this tree.
+    pub fn excludes(&self) -> &GlobSet {
+        &self.excludes
+    }
+
+    /// Return the selection of entries included in this tree.
+    pub fn includes(&self) -> &Includes {
+        &self.includes
+    }
+
     pub fn band(&self) -> &Band {
         &self.band
     }
@@ -77,39 +109,89 @@ impl StoredTree {
         self.band.is_closed()
     }
 
-    pub fn validate(&self) -> Result<()> {
+    /// Check that every file in this tree refers only to blocks that are present, and
+    /// lie within them; report any problems to the Report.
+    ///
+    /// `blocks` has the hash of every block in the archive known to be good, and its
+    /// uncompressed length if that's known.
+    pub fn validate(&self, blocks: &HashMap<String, Option<u64>>) -> Result<()> {
         let report = self.report();
         report.set_phase(format!("Check tree {}", self.band().id()));
-        self.iter_entries(self.report())?
-            .map(Result::unwrap)
-            .filter(|e| e.kind() == Kind::File)
-            .par_bridge()
-            .map(|e| self.validate_one_entry(&e))
-            .inspect(|e| {
-                if let Err(e) = e {
-                    report.problem(&e.to_string());
-                }
-            })
-            .find_any(Result::is_err)
-            .unwrap_or(Ok(()))
-    }
-
-    fn validate_one_entry(&self, e: &Entry) -> Result<()> {
-        self.report().start_entry(e);
-        self.open_stored_file(&e)?.validate()
+        // Damage to the index itself is reported by `ReadIndex::validate`, so isn't
+        // reported again while reading it here.
+        let index_report = Report::with_ui(Box::new(ui::null::NullUI::new()));
+        for entry in self.iter_entries(&index_report)? {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            };
+            if entry.kind() != Kind::File {
+                continue;
+            }
+            report.start_entry(&entry);
+            for addr in &entry.addrs {
+                let message = match blocks.get(&addr.hash) {
+                    None => format!("Block {} is missing or damaged", addr.hash),
+                    Some(Some(len)) if !addr.fits_in(*len) => {
+                        format!("Address extends past the end of block {}", addr.hash)
+                    }
+                    Some(_) => continue,
+                };
+                report.problem(Problem::new(
+                    ProblemKind::DamagedFile,
+                    entry.apath(),
+                    &message,
+                ));
+                break;
+            }
+        }
+        Ok(())
     }
 
     /// Open a file stored within this tree.
     fn open_stored_file(&self, entry: &Entry) -> Result<StoredFile> {
-        Ok(StoredFile::open(
+        let stored_file = StoredFile::open(
             self.archive.block_dir().clone(),
             entry.addrs.clone(),
             self.report(),
-        ))
+        );
+        if self.zero_fill {
+            Ok(stored_file.with_zero_fill(entry.apath.clone()))
+        } else {
+            Ok(stored_file)
+        }
+    }
+
+    /// Find the entry for one apath, or None if it's not in this tree.
+    ///
+    /// Only the parts of the index that might contain it are read.
+    pub fn find_entry(&self, apath: &Apath) -> Result<Option<Entry>> {
+        let it = self
+            .band
+            .index()
+            .iter(&self.excludes, self.report())?
+            .with_includes(Includes::from_subtrees(vec![apath.clone()]));
+        for entry in it {
+            let entry = entry?;
+            if entry.apath == *apath {
+                return Ok(Some(entry));
+            } else if entry.apath > *apath {
+                break;
+            }
+        }
+        Ok(None)
     }
 
-    // TODO: Perhaps add a way to open a file by name, bearing in mind this might be slow to
-    // call if it reads the whole index.
+    /// Open a file stored in this tree, by its apath, to read its contents.
+    pub fn open_file(&self, apath: &Apath) -> Result<ReadStoredFile> {
+        match self.find_entry(apath)? {
+            None => Err(Error::ApathNotFound(apath.clone())),
+            Some(ref entry) if entry.kind() != Kind::File => {
+                Err(Error::NotAStoredFile(apath.clone()))
+            }
+            Some(entry) => self.file_contents(&entry),
+        }
+    }
 }
 
 impl ReadTree for StoredTree {
@@ -118,7 +200,11 @@ impl ReadTree for StoredTree {
 
     /// Return an iter of index entries in this stored tree.
     fn iter_entries(&self, report: &Report) -> Result<index::Iter> {
-        self.band.index().iter(&self.excludes, report)
+        Ok(self
+            .band
+            .index()
+            .iter(&self.excludes, report)?
+            .with_includes(self.includes.clone()))
     }
 
     fn file_contents(&self, entry: &Entry) -> Result<Self::R> {
@@ -171,6 +257,43 @@ mod test {
         assert_eq!(expected, names);
     }
 
+    #[test]
+    pub fn open_file_by_apath() {
+        use std::io::Read;
+
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let st = StoredTree::open_last(&af).unwrap();
+
+        let entry = st
+            .find_entry(&Apath::from("/subdir/subfile"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.kind(), Kind::File);
+        assert!(st.find_entry(&Apath::from("/nothing")).unwrap().is_none());
+        assert!(st.find_entry(&Apath::from("/subdir/a")).unwrap().is_none());
+
+        let mut content = String::new();
+        st.open_file(&Apath::from("/subdir/subfile"))
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "contents");
+
+        assert_eq!(
+            st.open_file(&Apath::from("/subdir"))
+                .unwrap_err()
+                .to_string(),
+            "Not a file in the archive: /subdir"
+        );
+        assert_eq!(
+            st.open_file(&Apath::from("/nothing"))
+                .unwrap_err()
+                .to_string(),
+            "Not found in the archive: /nothing"
+        );
+    }
+
     #[test]
     pub fn cant_open_no_versions() {
         let af = ScratchArchive::new();
@@ -0,0 +1,833 @@
+// Conserve backup system.
+// Copyright 2019 Martin Pool.
+
+//! Read a tar stream as a tree, so that it can be backed up like a directory.
+//!
+//! Tar entries can be in any order, but a tree must be read in apath order, and a stream
+//! such as stdin can only be read once. So the whole stream is read when the tree is
+//! opened: file contents are spooled into a temporary file, and the entries are sorted
+//! by apath in runs that are spilled to temporary files, then merged.
+//!
+//! Only hard links, the files they link to, and entries that have others inside them
+//! without being directories, are held in memory until the whole stream is sorted.
+
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter, SeekFrom};
+use std::iter::Peekable;
+use std::path::{Component, Path};
+
+use globset::GlobSet;
+use tempfile::NamedTempFile;
+
+use crate::excludes::MetadataExcludes;
+use crate::*;
+
+/// Number of entries sorted in memory before they're spilled to a temporary file.
+const RUN_LEN: usize = 100_000;
+
+/// An entry from the tar stream, as it's sorted.
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    /// Position of the entry in the stream, counting from 1, or 0 for a directory that's
+    /// recorded only because something is inside it.
+    position: u64,
+
+    entry: Entry,
+
+    /// For files, the position and length of their content in the spool.
+    content: Option<(u64, u64)>,
+
+    /// For hard links, the apath of the file linked to.
+    link: Option<Apath>,
+}
+
+impl Record {
+    /// A directory that's recorded only because something is inside it.
+    fn missing_dir(apath: &str) -> Record {
+        Record {
+            position: 0,
+            entry: Entry {
+                apath: Apath::from(apath),
+                kind: Kind::Dir,
+                mtime: None,
+                addrs: vec![],
+                target: None,
+                size: None,
+            },
+            content: None,
+            link: None,
+        }
+    }
+
+    /// Return the entry, with its size for files, which isn't serialized.
+    fn into_entry(self) -> Entry {
+        let mut entry = self.entry;
+        if let Some((_start, len)) = self.content {
+            entry.size = Some(len);
+        }
+        entry
+    }
+}
+
+type Records =
+    serde_json::StreamDeserializer<'static, serde_json::de::IoRead<BufReader<File>>, Record>;
+
+/// Read records, one per line, from the start of `file`.
+fn read_records(file: &File) -> Result<Records> {
+    let mut file = file.try_clone()?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(serde_json::Deserializer::from_reader(BufReader::new(file)).into_iter())
+}
+
+/// Sort records by apath and then position, and write them to a new temporary file.
+fn write_run(records: &mut Vec<Record>) -> Result<File> {
+    records
+        .sort_unstable_by(|a, b| (&a.entry.apath, a.position).cmp(&(&b.entry.apath, b.position)));
+    let mut file = tempfile::tempfile()?;
+    {
+        let mut w = BufWriter::new(&mut file);
+        for record in records.drain(..) {
+            serde_json::to_writer(&mut w, &record)?;
+            w.write_all(b"\n")?;
+        }
+        w.flush()?;
+    }
+    Ok(file)
+}
+
+/// Merge sorted runs of records into one sequence, ordered by apath and then position.
+struct Merge {
+    runs: Vec<Records>,
+    /// The next record from each run, if it has any more.
+    next: Vec<Option<Record>>,
+    /// Keys of the next records, so that the least is popped first.
+    heads: BinaryHeap<Reverse<(Apath, u64, usize)>>,
+}
+
+impl Merge {
+    fn new(runs: &[File]) -> Result<Merge> {
+        let mut merge = Merge {
+            runs: Vec::with_capacity(runs.len()),
+            next: Vec::with_capacity(runs.len()),
+            heads: BinaryHeap::with_capacity(runs.len()),
+        };
+        for (i, run) in runs.iter().enumerate() {
+            merge.runs.push(read_records(run)?);
+            merge.next.push(None);
+            merge.pull(i)?;
+        }
+        Ok(merge)
+    }
+
+    /// Read the next record from run `i`.
+    fn pull(&mut self, i: usize) -> Result<()> {
+        if let Some(record) = self.runs[i].next().transpose()? {
+            self.heads
+                .push(Reverse((record.entry.apath.clone(), record.position, i)));
+            self.next[i] = Some(record);
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for Merge {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Result<Record>> {
+        let Reverse((_apath, _position, i)) = self.heads.pop()?;
+        let record = self.next[i].take().unwrap();
+        match self.pull(i) {
+            Ok(()) => Some(Ok(record)),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Return all the records for the next apath, in stream order.
+fn next_group(merge: &mut Peekable<Merge>) -> Result<Vec<Record>> {
+    let mut group = Vec::<Record>::new();
+    loop {
+        match merge.peek() {
+            Some(Ok(record)) if group.is_empty() || record.entry.apath == group[0].entry.apath => {
+                group.push(merge.next().unwrap()?)
+            }
+            Some(Err(_)) => return Err(merge.next().unwrap().unwrap_err()),
+            _ => return Ok(group),
+        }
+    }
+}
+
+/// Find the content of `target` as it was in the stream before `position`.
+///
+/// Links that can't be resolved are skipped, so they don't replace an earlier file.
+fn resolve_link(
+    versions: &HashMap<String, Vec<Record>>,
+    target: &str,
+    position: u64,
+) -> Option<(u64, u64)> {
+    for record in versions.get(target)?.iter().rev() {
+        if record.position == 0 || record.position >= position {
+            continue;
+        }
+        match &record.link {
+            Some(next) => {
+                if let Some(content) = resolve_link(versions, next, record.position) {
+                    return Some(content);
+                }
+            }
+            None => return record.content,
+        }
+    }
+    None
+}
+
+/// Merge the runs into the entries of the tree, and write their records to `out` in apath
+/// order.
+///
+/// Returns the number of entries.
+fn write_tree(
+    runs: &[File],
+    versions: &HashMap<String, Vec<Record>>,
+    out: &mut dyn Write,
+    report: &Report,
+) -> Result<u64> {
+    let mut w = BufWriter::new(out);
+    let mut len = 0;
+    // Positions of entries that aren't directories, but that have other entries inside
+    // them.
+    let mut non_dir_parents = HashMap::<String, u64>::new();
+    let mut merge = Merge::new(runs)?.peekable();
+    loop {
+        let group = next_group(&mut merge)?;
+        let apath = match group.first() {
+            Some(record) => record.entry.apath.clone(),
+            None => break,
+        };
+        let mut has_children = false;
+        let mut latest = None;
+        for mut record in group {
+            if record.position == 0 {
+                has_children = true;
+                continue;
+            }
+            if let Some(target) = &record.link {
+                match resolve_link(versions, target, record.position) {
+                    Some(content) => record.content = Some(content),
+                    None => {
+                        report.problem(Problem::new(
+                            ProblemKind::SourceRead,
+                            &apath,
+                            "Skipped hard link to a file not earlier in the tar",
+                        ));
+                        continue;
+                    }
+                }
+            }
+            latest = Some(record);
+        }
+        let record = match latest {
+            Some(record) => record,
+            None if has_children => Record::missing_dir(&apath),
+            None => continue,
+        };
+        if has_children && record.entry.kind != Kind::Dir {
+            non_dir_parents.insert(apath.to_string(), record.position);
+        }
+        // If the non-directory came later in the stream, it replaced a directory and
+        // everything in it. Otherwise the entry is skipped and reported.
+        if let Some(parent_position) = non_dir_parent(&apath, &non_dir_parents) {
+            if parent_position < record.position {
+                report.problem(Problem::new(
+                    ProblemKind::SourceRead,
+                    &apath,
+                    "Skipped tar entry inside something that isn't a directory",
+                ));
+            }
+            continue;
+        }
+        serde_json::to_writer(&mut w, &record)?;
+        w.write_all(b"\n")?;
+        len += 1;
+    }
+    w.flush()?;
+    Ok(len)
+}
+
+/// Return the position of the nearest entry above `apath` that isn't a directory, if any.
+fn non_dir_parent(apath: &str, non_dir_parents: &HashMap<String, u64>) -> Option<u64> {
+    let mut parent = apath;
+    while parent != "/" {
+        parent = &apath[..parent.rfind('/').unwrap().max(1)];
+        if let Some(position) = non_dir_parents.get(parent) {
+            return Some(*position);
+        }
+    }
+    None
+}
+
+/// Where `TarTree::file_contents` has read up to in the sorted entries.
+struct ContentCursor {
+    records: Records,
+    last: Option<Record>,
+}
+
+/// The contents of a tar stream, as a tree.
+pub struct TarTree {
+    /// Records for the entries of the tree, in apath order.
+    sorted: NamedTempFile,
+    len: u64,
+
+    /// The contents of all files, one after another.
+    spool: NamedTempFile,
+
+    /// Entries are normally read in order, so file content is found by reading forward
+    /// from the last file.
+    cursor: RefCell<Option<ContentCursor>>,
+
+    report: Report,
+    excludes: GlobSet,
+    metadata_excludes: MetadataExcludes,
+}
+
+impl TarTree {
+    /// Read a whole tar stream into a tree.
+    ///
+    /// Entries whose paths can't be stored, or that are hard links to files not earlier in
+    /// the stream, are skipped and reported as problems. Later entries replace earlier
+    /// ones with the same path, and if a directory is replaced by another kind of entry,
+    /// whatever was earlier inside it is removed too. Entries inside something that isn't
+    /// a directory are skipped and reported. Directories that are missing from the stream,
+    /// but that contain other entries, are added with no modification time.
+    pub fn read<R: Read>(from: R, report: &Report) -> Result<TarTree> {
+        TarTree::read_in_runs(from, report, RUN_LEN)
+    }
+
+    fn read_in_runs<R: Read>(from: R, report: &Report, run_len: usize) -> Result<TarTree> {
+        report.set_phase("Read tar");
+        let mut spool = NamedTempFile::new()?;
+        let mut spool_len = 0u64;
+        let mut runs = Vec::new();
+        let mut records = Vec::new();
+        let mut link_targets = HashSet::new();
+        // Every directory containing an entry is recorded, so that missing ones can be
+        // added. Entries are usually grouped by directory, so they're only recorded when
+        // the parent changes.
+        let mut last_parent = String::new();
+        records.push(Record::missing_dir("/"));
+        let mut tar = tar::Archive::new(from);
+        for (i, tar_entry) in tar.entries()?.enumerate() {
+            let mut tar_entry = tar_entry?;
+            let tar_path = tar_entry.path()?.into_owned();
+            let apath = match apath_from_tar_path(&tar_path) {
+                Some(apath) => apath,
+                None => {
+                    report.problem(Problem::for_path(
+                        ProblemKind::UndecodableName,
+                        &tar_path,
+                        "Skipped tar entry whose path can't be stored",
+                    ));
+                    continue;
+                }
+            };
+            let header = tar_entry.header();
+            let mut record = Record {
+                position: i as u64 + 1,
+                entry: Entry {
+                    apath: apath.clone(),
+                    kind: Kind::Unknown,
+                    mtime: header.mtime().ok(),
+                    addrs: vec![],
+                    target: None,
+                    size: None,
+                },
+                content: None,
+                link: None,
+            };
+            match header.entry_type() {
+                tar::EntryType::Regular | tar::EntryType::Continuous => {
+                    let len = io::copy(&mut tar_entry, &mut spool)?;
+                    record.content = Some((spool_len, len));
+                    spool_len += len;
+                    record.entry.kind = Kind::File;
+                }
+                tar::EntryType::Link => {
+                    match tar_entry.link_name()?.and_then(|p| apath_from_tar_path(&p)) {
+                        Some(target) => {
+                            link_targets.insert(target.to_string());
+                            record.link = Some(target);
+                            record.entry.kind = Kind::File;
+                        }
+                        None => {
+                            report.problem(Problem::new(
+                                ProblemKind::SourceRead,
+                                &apath,
+                                "Skipped hard link to a file not earlier in the tar",
+                            ));
+                            continue;
+                        }
+                    }
+                }
+                tar::EntryType::Directory => record.entry.kind = Kind::Dir,
+                tar::EntryType::Symlink => {
+                    record.entry.kind = Kind::Symlink;
+                    match tar_entry.link_name()?.as_ref().and_then(|p| p.to_str()) {
+                        Some(target) => record.entry.target = Some(target.to_owned()),
+                        None => {
+                            report.problem(Problem::new(
+                                ProblemKind::UndecodableName,
+                                &apath,
+                                "Skipped symlink whose target can't be decoded",
+                            ));
+                            continue;
+                        }
+                    }
+                }
+                // Devices, fifos and so on are passed on, to be reported as unsupported.
+                _ => (),
+            }
+            let parent = &apath[..apath.rfind('/').unwrap().max(1)];
+            if &apath[..] != "/" && parent != last_parent {
+                last_parent = parent.to_owned();
+                for (end, _) in apath.match_indices('/').skip(1) {
+                    records.push(Record::missing_dir(&apath[..end]));
+                }
+            }
+            records.push(record);
+            if records.len() >= run_len {
+                runs.push(write_run(&mut records)?);
+            }
+        }
+        spool.flush()?;
+        if !records.is_empty() {
+            runs.push(write_run(&mut records)?);
+        }
+
+        // Hard links can be to files anywhere in the tree, so first find every version of
+        // the files they link to.
+        let mut versions = HashMap::<String, Vec<Record>>::new();
+        if !link_targets.is_empty() {
+            for record in Merge::new(&runs)? {
+                let record = record?;
+                if link_targets.contains(&record.entry.apath[..]) {
+                    versions
+                        .entry(record.entry.apath.to_string())
+                        .or_default()
+                        .push(record);
+                }
+            }
+        }
+
+        let mut sorted = NamedTempFile::new()?;
+        let len = write_tree(&runs, &versions, &mut sorted, report)?;
+        report.clear_phase();
+        Ok(TarTree {
+            sorted,
+            len,
+            spool,
+            cursor: RefCell::new(None),
+            report: report.clone(),
+            excludes: excludes::excludes_nothing(),
+            metadata_excludes: MetadataExcludes::nothing(),
+        })
+    }
+
+    /// Return a new TarTree which when listed will skip entries matching `excludes`, and
+    /// everything inside excluded directories.
+    ///
+    /// This replaces any previous exclusions.
+    pub fn with_excludes(self, excludes: GlobSet) -> TarTree {
+        TarTree { excludes, ..self }
+    }
+
+    /// Return a new TarTree which when listed will skip entries by their size,
+    /// modification time, or kind.
+    ///
+    /// This replaces any previous metadata exclusions.
+    pub fn with_metadata_excludes(self, metadata_excludes: MetadataExcludes) -> TarTree {
+        TarTree {
+            metadata_excludes,
+            ..self
+        }
+    }
+
+    /// Find the position and length of a file's content in the spool.
+    fn find_content(&self, apath: &Apath) -> Result<Option<(u64, u64)>> {
+        let mut cursor = self.cursor.borrow_mut();
+        let passed = match &*cursor {
+            Some(ContentCursor {
+                last: Some(last), ..
+            }) => last.entry.apath > *apath,
+            Some(_) => false,
+            None => true,
+        };
+        if passed {
+            *cursor = Some(ContentCursor {
+                records: read_records(self.sorted.as_file())?,
+                last: None,
+            });
+        }
+        let cursor = cursor.as_mut().unwrap();
+        loop {
+            if let Some(last) = &cursor.last {
+                if last.entry.apath == *apath {
+                    return Ok(last.content);
+                } else if last.entry.apath > *apath {
+                    return Ok(None);
+                }
+            }
+            match cursor.records.next() {
+                Some(record) => cursor.last = Some(record?),
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+/// Convert a path from a tar header to an apath.
+///
+/// Leading `/` and `.` components are removed. Returns None for paths containing `..`, or
+/// that aren't valid UTF-8.
+fn apath_from_tar_path(path: &Path) -> Option<Apath> {
+    let mut apath = String::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => {
+                apath.push('/');
+                apath.push_str(name.to_str()?);
+            }
+            Component::RootDir | Component::CurDir => (),
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    if apath.is_empty() {
+        apath.push('/');
+    }
+    if Apath::is_valid(&apath) {
+        Some(Apath::from(apath))
+    } else {
+        None
+    }
+}
+
+/// Iterate the entries of a `TarTree`, in apath order.
+pub struct Iter {
+    records: Records,
+    report: Report,
+    excludes: GlobSet,
+    metadata_excludes: MetadataExcludes,
+    excluded_dirs: HashSet<String>,
+}
+
+impl Iter {
+    /// True if `entry` is excluded, or inside an excluded directory.
+    fn is_excluded(&mut self, entry: &Entry) -> bool {
+        let apath = &entry.apath;
+        let in_excluded_dir = apath
+            .match_indices('/')
+            .skip(1)
+            .any(|(i, _)| self.excluded_dirs.contains(&apath[..i]));
+        if in_excluded_dir || self.excludes.is_match(&apath[..]) {
+            match entry.kind {
+                Kind::File => self.report.increment("skipped.excluded.files", 1),
+                Kind::Dir => self.report.increment("skipped.excluded.directories", 1),
+                Kind::Symlink => self.report.increment("skipped.excluded.symlinks", 1),
+                Kind::Unknown => (),
+            }
+        } else if let Some(counter) = self.metadata_excludes.check(entry) {
+            self.report.increment(counter, 1);
+        } else {
+            return false;
+        }
+        if entry.kind == Kind::Dir {
+            self.excluded_dirs.insert(apath.to_string());
+        }
+        true
+    }
+}
+
+impl Iterator for Iter {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Result<Entry>> {
+        loop {
+            let entry = match self.records.next()? {
+                Ok(record) => record.into_entry(),
+                Err(e) => return Some(Err(e.into())),
+            };
+            if !self.is_excluded(&entry) {
+                self.report.increment("source.selected", 1);
+                return Some(Ok(entry));
+            }
+        }
+    }
+}
+
+impl ReadTree for TarTree {
+    type I = Iter;
+    type R = io::Take<File>;
+
+    /// Return the entries in apath order, without those that are excluded.
+    fn iter_entries(&self, report: &Report) -> Result<Self::I> {
+        Ok(Iter {
+            records: read_records(self.sorted.as_file())?,
+            report: report.clone(),
+            excludes: self.excludes.clone(),
+            metadata_excludes: self.metadata_excludes.clone(),
+            excluded_dirs: HashSet::new(),
+        })
+    }
+
+    fn file_contents(&self, entry: &Entry) -> Result<Self::R> {
+        let (start, len) = self
+            .find_content(&entry.apath)?
+            .ok_or_else(|| Error::NotAFile(entry.apath[..].into()))?;
+        // Open the spool again so that this has its own position.
+        let mut f = File::open(self.spool.path())?;
+        f.seek(SeekFrom::Start(start))?;
+        Ok(f.take(len))
+    }
+
+    fn estimate_count(&self) -> Result<u64> {
+        Ok(self.len)
+    }
+}
+
+impl HasReport for TarTree {
+    fn report(&self) -> &Report {
+        &self.report
+    }
+}
+
+impl fmt::Debug for TarTree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TarTree")
+            .field("entries", &self.len)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::*;
+
+    fn header(entry_type: tar::EntryType, size: u64, mtime: u64) -> tar::Header {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_size(size);
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+        header
+    }
+
+    /// Make a tar stream with entries out of order, missing directories, a hard link, a
+    /// symlink, a replaced file, and an unsafe path.
+    fn make_tar() -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut add_file = |path: &str, content: &[u8], mtime| {
+            builder
+                .append_data(
+                    &mut header(tar::EntryType::Regular, content.len() as u64, mtime),
+                    path,
+                    content,
+                )
+                .unwrap();
+        };
+        add_file("./zzz", b"last", 1000);
+        add_file("./sub/deeper/file", b"deep", 1001);
+        add_file("./aaa", b"old", 1002);
+        add_file("./aaa", b"first", 1003);
+        let mut builder = builder;
+        builder
+            .append_data(
+                &mut header(tar::EntryType::Directory, 0, 1004),
+                "./sub/",
+                io::empty(),
+            )
+            .unwrap();
+        builder
+            .append_link(
+                &mut header(tar::EntryType::Link, 0, 1005),
+                "./sub/hardlink",
+                "./zzz",
+            )
+            .unwrap();
+        builder
+            .append_link(
+                &mut header(tar::EntryType::Symlink, 0, 1006),
+                "./link",
+                "aaa",
+            )
+            .unwrap();
+        // Written directly, because tar::Builder refuses paths containing "..".
+        let mut h = header(tar::EntryType::Regular, 4, 1007);
+        h.as_old_mut().name[..13].copy_from_slice(b"../escape.txt");
+        h.set_cksum();
+        builder.append(&h, &b"evil"[..]).unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn read_tar_in_apath_order() {
+        let report = Report::new();
+        let tree = TarTree::read(make_tar().as_slice(), &report).unwrap();
+        assert_eq!(
+            report
+                .borrow_counts()
+                .count_problems_of_kind(ProblemKind::UndecodableName),
+            1
+        );
+        let entries: Vec<Entry> = tree
+            .iter_entries(&report)
+            .unwrap()
+            .map(|e| e.unwrap())
+            .collect();
+        let apaths: Vec<&str> = entries.iter().map(|e| &e.apath[..]).collect();
+        assert_eq!(
+            apaths,
+            [
+                "/",
+                "/aaa",
+                "/link",
+                "/sub",
+                "/zzz",
+                "/sub/deeper",
+                "/sub/hardlink",
+                "/sub/deeper/file"
+            ]
+        );
+        assert_eq!(entries[0].kind, Kind::Dir);
+        assert_eq!(entries[0].mtime, None);
+        assert_eq!(entries[2].kind, Kind::Symlink);
+        assert_eq!(entries[2].target.as_ref().unwrap(), "aaa");
+        assert_eq!(entries[3].mtime, Some(1004));
+        assert_eq!(entries[5].kind, Kind::Dir);
+
+        let mut content = String::new();
+        tree.file_contents(&entries[1])
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "first");
+        assert_eq!(entries[1].mtime, Some(1003));
+        let mut content = String::new();
+        tree.file_contents(&entries[6])
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "last");
+    }
+
+    /// Entries and their contents are the same however many runs they're sorted in.
+    #[test]
+    fn read_in_small_runs() {
+        let read_all = |run_len| {
+            let report = Report::new();
+            let tree = TarTree::read_in_runs(make_tar().as_slice(), &report, run_len).unwrap();
+            let entries: Vec<(Entry, String)> = tree
+                .iter_entries(&report)
+                .unwrap()
+                .map(|e| {
+                    let e = e.unwrap();
+                    let mut content = String::new();
+                    if e.kind == Kind::File {
+                        tree.file_contents(&e)
+                            .unwrap()
+                            .read_to_string(&mut content)
+                            .unwrap();
+                    }
+                    (e, content)
+                })
+                .collect();
+            let problems = report.borrow_counts().count_problems();
+            (entries, problems)
+        };
+        let expected = read_all(RUN_LEN);
+        assert_eq!(expected.0.len(), 8);
+        for run_len in &[1, 2, 3] {
+            assert_eq!(read_all(*run_len), expected);
+        }
+    }
+
+    #[test]
+    fn entries_replaced_by_another_kind() {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut add = |entry_type, path: &str, content: &[u8]| {
+            builder
+                .append_data(
+                    &mut header(entry_type, content.len() as u64, 1000),
+                    path,
+                    content,
+                )
+                .unwrap();
+        };
+        add(tar::EntryType::Directory, "dir/", b"");
+        add(tar::EntryType::Regular, "dir/inside", b"gone");
+        add(tar::EntryType::Directory, "dir/sub/", b"");
+        add(tar::EntryType::Regular, "dir", b"now a file");
+        add(tar::EntryType::Regular, "file", b"file");
+        add(tar::EntryType::Regular, "file/under", b"can't be here");
+        let tar = builder.into_inner().unwrap();
+
+        let report = Report::new();
+        let tree = TarTree::read(tar.as_slice(), &report).unwrap();
+        let entries: Vec<Entry> = tree
+            .iter_entries(&report)
+            .unwrap()
+            .map(|e| e.unwrap())
+            .collect();
+        let apaths: Vec<&str> = entries.iter().map(|e| &e.apath[..]).collect();
+        assert_eq!(apaths, ["/", "/dir", "/file"]);
+        assert_eq!(entries[1].kind, Kind::File);
+        assert_eq!(
+            report
+                .borrow_counts()
+                .count_problems_of_kind(ProblemKind::SourceRead),
+            1
+        );
+    }
+
+    #[test]
+    fn exclude_from_tar() {
+        let report = Report::new();
+        let tree = TarTree::read(make_tar().as_slice(), &report)
+            .unwrap()
+            .with_excludes(excludes::from_strings(["/sub/deeper", "/zzz"]).unwrap());
+        let apaths: Vec<String> = tree
+            .iter_entries(&report)
+            .unwrap()
+            .map(|e| e.unwrap().apath.into())
+            .collect();
+        assert_eq!(apaths, ["/", "/aaa", "/link", "/sub", "/sub/hardlink"]);
+        assert_eq!(report.get_count("skipped.excluded.files"), 2);
+        assert_eq!(report.get_count("skipped.excluded.directories"), 1);
+    }
+
+    #[test]
+    fn backup_from_tar() {
+        let af = ScratchArchive::new();
+        let tree = TarTree::read(make_tar().as_slice(), af.report()).unwrap();
+        copy_tree(&tree, &mut BackupWriter::begin(&af).unwrap()).unwrap();
+
+        let st = StoredTree::open_last(&af).unwrap();
+        let mut content = String::new();
+        st.open_file(&"/sub/deeper/file".into())
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "deep");
+        af.validate(ValidateDepth::Deep).unwrap();
+        assert_eq!(
+            af.report()
+                .borrow_counts()
+                .count_problems_of_kind(ProblemKind::BadIndex),
+            0
+        );
+    }
+}
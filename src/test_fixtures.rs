@@ -62,8 +62,139 @@ impl ScratchArchive {
         srcdir.create_file("hello2");
         copy_tree(&lt, &mut BackupWriter::begin(&self).unwrap()).unwrap();
     }
+
+    /// Store a version containing `/` and files `/a`, `/b` and `/c`, with one index hunk
+    /// for each entry.
+    ///
+    /// Each file has different contents, and so a different block, so damage to one
+    /// file's block or index hunk leaves the others readable.
+    pub fn store_version_in_hunks(&self) {
+        let report = self.report();
+        let band = Band::create(self).unwrap();
+        let mut block_dir = self.block_dir().clone();
+        let mut ib = band.index_builder();
+        ib.push(Entry {
+            apath: "/".into(),
+            kind: Kind::Dir,
+            mtime: None,
+            addrs: vec![],
+            target: None,
+            size: None,
+        });
+        ib.finish_hunk(report).unwrap();
+        for name in &["/a", "/b", "/c"] {
+            let contents = format!("contents of {}", name);
+            let addrs = block_dir.store(&mut contents.as_bytes(), report).unwrap();
+            ib.push(Entry {
+                apath: (*name).into(),
+                kind: Kind::File,
+                mtime: None,
+                addrs,
+                target: None,
+                size: None,
+            });
+            ib.finish_hunk(report).unwrap();
+        }
+        band.close(report).unwrap();
+    }
+
+    /// Damage the first version in the archive, which should have been stored by
+    /// `store_version_in_hunks`.
+    ///
+    /// Returns the path of the file that was damaged, deleted or created.
+    pub fn damage(&self, damage: Damage) -> PathBuf {
+        let band_dir = self.path().join("b0000");
+        let block_path = || {
+            let st = StoredTree::open_incomplete_version(self, &BandId::new(&[0])).unwrap();
+            let hash = st.find_entry(&"/b".into()).unwrap().unwrap().addrs[0]
+                .hash
+                .clone();
+            self.path().join("d").join(&hash[..3]).join(&hash)
+        };
+        match damage {
+            Damage::TruncateBlock => {
+                let path = block_path();
+                let content = fs::read(&path).unwrap();
+                fs::write(&path, &content[..content.len() / 2]).unwrap();
+                path
+            }
+            Damage::FlipBlockBit => {
+                let path = block_path();
+                let mut content = fs::read(&path).unwrap();
+                let middle = content.len() / 2;
+                content[middle] ^= 0x04;
+                fs::write(&path, &content).unwrap();
+                path
+            }
+            Damage::DeleteHunk => {
+                // Hunk 0 holds `/`, so hunk 2 holds `/b`.
+                let path = band_dir.join("i").join("00000").join("000000002");
+                fs::remove_file(&path).unwrap();
+                path
+            }
+            Damage::CorruptHunk => {
+                let path = band_dir.join("i").join("00000").join("000000002");
+                fs::write(&path, b"not snappy").unwrap();
+                path
+            }
+            Damage::DeleteTail => {
+                let path = band_dir.join("BANDTAIL");
+                fs::remove_file(&path).unwrap();
+                path
+            }
+            Damage::StrayTmpFile => {
+                let path = block_path().with_file_name("tmp8Sh2aE");
+                fs::write(&path, b"partly written").unwrap();
+                path
+            }
+            Damage::BadHeadJson => {
+                let path = band_dir.join("BANDHEAD");
+                fs::write(&path, b"{\"start_time\": ").unwrap();
+                path
+            }
+            Damage::BadTailJson => {
+                let path = band_dir.join("BANDTAIL");
+                fs::write(&path, b"{\"end_time\": \"yesterday\"}").unwrap();
+                path
+            }
+        }
+    }
+}
+
+/// Damage that might plausibly happen to an archive, through bugs, interruptions, or
+/// failures of the underlying storage.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Damage {
+    /// Cut the block holding `/b` short.
+    TruncateBlock,
+    /// Flip one bit in the middle of the block holding `/b`.
+    FlipBlockBit,
+    /// Delete the index hunk holding `/b`, from the middle of the index.
+    DeleteHunk,
+    /// Overwrite the index hunk holding `/b` with data that can't be decompressed.
+    CorruptHunk,
+    /// Delete the band tail, as if the backup had been interrupted.
+    DeleteTail,
+    /// Leave a temporary file in the blockdir, as if storing a block had been interrupted.
+    StrayTmpFile,
+    /// Overwrite the band head with truncated JSON.
+    BadHeadJson,
+    /// Overwrite the band tail with JSON of the wrong type.
+    BadTailJson,
 }
 
+/// All kinds of damage, in order.
+pub static ALL_DAMAGE: &[Damage] = &[
+    Damage::TruncateBlock,
+    Damage::FlipBlockBit,
+    Damage::DeleteHunk,
+    Damage::CorruptHunk,
+    Damage::DeleteTail,
+    Damage::StrayTmpFile,
+    Damage::BadHeadJson,
+    Damage::BadTailJson,
+];
+
 impl Deref for ScratchArchive {
     type Target = Archive;
 
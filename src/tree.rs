@@ -28,9 +28,16 @@ pub trait ReadTree: HasReport {
     ///
     /// This typically requires walking all entries, which may take a while.
     fn size(&self) -> Result<TreeSize> {
+        self.measure(self.report())
+    }
+
+    /// Measure the tree size, counting entries and problems into `iter_report`.
+    ///
+    /// Progress is shown on the tree's own report.
+    fn measure(&self, iter_report: &Report) -> Result<TreeSize> {
         let report = self.report();
         let mut tot = 0u64;
-        for e in self.iter_entries(self.report())? {
+        for e in self.iter_entries(iter_report)? {
             // While just measuring size, ignore directories/files we can't stat.
             match e {
                 Ok(e) => {
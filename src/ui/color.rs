@@ -162,7 +162,7 @@ impl UI for ColorUI {
         t.flush().unwrap();
     }
 
-    fn problem(&mut self, s: &str) {
+    fn error(&mut self, s: &str) {
         self.clear_progress();
         let t = &mut self.t;
         t.fg(term::color::BRIGHT_RED).unwrap();
@@ -0,0 +1,43 @@
+// Conserve backup system.
+// Copyright 2019 Martin Pool.
+
+//! Write messages to stdout as JSON Lines, for use by other programs.
+//!
+//! Commands that support `--json` print their results as lines of JSON, which are passed
+//! through unchanged. Problems are wrapped in a JSON object so that every line of output
+//! can be parsed. There is no progress bar.
+
+use super::Report;
+use crate::output::{json_error, json_problem};
+use crate::Problem;
+
+#[derive(Debug, Default)]
+pub struct JsonUI;
+
+impl JsonUI {
+    /// Make a JsonUI.
+    pub fn new() -> JsonUI {
+        JsonUI {}
+    }
+}
+
+impl super::UI for JsonUI {
+    fn show_progress(&mut self, _report: &Report) {}
+
+    fn print(&mut self, s: &str) {
+        println!("{}", s);
+    }
+
+    /// Notes aren't shown, so that every line of output is JSON.
+    fn note(&mut self, _s: &str) {}
+
+    fn problem(&mut self, problem: &Problem) {
+        self.print(&json_problem(problem))
+    }
+
+    fn error(&mut self, s: &str) {
+        self.print(&json_error(s))
+    }
+
+    fn finish(&mut self) {}
+}
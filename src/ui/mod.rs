@@ -9,8 +9,11 @@ use std::time::Duration;
 use isatty;
 
 pub use super::report::{Counts, Report, Sizes};
+use crate::Problem;
 
 pub mod color;
+pub mod json;
+pub mod null;
 pub mod plain;
 
 /// Display information about backup progress to the user in some way.
@@ -21,8 +24,19 @@ pub trait UI: fmt::Debug {
     /// Show a plain text message.
     fn print(&mut self, s: &str);
 
+    /// Show an informational message about what's happening, that isn't part of the
+    /// results of the command.
+    fn note(&mut self, s: &str) {
+        self.print(s)
+    }
+
+    /// Show a problem that doesn't stop the operation.
+    fn problem(&mut self, problem: &Problem) {
+        self.error(&problem.to_string())
+    }
+
     /// Print an error message.
-    fn problem(&mut self, s: &str);
+    fn error(&mut self, s: &str);
 
     /// Clear up the UI before exiting.
     fn finish(&mut self);
@@ -31,8 +45,11 @@ pub trait UI: fmt::Debug {
 impl dyn UI {
     /// Construct a UI by name.
     ///
-    /// `ui_name` must be `"auto"`, `"plain"`, or `"color"`.
+    /// `ui_name` must be `"auto"`, `"plain"`, `"color"` or `"json"`.
     pub fn by_name(ui_name: &str, progress_bar: bool) -> Option<Box<dyn UI + Send>> {
+        if ui_name == "json" {
+            return Some(Box::new(json::JsonUI::new()));
+        }
         if ui_name == "color" || (ui_name == "auto" && isatty::stdout_isatty()) {
             if let Some(ui) = color::ColorUI::new(progress_bar) {
                 return Some(Box::new(ui));
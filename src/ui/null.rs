@@ -0,0 +1,33 @@
+// Conserve backup system.
+// Copyright 2019 Martin Pool.
+
+//! A UI that shows nothing at all.
+
+use super::Report;
+use crate::Problem;
+
+/// Discards all messages and progress.
+///
+/// This is useful for a Report that only collects counts, such as while measuring a tree
+/// that will be walked again later.
+#[derive(Debug, Default)]
+pub struct NullUI;
+
+impl NullUI {
+    /// Make a NullUI.
+    pub fn new() -> NullUI {
+        NullUI {}
+    }
+}
+
+impl super::UI for NullUI {
+    fn show_progress(&mut self, _report: &Report) {}
+
+    fn print(&mut self, _s: &str) {}
+
+    fn problem(&mut self, _problem: &Problem) {}
+
+    fn error(&mut self, _s: &str) {}
+
+    fn finish(&mut self) {}
+}
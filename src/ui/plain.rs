@@ -26,7 +26,7 @@ impl super::UI for PlainUI {
         println!("{}", s);
     }
 
-    fn problem(&mut self, s: &str) {
+    fn error(&mut self, s: &str) {
         self.print(s)
     }
 
@@ -9,8 +9,12 @@ extern crate lazy_static;
 extern crate assert_cmd;
 extern crate assert_fs;
 extern crate escargot;
+extern crate filetime;
 extern crate predicates;
+extern crate serde_json;
+extern crate tar;
 extern crate tempfile;
+extern crate zip;
 
 use std::process::Command;
 
@@ -18,22 +22,29 @@ use assert_cmd::prelude::*;
 use assert_fs::prelude::*;
 use assert_fs::TempDir;
 use escargot::CargoRun;
+use filetime::{set_file_mtime, FileTime};
 use predicates::prelude::*;
+use serde_json::Value;
 
 use crate::predicate::path::{is_dir, is_file};
 use crate::predicate::str::{contains, is_empty, is_match, starts_with};
 
 extern crate conserve;
-use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+use conserve::test_fixtures::{Damage, ScratchArchive, TreeFixture, ALL_DAMAGE};
+use conserve::{Report, SYMLINKS_SUPPORTED};
 
 lazy_static! {
     // This doesn's pass `.current_target()` because it doesn't seem
     // necessary for typical cases (cross-builds won't work with this)
     // and it causes everything to rebuild which slows the tests a lot.
-    static ref CARGO_RUN: CargoRun = escargot::CargoBuild::new()
-        .current_release()
-        .run() // Build it and return a proxy to run it
-        .unwrap();
+    static ref CARGO_RUN: CargoRun = {
+        let build = escargot::CargoBuild::new().current_release();
+        // Build with the features the tests have, so that their commands can be tested.
+        #[cfg(feature = "fuse")]
+        let build = build.arg("--features=fuse");
+        build.run() // Build it and return a proxy to run it
+            .unwrap()
+    };
 }
 
 fn main_binary() -> Command {
@@ -148,11 +159,20 @@ fn blackbox_backup() {
         .assert()
         .success()
         .stderr(is_empty())
+        .stdout("");
+
+    main_binary()
+        .args(&["diff", "--include-unchanged"])
+        .arg(&arch_dir)
+        .arg(src.path())
+        .assert()
+        .success()
+        .stderr(is_empty())
         .stdout(
             "\
-both     /
-both     /hello
-both     /subdir
+same     /
+same     /hello
+same     /subdir
 ",
         );
 
@@ -326,3 +346,1071 @@ fn incomplete_version() {
         .stderr(is_empty())
         .stdout(is_empty());
 }
+
+/// Exclusions can be read from a file, and stored in the archive as defaults for backup.
+#[test]
+fn exclude_from_file_and_archive_defaults() {
+    let testdir = TempDir::new().unwrap();
+    let arch_dir = testdir.path().join("a");
+    let excludes_file = testdir.child("excludes");
+    excludes_file
+        .write_str("# Editor droppings\n/**/*.swp\n\n/cache\n")
+        .unwrap();
+
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    src.create_file("hello.swp");
+    src.create_dir("cache");
+    src.create_file("cache/big");
+    src.create_file("junk");
+
+    main_binary()
+        .args(&["source", "ls", "--exclude-from"])
+        .arg(excludes_file.path())
+        .arg(src.path())
+        .assert()
+        .success()
+        .stderr(is_empty())
+        .stdout("/\n/hello\n/junk\n");
+
+    main_binary()
+        .arg("init")
+        .arg("--exclude-from")
+        .arg(excludes_file.path())
+        .arg(&arch_dir)
+        .assert()
+        .success();
+
+    main_binary()
+        .args(&["excludes", "show"])
+        .arg(&arch_dir)
+        .assert()
+        .success()
+        .stderr(is_empty())
+        .stdout("/**/*.swp\n/cache\n");
+
+    // Default excludes from the archive are combined with those on the command line.
+    main_binary()
+        .args(&["backup", "--exclude", "/junk"])
+        .arg(&arch_dir)
+        .arg(src.path())
+        .assert()
+        .success();
+
+    main_binary()
+        .arg("ls")
+        .arg(&arch_dir)
+        .assert()
+        .success()
+        .stderr(is_empty())
+        .stdout("/\n/hello\n");
+
+    // The source has more files than the backup, unless the archive's excludes are applied.
+    main_binary()
+        .args(&[
+            "diff",
+            "--include-unchanged",
+            "--exclude",
+            "/junk",
+            "--archive-excludes",
+        ])
+        .arg(&arch_dir)
+        .arg(src.path())
+        .assert()
+        .success()
+        .stdout("same     /\nsame     /hello\n");
+
+    // Clearing the defaults means the next backup includes everything.
+    main_binary()
+        .args(&["excludes", "set"])
+        .arg(&arch_dir)
+        .assert()
+        .success();
+    main_binary()
+        .args(&["excludes", "show"])
+        .arg(&arch_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    main_binary()
+        .arg("backup")
+        .arg(&arch_dir)
+        .arg(src.path())
+        .assert()
+        .success();
+    main_binary()
+        .arg("ls")
+        .arg(&arch_dir)
+        .assert()
+        .success()
+        .stdout("/\n/cache\n/hello\n/hello.swp\n/junk\n/cache/big\n");
+}
+
+#[test]
+fn include_patterns_and_multiple_sources() {
+    let testdir = TempDir::new().unwrap();
+    let arch_dir = testdir.path().join("a");
+
+    let src = TreeFixture::new();
+    src.create_dir("docs");
+    src.create_file("docs/a.txt");
+    src.create_file("docs/b.jpg");
+    src.create_dir("music");
+    src.create_file("music/song");
+    src.create_dir("tmp");
+    src.create_file("tmp/junk");
+
+    main_binary()
+        .args(&["source", "ls", "--include", "*.txt"])
+        .arg(src.path())
+        .assert()
+        .success()
+        .stderr(is_empty())
+        .stdout("/\n/docs\n/music\n/tmp\n/docs/a.txt\n");
+
+    main_binary().arg("init").arg(&arch_dir).assert().success();
+
+    // Several sources are stored relative to their common parent.
+    main_binary()
+        .args(&["backup", "--exclude", "/docs/*.jpg"])
+        .arg(&arch_dir)
+        .arg(src.path().join("docs"))
+        .arg(src.path().join("music"))
+        .assert()
+        .success();
+
+    main_binary()
+        .arg("ls")
+        .arg(&arch_dir)
+        .assert()
+        .success()
+        .stderr(is_empty())
+        .stdout("/\n/docs\n/music\n/docs/a.txt\n/music/song\n");
+}
+
+#[test]
+fn exclude_by_size_and_kind() {
+    let src = TreeFixture::new();
+    src.create_file_with_contents("core", &[0; 5000]);
+    src.create_file_with_contents("notes", b"hello");
+    src.create_dir("sub");
+    src.create_file_with_contents("sub/big.iso", &[0; 3000]);
+
+    main_binary()
+        .args(&["source", "ls", "--exclude-larger-than", "2K"])
+        .arg(src.path())
+        .assert()
+        .success()
+        .stderr(is_empty())
+        .stdout("/\n/notes\n/sub\n");
+
+    main_binary()
+        .args(&["source", "ls", "--exclude-kind", "dir"])
+        .arg(src.path())
+        .assert()
+        .success()
+        .stdout("/\n/core\n/notes\n");
+
+    main_binary()
+        .args(&["source", "ls", "--exclude-older-than", "2999-01-01"])
+        .arg(src.path())
+        .assert()
+        .success()
+        .stdout("/\n/sub\n");
+
+    main_binary()
+        .args(&["source", "ls", "--exclude-larger-than", "lots"])
+        .arg(src.path())
+        .assert()
+        .failure()
+        .stdout(contains("Invalid size"));
+}
+
+#[test]
+fn diff_changed_source() {
+    let testdir = TempDir::new().unwrap();
+    let arch_dir = testdir.path().join("a");
+    let src = TreeFixture::new();
+    src.create_file_with_contents("edited", b"hello");
+    src.create_file_with_contents("grown", b"hello");
+    src.create_file("gone");
+    let old_mtime = FileTime::from_unix_time(1_500_000_000, 0);
+    set_file_mtime(src.path().join("edited"), old_mtime).unwrap();
+    set_file_mtime(src.path(), old_mtime).unwrap();
+
+    main_binary().arg("init").arg(&arch_dir).assert().success();
+    main_binary()
+        .arg("backup")
+        .arg(&arch_dir)
+        .arg(src.path())
+        .assert()
+        .success();
+
+    // Change a file without changing its size or mtime.
+    src.create_file_with_contents("edited", b"jello");
+    set_file_mtime(src.path().join("edited"), old_mtime).unwrap();
+    src.create_file_with_contents("grown", b"hello world");
+    std::fs::remove_file(src.path().join("gone")).unwrap();
+    src.create_file("new");
+
+    main_binary()
+        .arg("diff")
+        .arg(&arch_dir)
+        .arg(src.path())
+        .assert()
+        .success()
+        .stderr(is_empty())
+        .stdout("metadata /\nleft     /gone\nchanged  /grown\nright    /new\n");
+
+    main_binary()
+        .args(&["diff", "--compare-content"])
+        .arg(&arch_dir)
+        .arg(src.path())
+        .assert()
+        .success()
+        .stderr(is_empty())
+        .stdout("metadata /\nchanged  /edited\nleft     /gone\nchanged  /grown\nright    /new\n");
+}
+
+#[test]
+fn diff_two_versions() {
+    let testdir = TempDir::new().unwrap();
+    let arch_dir = testdir.path().join("a");
+    let src = TreeFixture::new();
+    src.create_file_with_contents("config", b"debug=0");
+    src.create_file("old");
+    src.create_file("same");
+    let root_mtime = FileTime::from_unix_time(1_500_000_000, 0);
+    set_file_mtime(src.path(), root_mtime).unwrap();
+
+    main_binary().arg("init").arg(&arch_dir).assert().success();
+    let backup = || {
+        main_binary()
+            .arg("backup")
+            .arg(&arch_dir)
+            .arg(src.path())
+            .assert()
+            .success();
+    };
+    backup();
+    src.create_file_with_contents("config", b"debug=1");
+    std::fs::remove_file(src.path().join("old")).unwrap();
+    src.create_file("new");
+    set_file_mtime(src.path(), root_mtime).unwrap();
+    backup();
+
+    main_binary()
+        .args(&["diff", "-b", "b0", "-b", "b1"])
+        .arg(&arch_dir)
+        .assert()
+        .success()
+        .stderr(is_empty())
+        .stdout("changed  /config\nright    /new\nleft     /old\n");
+
+    main_binary()
+        .args(&["diff", "-b", "b0", "-b", "b1", "--exclude", "/old"])
+        .arg(&arch_dir)
+        .arg(src.path())
+        .assert()
+        .failure()
+        .stderr(contains("A source can't be given"));
+
+    main_binary()
+        .args(&["diff", "-b", "b0", "-b", "b1", "--one-file-system"])
+        .arg(&arch_dir)
+        .assert()
+        .code(2)
+        .stderr(contains("--one-file-system can't be given"));
+
+    main_binary()
+        .args(&["diff", "-b", "b0"])
+        .arg(&arch_dir)
+        .assert()
+        .failure()
+        .stderr(contains("A source directory is required"));
+}
+
+#[test]
+fn restore_only_some_paths() {
+    let testdir = TempDir::new().unwrap();
+    let arch_dir = testdir.path().join("a");
+
+    let src = TreeFixture::new();
+    src.create_dir("docs");
+    src.create_file("docs/a.txt");
+    src.create_dir("docs/old");
+    src.create_file("docs/old/b.txt");
+    src.create_dir("music");
+    src.create_file("music/song");
+    src.create_file("top.txt");
+
+    main_binary().arg("init").arg(&arch_dir).assert().success();
+    main_binary()
+        .arg("backup")
+        .arg(&arch_dir)
+        .arg(src.path())
+        .assert()
+        .success();
+
+    let restore_dir = testdir.path().join("r1");
+    main_binary()
+        .arg("restore")
+        .arg(&arch_dir)
+        .arg(&restore_dir)
+        .arg("/docs/old")
+        .assert()
+        .success();
+    assert!(restore_dir.join("docs/old/b.txt").is_file());
+    assert!(!restore_dir.join("docs/a.txt").exists());
+    assert!(!restore_dir.join("music").exists());
+    assert!(!restore_dir.join("top.txt").exists());
+
+    let restore_dir = testdir.path().join("r2");
+    main_binary()
+        .args(&["restore", "--only", "/**/*.txt"])
+        .arg(&arch_dir)
+        .arg(&restore_dir)
+        .assert()
+        .success();
+    assert!(restore_dir.join("docs/a.txt").is_file());
+    assert!(restore_dir.join("docs/old/b.txt").is_file());
+    assert!(restore_dir.join("top.txt").is_file());
+    assert!(!restore_dir.join("music/song").exists());
+
+    main_binary()
+        .arg("restore")
+        .arg(&arch_dir)
+        .arg(testdir.path().join("r3"))
+        .arg("docs")
+        .assert()
+        .failure()
+        .stdout(contains("Invalid apath"));
+}
+
+#[test]
+fn restore_into_existing_directory() {
+    let testdir = TempDir::new().unwrap();
+    let arch_dir = testdir.path().join("a");
+    let src = TreeFixture::new();
+    src.create_file("config");
+    src.create_file("data");
+
+    main_binary().arg("init").arg(&arch_dir).assert().success();
+    main_binary()
+        .arg("backup")
+        .arg(&arch_dir)
+        .arg(src.path())
+        .assert()
+        .success();
+
+    let dest = TreeFixture::new();
+    dest.create_file_with_contents("config", b"local override");
+    dest.create_file_with_contents("data", b"corrupted");
+    dest.create_file("stray");
+
+    // Without an overwrite mode, the destination must be empty.
+    main_binary()
+        .arg("restore")
+        .arg(&arch_dir)
+        .arg(dest.path())
+        .assert()
+        .failure()
+        .stdout(contains("Destination directory not empty"));
+
+    // --delete doesn't choose an overwrite mode by itself.
+    main_binary()
+        .args(&["restore", "--delete"])
+        .arg(&arch_dir)
+        .arg(dest.path())
+        .assert()
+        .code(2);
+    assert!(dest.path().join("stray").is_file());
+
+    main_binary()
+        .args(&["restore", "--dry-run", "--overwrite=different", "--delete"])
+        .args(&["--exclude", "/config"])
+        .arg(&arch_dir)
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stderr(is_empty())
+        .stdout("replace  /data\ndelete   /stray\nDry run: nothing changed.\n");
+    assert!(dest.path().join("stray").is_file());
+
+    main_binary()
+        .args(&["restore", "--overwrite=different", "--delete"])
+        .args(&["--exclude", "/config"])
+        .arg(&arch_dir)
+        .arg(dest.path())
+        .assert()
+        .success();
+    assert!(!dest.path().join("stray").exists());
+    assert_eq!(
+        std::fs::read(dest.path().join("config")).unwrap(),
+        b"local override"
+    );
+    assert_eq!(
+        std::fs::read(dest.path().join("data")).unwrap(),
+        b"contents"
+    );
+}
+
+#[test]
+fn select_version_by_time() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    main_binary()
+        .args(&["ls", "--at", "now"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(contains("/hello2"));
+
+    main_binary()
+        .args(&["ls", "--at", "yesterday"])
+        .arg(af.path())
+        .assert()
+        .failure()
+        .stdout(contains("Archive has no bands completed before"));
+
+    main_binary()
+        .args(&["ls", "--at", "1999-01-01 10:00"])
+        .arg(af.path())
+        .assert()
+        .failure()
+        .stdout(contains(
+            "Archive has no bands completed before 1999-01-01T10:00:00",
+        ));
+
+    main_binary()
+        .args(&["ls", "--at", "the day before"])
+        .arg(af.path())
+        .assert()
+        .failure()
+        .stdout(contains("Invalid date"));
+
+    main_binary()
+        .args(&["ls", "--at", "now", "-b", "b0"])
+        .arg(af.path())
+        .assert()
+        .failure()
+        .stderr(contains("cannot be used with"));
+}
+
+#[test]
+fn cat_stored_file() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    main_binary()
+        .arg("cat")
+        .arg(af.path())
+        .arg("/subdir/subfile")
+        .assert()
+        .success()
+        .stderr(is_empty())
+        .stdout("contents");
+
+    // hello2 was only added in the second version.
+    main_binary()
+        .args(&["cat", "-b", "b0"])
+        .arg(af.path())
+        .arg("/hello2")
+        .assert()
+        .failure()
+        .stdout(contains("Not found in the archive: /hello2"));
+
+    main_binary()
+        .arg("cat")
+        .arg(af.path())
+        .arg("/subdir")
+        .assert()
+        .failure()
+        .stdout(contains("Not a file in the archive: /subdir"));
+}
+
+/// Run a command and parse each line of its output as JSON.
+fn json_lines(command: &mut Command) -> Vec<Value> {
+    let output = command.output().unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect()
+}
+
+#[test]
+fn json_output() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    let versions = json_lines(main_binary().args(&["versions", "--json"]).arg(af.path()));
+    assert_eq!(versions.len(), 2);
+    assert_eq!(versions[0]["id"], "b0000");
+    assert_eq!(versions[1]["complete"], true);
+    assert!(versions[1]["end_time"].is_string());
+
+    let entries = json_lines(main_binary().args(&["ls", "--json"]).arg(af.path()));
+    assert_eq!(entries[0]["apath"], "/");
+    assert_eq!(entries[0]["kind"], "dir");
+    assert_eq!(entries[0]["size"], Value::Null);
+    assert_eq!(entries[1]["apath"], "/hello");
+    assert_eq!(entries[1]["kind"], "file");
+    assert_eq!(entries[1]["size"], 8);
+    assert!(entries[1]["mtime"].is_u64());
+
+    let sizes = json_lines(
+        main_binary()
+            .args(&["tree", "size", "--json"])
+            .arg(af.path()),
+    );
+    assert_eq!(sizes, vec![serde_json::json!({ "file_bytes": 24 })]);
+
+    let diff = json_lines(
+        main_binary()
+            .args(&["diff", "--json", "-b", "b0", "-b", "b1"])
+            .arg(af.path()),
+    );
+    assert!(diff.contains(&serde_json::json!({"apath": "/hello2", "change": "right"})));
+
+    // Validation problems are also JSON, and there's no other output.
+    std::fs::create_dir(af.path().join("junk")).unwrap();
+    let output = main_binary()
+        .args(&["validate", "--json"])
+        .arg(af.path())
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let problem: Value = serde_json::from_str(stdout.lines().next().unwrap()).unwrap();
+    assert_eq!(problem["kind"], "problem.unexpected_file");
+    assert_eq!(
+        problem["problem"],
+        "Unexpected directory in archive directory"
+    );
+    assert!(problem["path"].as_str().unwrap().ends_with("junk"));
+}
+
+#[test]
+fn exit_codes() {
+    let testdir = TempDir::new().unwrap();
+    main_binary()
+        .args(&["ls", "--no-such-option"])
+        .assert()
+        .code(2);
+
+    main_binary()
+        .arg("ls")
+        .arg(testdir.path().join("nothing"))
+        .assert()
+        .code(5)
+        .stdout(contains("Not a Conserve archive"));
+
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    main_binary()
+        .arg("validate")
+        .arg(af.path())
+        .assert()
+        .code(0);
+    main_binary()
+        .args(&["validate", "--shallow"])
+        .arg(af.path())
+        .assert()
+        .code(0)
+        .stdout(contains("Archive is OK.\n"));
+    std::fs::write(af.path().join("junk"), b"").unwrap();
+    main_binary()
+        .arg("validate")
+        .arg(af.path())
+        .assert()
+        .code(4)
+        .stdout(contains("1 problems: 1 unexpected file."));
+}
+
+/// Source files that can't be backed up are reported and give a distinct exit code.
+#[cfg(target_os = "linux")]
+#[test]
+fn backup_with_problems_exit_code() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let testdir = TempDir::new().unwrap();
+    let arch_dir = testdir.path().join("a");
+    let src = TreeFixture::new();
+    src.create_file("good");
+    std::fs::write(src.path().join(OsStr::from_bytes(b"bad\xff")), b"").unwrap();
+    main_binary().arg("init").arg(&arch_dir).assert().success();
+
+    main_binary()
+        .arg("backup")
+        .arg(&arch_dir)
+        .arg(src.path())
+        .assert()
+        .code(3)
+        .stdout(contains("Can't decode filename"))
+        .stdout(contains("1 problems: 1 undecodable name."));
+
+    main_binary()
+        .args(&["--fail-on-warning", "backup"])
+        .arg(&arch_dir)
+        .arg(src.path())
+        .assert()
+        .code(1);
+
+    // The good file was still backed up.
+    main_binary()
+        .arg("ls")
+        .arg(&arch_dir)
+        .assert()
+        .code(0)
+        .stdout("/\n/good\n");
+}
+
+/// Damaged archives fail validation, and restoring from them exits with a problem or an
+/// error rather than panicking.
+#[test]
+fn damaged_archive_exit_codes() {
+    for &damage in ALL_DAMAGE {
+        let af = ScratchArchive::new();
+        af.store_version_in_hunks();
+        af.store_version_in_hunks();
+        af.damage(damage);
+
+        main_binary()
+            .arg("validate")
+            .arg(af.path())
+            .assert()
+            .code(4);
+
+        let restore_code = match damage {
+            Damage::TruncateBlock
+            | Damage::FlipBlockBit
+            | Damage::DeleteHunk
+            | Damage::CorruptHunk => 3,
+            Damage::DeleteTail | Damage::BadHeadJson => 1,
+            Damage::StrayTmpFile | Damage::BadTailJson => 0,
+        };
+        let restore_dir = TempDir::new().unwrap();
+        main_binary()
+            .args(&["restore", "-b", "b0000"])
+            .arg(af.path())
+            .arg(restore_dir.path())
+            .assert()
+            .code(restore_code);
+    }
+}
+
+/// `restore --salvage` lists files that were lost, and with `--zero-fill` restores them
+/// with holes.
+#[test]
+fn restore_salvage_lists_lost_files() {
+    let af = ScratchArchive::new();
+    af.store_version_in_hunks();
+    af.damage(Damage::TruncateBlock);
+
+    let restore_dir = TempDir::new().unwrap();
+    main_binary()
+        .args(&["restore", "--salvage"])
+        .arg(af.path())
+        .arg(restore_dir.path())
+        .assert()
+        .code(3)
+        .stdout(contains("Lost files, which were not restored:\n  /b\n"));
+    restore_dir.child("a").assert("contents of /a");
+    restore_dir.child("b").assert(predicate::path::missing());
+
+    let restore_dir = TempDir::new().unwrap();
+    main_binary()
+        .args(&["restore", "--salvage", "--zero-fill"])
+        .arg(af.path())
+        .arg(restore_dir.path())
+        .assert()
+        .code(3)
+        .stdout(contains(
+            "Damaged files, restored with zeros in place of unreadable data:\n  /b\n",
+        ));
+    restore_dir.child("b").assert(&[0u8; 14][..]);
+
+    let restore_dir = TempDir::new().unwrap();
+    main_binary()
+        .args(&["restore", "--zero-fill"])
+        .arg(af.path())
+        .arg(restore_dir.path())
+        .assert()
+        .code(2);
+}
+
+/// `repair` lists what it would do, and only does it given `--apply`, after which an
+/// interrupted backup can be read as a partial version.
+#[test]
+fn repair_interrupted_backup() {
+    let af = ScratchArchive::new();
+    af.store_version_in_hunks();
+    af.damage(Damage::DeleteTail);
+
+    main_binary()
+        .arg("repair")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(contains(
+            "Close incomplete band b0000 as partial\n\
+             Dry run: nothing changed. Use --apply to make these repairs.\n",
+        ));
+    main_binary()
+        .arg("ls")
+        .arg(af.path())
+        .assert()
+        .failure()
+        .stdout(contains("Archive has no complete bands"));
+
+    main_binary()
+        .args(&["repair", "--apply"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(contains(
+            "Close incomplete band b0000 as partial\nRepair complete.\n",
+        ));
+    main_binary()
+        .arg("versions")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(contains("b0000").and(contains("partial")));
+    main_binary()
+        .arg("ls")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("/\n/a\n/b\n/c\n");
+
+    main_binary()
+        .arg("repair")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(contains("Nothing to repair.\n"));
+}
+
+/// A damaged block can be reconstructed from parity written by `backup --parity`.
+#[test]
+fn parity_reconstructs_damaged_block() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    main_binary()
+        .args(&["backup", "--parity"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success()
+        .stdout(contains("Wrote 1 parity groups.\n"));
+    main_binary()
+        .arg("parity")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(contains("Wrote 0 parity groups.\n"));
+
+    let block_name = af
+        .block_dir()
+        .block_names(&Report::new())
+        .unwrap()
+        .remove(0);
+    let block_path = af.path().join("d").join(&block_name[..3]).join(&block_name);
+    std::fs::write(&block_path, b"rotten").unwrap();
+    main_binary()
+        .arg("validate")
+        .arg(af.path())
+        .assert()
+        .code(4)
+        .stdout(contains(format!(
+            "Can reconstruct {} from parity",
+            block_path.display()
+        )));
+
+    main_binary()
+        .args(&["repair", "--apply"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(contains(format!(
+            "Reconstruct {} from parity\nRepair complete.\n",
+            block_path.display()
+        )));
+    main_binary()
+        .arg("validate")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(contains("Archive is OK.\n"));
+}
+
+#[test]
+fn copy_bands_between_archives() {
+    let source = ScratchArchive::new();
+    source.store_two_versions();
+    let dest = ScratchArchive::new();
+
+    main_binary()
+        .arg("copy")
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stdout(
+            contains("Copied b0000 to b0000\nCopied b0001 to b0001\n")
+                .and(contains("1 blocks copied, 1 already present.\n")),
+        );
+    main_binary()
+        .arg("ls")
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stdout("/\n/hello\n/hello2\n/link\n/subdir\n/subdir/subfile\n");
+
+    // Everything is already there, so nothing more is copied.
+    main_binary()
+        .arg("copy")
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stdout(contains("0 blocks copied, 0 already present.\n"));
+
+    main_binary()
+        .args(&["copy", "--fresh-ids", "-b", "b0000"])
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stdout(contains(
+            "Copied b0000 to b0002\n0 blocks copied, 1 already present.\n",
+        ));
+    main_binary()
+        .arg("validate")
+        .arg(dest.path())
+        .assert()
+        .success();
+
+    // Bands already copied with fresh ids aren't copied again.
+    let fresh = ScratchArchive::new();
+    main_binary()
+        .args(&["copy", "--fresh-ids"])
+        .arg(source.path())
+        .arg(fresh.path())
+        .assert()
+        .success()
+        .stdout(contains("Copied b0000 to b0000\nCopied b0001 to b0001\n"));
+    main_binary()
+        .args(&["copy", "--fresh-ids"])
+        .arg(source.path())
+        .arg(fresh.path())
+        .assert()
+        .success()
+        .stdout("0 blocks copied, 0 already present.\n");
+}
+
+#[test]
+fn copy_finishes_interrupted_copy() {
+    let source = ScratchArchive::new();
+    source.store_two_versions();
+    let dest = ScratchArchive::new();
+    main_binary()
+        .arg("copy")
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+    // Interrupt the copy of b0001 before its tail was written.
+    std::fs::remove_file(dest.path().join("b0001").join("BANDTAIL")).unwrap();
+
+    main_binary()
+        .arg("copy")
+        .arg(source.path())
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stdout("Copied b0001 to b0001\n0 blocks copied, 1 already present.\n");
+    main_binary()
+        .arg("validate")
+        .arg(dest.path())
+        .assert()
+        .success();
+}
+
+#[cfg(all(unix, feature = "fuse"))]
+#[test]
+fn mount_versions() {
+    use std::fs;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let mountpoint = TempDir::new().unwrap();
+    let latest = mountpoint.path().join("latest");
+    let mut child = main_binary()
+        .arg("mount")
+        .arg(af.path())
+        .arg(mountpoint.path())
+        .spawn()
+        .unwrap();
+    for _ in 0..100 {
+        if latest.exists() || child.try_wait().unwrap().is_some() {
+            break;
+        }
+        sleep(Duration::from_millis(100));
+    }
+    if !latest.exists() {
+        // FUSE isn't available, or this user isn't allowed to mount.
+        child.kill().ok();
+        eprintln!("Couldn't mount; skipping test");
+        return;
+    }
+
+    assert_eq!(fs::read_link(&latest).unwrap().to_str(), Some("b0001"));
+    assert_eq!(
+        fs::read_to_string(latest.join("hello2")).unwrap(),
+        "contents"
+    );
+    assert_eq!(
+        fs::read_to_string(mountpoint.path().join("b0000/subdir/subfile")).unwrap(),
+        "contents"
+    );
+    assert!(!mountpoint.path().join("b0000/hello2").exists());
+    assert!(fs::write(latest.join("new"), "new").is_err());
+
+    // Interrupting it unmounts the archive.
+    Command::new("kill")
+        .arg("-INT")
+        .arg(child.id().to_string())
+        .assert()
+        .success();
+    assert!(child.wait().unwrap().success());
+    assert!(!latest.exists());
+}
+
+#[test]
+fn export_tar_and_zip() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    let output = main_binary()
+        .args(&["export", "-b", "b0000"])
+        .arg(af.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+    let mut tar = tar::Archive::new(output.stdout.as_slice());
+    let names: Vec<String> = tar
+        .entries()
+        .unwrap()
+        .map(|e| e.unwrap().path().unwrap().to_string_lossy().into_owned())
+        .collect();
+    assert!(names.contains(&"hello".to_owned()));
+    assert!(names.contains(&"subdir/subfile".to_owned()));
+    assert!(!names.contains(&"hello2".to_owned()));
+
+    let outdir = TempDir::new().unwrap();
+    let zip_path = outdir.path().join("out.zip");
+    main_binary()
+        .args(&["export", "--format", "zip", "-o"])
+        .arg(&zip_path)
+        .arg(af.path())
+        .assert()
+        .code(if SYMLINKS_SUPPORTED { 3 } else { 0 })
+        .stdout(contains("Export complete.\n"));
+    let mut zip = zip::ZipArchive::new(std::fs::File::open(&zip_path).unwrap()).unwrap();
+    assert!(zip.by_name("hello2").is_ok());
+    assert!(zip.by_name("subdir/subfile").is_ok());
+}
+
+#[test]
+fn backup_from_tar_on_stdin() {
+    let af = ScratchArchive::new();
+    let mut builder = tar::Builder::new(Vec::new());
+    for (path, content) in &[("./b/file", "in b"), ("./a", "hello")] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(1_500_000_000);
+        builder
+            .append_data(&mut header, path, content.as_bytes())
+            .unwrap();
+    }
+    let tar_bytes = builder.into_inner().unwrap();
+
+    main_binary()
+        .args(&["backup", "--from-tar", "-"])
+        .arg(af.path())
+        .with_stdin()
+        .buffer(tar_bytes)
+        .assert()
+        .success()
+        .stdout(contains("Backup complete.\n"));
+    main_binary()
+        .arg("ls")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("/\n/a\n/b\n/b/file\n");
+    main_binary()
+        .arg("cat")
+        .arg(af.path())
+        .arg("/b/file")
+        .assert()
+        .success()
+        .stdout("in b");
+
+    main_binary()
+        .args(&["backup", "--from-tar", "-"])
+        .arg(af.path())
+        .arg("/some/source")
+        .assert()
+        .code(2);
+}
+
+#[test]
+fn backup_stdin_as_named_file() {
+    let af = ScratchArchive::new();
+
+    main_binary()
+        .args(&["backup", "--stdin", "--name", "/db.sql"])
+        .arg(af.path())
+        .with_stdin()
+        .buffer("insert into t values (1);\n")
+        .assert()
+        .success()
+        .stdout(contains("Backup complete.\n"));
+    main_binary()
+        .arg("ls")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("/\n/db.sql\n");
+    main_binary()
+        .arg("cat")
+        .arg(af.path())
+        .arg("/db.sql")
+        .assert()
+        .success()
+        .stdout("insert into t values (1);\n");
+
+    // --name is required, and must be a valid apath.
+    main_binary()
+        .args(&["backup", "--stdin"])
+        .arg(af.path())
+        .assert()
+        .code(2);
+    main_binary()
+        .args(&["backup", "--stdin", "--name", "db.sql"])
+        .arg(af.path())
+        .assert()
+        .code(1)
+        .stdout(contains("Invalid apath"));
+}
@@ -0,0 +1,280 @@
+// Copyright 2019 Martin Pool.
+
+/// Test that damaged archives are reported by `validate`, that restoring from them
+/// fails cleanly, and that as much as possible can be salvaged.
+extern crate conserve;
+
+extern crate tempfile;
+
+use std::path::Path;
+
+use tempfile::TempDir;
+
+use conserve::test_fixtures::{Damage, ScratchArchive, ALL_DAMAGE};
+use conserve::*;
+
+/// Make an archive with two versions, and damage the first.
+fn damaged_archive(damage: Damage) -> (ScratchArchive, String) {
+    let af = ScratchArchive::new();
+    af.store_version_in_hunks();
+    af.store_version_in_hunks();
+    let path = af.damage(damage);
+    (af, path.display().to_string())
+}
+
+fn problem_kinds_and_paths(report: &Report) -> Vec<(ProblemKind, String)> {
+    report
+        .borrow_counts()
+        .problems()
+        .iter()
+        .map(|p| (p.kind, p.path.clone()))
+        .collect()
+}
+
+fn parent(path: &str) -> String {
+    Path::new(path).parent().unwrap().display().to_string()
+}
+
+#[test]
+fn validate_reports_damage() {
+    for &damage in ALL_DAMAGE {
+        let (af, path) = damaged_archive(damage);
+        let report = Report::new();
+        Archive::open(af.path(), &report)
+            .unwrap()
+            .validate(ValidateDepth::Deep)
+            .unwrap();
+        let expected = match damage {
+            // Both versions have `/b` in the damaged block.
+            Damage::TruncateBlock | Damage::FlipBlockBit => vec![
+                (ProblemKind::BadBlock, path),
+                (ProblemKind::DamagedFile, "/b".to_owned()),
+                (ProblemKind::DamagedFile, "/b".to_owned()),
+            ],
+            Damage::DeleteHunk => vec![(ProblemKind::MissingFile, path)],
+            Damage::CorruptHunk => vec![(ProblemKind::BadIndex, path)],
+            Damage::DeleteTail | Damage::BadHeadJson => vec![(ProblemKind::BadBand, parent(&path))],
+            Damage::StrayTmpFile => vec![(ProblemKind::UnexpectedFile, path)],
+            Damage::BadTailJson => vec![(ProblemKind::BadBand, path)],
+        };
+        assert_eq!(problem_kinds_and_paths(&report), expected, "{:?}", damage);
+    }
+}
+
+#[test]
+fn shallow_validate_reports_damage_except_to_block_contents() {
+    for &damage in ALL_DAMAGE {
+        let (af, _path) = damaged_archive(damage);
+        let report = Report::new();
+        Archive::open(af.path(), &report)
+            .unwrap()
+            .validate(ValidateDepth::Shallow)
+            .unwrap();
+        let problems = report.borrow_counts().count_problems();
+        match damage {
+            Damage::TruncateBlock | Damage::FlipBlockBit => assert_eq!(problems, 0),
+            _ => assert_eq!(problems, 1, "{:?}", damage),
+        }
+    }
+}
+
+#[test]
+fn restore_damaged_version() {
+    for &damage in ALL_DAMAGE {
+        let (af, path) = damaged_archive(damage);
+        let report = Report::new();
+        let archive = Archive::open(af.path(), &report).unwrap();
+        let st = match StoredTree::open_version(&archive, &BandId::new(&[0])) {
+            Ok(st) => st,
+            Err(e) => {
+                match damage {
+                    Damage::DeleteTail => {
+                        assert_eq!(e.to_string(), "Band b0000 is incomplete")
+                    }
+                    Damage::BadHeadJson => match e {
+                        Error::JsonDeserialize(_) => (),
+                        _ => panic!("unexpected error {:?}", e),
+                    },
+                    _ => panic!("can't open {:?}: {:?}", damage, e),
+                }
+                continue;
+            }
+        };
+        let restore_dir = TempDir::new().unwrap();
+        let mut rt = RestoreTree::create(restore_dir.path(), &report).unwrap();
+        copy_tree(&st, &mut rt).unwrap();
+
+        // Everything that wasn't damaged is restored.
+        for name in &["a", "c"] {
+            assert_eq!(
+                std::fs::read_to_string(restore_dir.path().join(name)).unwrap(),
+                format!("contents of /{}", name)
+            );
+        }
+        let b_restored = restore_dir.path().join("b").exists();
+        let problems = problem_kinds_and_paths(&report);
+        match damage {
+            Damage::TruncateBlock | Damage::FlipBlockBit => {
+                assert!(!b_restored);
+                assert_eq!(
+                    problems,
+                    vec![
+                        (ProblemKind::BadBlock, path),
+                        (ProblemKind::CopyFailed, "/b".to_owned())
+                    ]
+                );
+            }
+            Damage::DeleteHunk => {
+                assert!(!b_restored);
+                assert_eq!(problems, vec![(ProblemKind::MissingFile, path)]);
+            }
+            Damage::CorruptHunk => {
+                assert!(!b_restored);
+                assert_eq!(problems, vec![(ProblemKind::SourceRead, path)]);
+            }
+            _ => {
+                assert!(b_restored, "{:?}", damage);
+                assert_eq!(problems, vec![], "{:?}", damage);
+            }
+        }
+    }
+}
+
+#[test]
+fn restore_damaged_blocks_with_zero_fill() {
+    for &damage in &[Damage::TruncateBlock, Damage::FlipBlockBit] {
+        let (af, path) = damaged_archive(damage);
+        let report = Report::new();
+        let archive = Archive::open(af.path(), &report).unwrap();
+        let st = StoredTree::open_version(&archive, &BandId::new(&[0]))
+            .unwrap()
+            .with_zero_fill(true);
+        let restore_dir = TempDir::new().unwrap();
+        let mut rt = RestoreTree::create(restore_dir.path(), &report).unwrap();
+        copy_tree(&st, &mut rt).unwrap();
+
+        assert_eq!(
+            std::fs::read(restore_dir.path().join("b")).unwrap(),
+            vec![0; "contents of /b".len()]
+        );
+        assert_eq!(
+            std::fs::read_to_string(restore_dir.path().join("c")).unwrap(),
+            "contents of /c"
+        );
+        assert_eq!(
+            problem_kinds_and_paths(&report),
+            vec![
+                (ProblemKind::BadBlock, path),
+                (ProblemKind::DamagedFile, "/b".to_owned())
+            ]
+        );
+    }
+}
+
+#[test]
+fn repair_damage() {
+    for &damage in ALL_DAMAGE {
+        let (af, path) = damaged_archive(damage);
+        let report = Report::new();
+        let archive = Archive::open(af.path(), &report).unwrap();
+        let repairs = plan_repair(&archive).unwrap();
+        let hash = Path::new(&path).file_name().unwrap().to_str().unwrap();
+        let expected = match damage {
+            Damage::TruncateBlock | Damage::FlipBlockBit => {
+                vec![Repair::QuarantineBlock(hash.to_owned())]
+            }
+            Damage::DeleteTail => vec![Repair::CloseBand(BandId::new(&[0]))],
+            _ => vec![],
+        };
+        assert_eq!(repairs, expected, "{:?}", damage);
+        repair(&archive, &repairs).unwrap();
+        assert_eq!(plan_repair(&archive).unwrap(), vec![], "{:?}", damage);
+
+        let report = Report::new();
+        Archive::open(af.path(), &report)
+            .unwrap()
+            .validate(ValidateDepth::Deep)
+            .unwrap();
+        let problems = problem_kinds_and_paths(&report);
+        match damage {
+            Damage::TruncateBlock | Damage::FlipBlockBit => {
+                assert!(af.quarantine_path().join(hash).is_file());
+                assert_eq!(
+                    problems,
+                    vec![
+                        (ProblemKind::DamagedFile, "/b".to_owned()),
+                        (ProblemKind::DamagedFile, "/b".to_owned()),
+                    ]
+                );
+            }
+            Damage::DeleteTail => assert_eq!(problems, vec![]),
+            _ => assert_eq!(problems.len(), 1, "{:?}", damage),
+        }
+    }
+}
+
+#[test]
+fn repair_interrupted_backup() {
+    let af = ScratchArchive::new();
+    af.store_version_in_hunks();
+    af.damage(Damage::DeleteTail);
+    let hunk_path = af.path().join("b0000/i/00000/000000003");
+    std::fs::write(&hunk_path, b"partly written").unwrap();
+    let report = Report::new();
+    let archive = Archive::open(af.path(), &report).unwrap();
+    match StoredTree::open_last(&archive) {
+        Err(Error::NoCompleteBands) => (),
+        other => panic!("unexpected {:?}", other),
+    }
+
+    let repairs = plan_repair(&archive).unwrap();
+    assert_eq!(
+        repairs,
+        vec![
+            Repair::RemoveHunk(hunk_path.clone()),
+            Repair::CloseBand(BandId::new(&[0])),
+        ]
+    );
+    assert_eq!(
+        repairs[1].to_string(),
+        "Close incomplete band b0000 as partial"
+    );
+    repair(&archive, &repairs).unwrap();
+    assert!(!hunk_path.exists());
+
+    let st = StoredTree::open_last(&archive).unwrap();
+    assert!(st.band().get_info(&report).unwrap().is_partial);
+    let apaths: Vec<String> = st
+        .iter_entries(&report)
+        .unwrap()
+        .map(|e| e.unwrap().apath.to_string())
+        .collect();
+    assert_eq!(apaths, vec!["/", "/a", "/b"]);
+    archive.validate(ValidateDepth::Deep).unwrap();
+    assert_eq!(report.borrow_counts().count_problems(), 0);
+}
+
+#[test]
+fn repair_from_parity() {
+    for &damage in &[
+        Damage::TruncateBlock,
+        Damage::FlipBlockBit,
+        Damage::DeleteHunk,
+        Damage::CorruptHunk,
+    ] {
+        let af = ScratchArchive::new();
+        af.store_version_in_hunks();
+        af.store_version_in_hunks();
+        assert_eq!(write_parity(&af).unwrap(), 1);
+        let path = af.damage(damage);
+        let report = Report::new();
+        let archive = Archive::open(af.path(), &report).unwrap();
+
+        let repairs = plan_repair(&archive).unwrap();
+        assert_eq!(repairs, vec![Repair::Reconstruct(path)], "{:?}", damage);
+        repair(&archive, &repairs).unwrap();
+        assert_eq!(plan_repair(&archive).unwrap(), vec![], "{:?}", damage);
+        archive.validate(ValidateDepth::Deep).unwrap();
+        assert_eq!(report.borrow_counts().count_problems(), 0, "{:?}", damage);
+    }
+}
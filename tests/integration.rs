@@ -44,7 +44,7 @@ pub fn simple_backup_with_excludes() {
     copy_tree(&lt, &mut bw).unwrap();
     check_backup(&af, &af.report());
     check_restore(&af);
-    af.validate().unwrap();
+    af.validate(ValidateDepth::Deep).unwrap();
 }
 
 fn check_backup(af: &ScratchArchive, report: &Report) {
@@ -108,13 +108,12 @@ fn check_restore(af: &ScratchArchive) {
         format!("{:?}", block_sizes)
     );
     let index_sizes = restore_report.get_size("index");
-    // Doubled because we currently read the index twice.
+    // The index is read twice, but reading it to measure the tree isn't counted.
     assert_eq!(
-        index_sizes.uncompressed,
-        267 * 2,
+        index_sizes.uncompressed, 267,
         "index_sizes.uncompressed on restore"
     );
-    assert!(index_sizes.compressed <= 267 * 2, index_sizes.compressed);
+    assert!(index_sizes.compressed <= 267, index_sizes.compressed);
     // TODO: Check what was restored.
 }
 